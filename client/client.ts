@@ -3,19 +3,24 @@ export interface ConnectionInfo {
   host: string;
   port: number;
   dbname: string;
+  socket_dir: string;
 }
 
 export interface ProcessInfo {
   pid: number;
+  uptime_seconds?: number;
 }
 
 export enum InstanceState {
+  Creating,
   Stopped,
   Running,
 }
 
 const parseState = (str: string): InstanceState => {
   switch (str) {
+    case "Creating":
+      return InstanceState.Creating;
     case "Stopped":
       return InstanceState.Stopped;
     case "Running":
@@ -30,6 +35,18 @@ interface RawInstance {
   state: string;
   conn_info: ConnectionInfo;
   proc_info?: ProcessInfo;
+  expires_at?: number;
+  version?: string;
+  server_version?: string;
+  labels: Record<string, string>;
+  created_at?: string | null;
+  last_started_at?: string | null;
+  role?: ReplicationRole;
+  primary?: string;
+  disk_bytes?: number;
+  connections?: number;
+  recovered?: boolean;
+  recovery_duration_ms?: number;
 }
 
 export interface Instance {
@@ -37,43 +54,221 @@ export interface Instance {
   state: InstanceState;
   connInfo: ConnectionInfo;
   procInfo?: ProcessInfo;
+  expiresAt?: number;
+  version?: string;
+  serverVersion?: string;
+  labels: Record<string, string>;
+  createdAt?: Date;
+  lastStartedAt?: Date;
+  role?: ReplicationRole;
+  primary?: string;
+  diskBytes?: number;
+  connections?: number;
+  recovered?: boolean;
+  recoveryDurationMs?: number;
 }
 
+// RFC3339, null for instances from before timestamps were recorded
+const parseTimestamp = (str?: string | null): Date | undefined =>
+  str ? new Date(str) : undefined;
+
 const parseInstance = (raw: RawInstance): Instance => {
   return {
     id: raw.id,
     state: parseState(raw.state),
     connInfo: raw.conn_info,
     procInfo: raw.proc_info,
+    expiresAt: raw.expires_at,
+    version: raw.version,
+    serverVersion: raw.server_version,
+    labels: raw.labels,
+    createdAt: parseTimestamp(raw.created_at),
+    lastStartedAt: parseTimestamp(raw.last_started_at),
+    role: raw.role,
+    primary: raw.primary,
+    diskBytes: raw.disk_bytes,
+    connections: raw.connections,
+    recovered: raw.recovered,
+    recoveryDurationMs: raw.recovery_duration_ms,
   };
 };
 
+export type Durability = "fast" | "safe";
+
+export type ReplicationRole = "primary" | "standby";
+
+export type SynchronousCommit =
+  | "on"
+  | "off"
+  | "local"
+  | "remote_write"
+  | "remote_apply";
+
+export interface Table {
+  schema: string;
+  name: string;
+  estimatedRows: number;
+  sizeBytes: number;
+}
+
+interface RawTable {
+  schema: string;
+  name: string;
+  estimated_rows: number;
+  size_bytes: number;
+}
+
+export interface QueryResult {
+  columns: string[];
+  rows: (string | null)[][];
+  rowsAffected: number;
+}
+
+interface RawQueryResult {
+  columns: string[];
+  rows: (string | null)[][];
+  rows_affected: number;
+}
+
+export interface CreateOptions {
+  id?: string;
+  durability?: Durability;
+  synchronousCommit?: SynchronousCommit;
+  listenAddresses?: string[];
+  maxConnections?: number;
+  sharedBuffers?: string;
+  maxWalSize?: string;
+  minWalSize?: string;
+  templateDatabase?: string;
+  encoding?: string;
+  locale?: string;
+  lcCollate?: string;
+  lcCtype?: string;
+  sharedPreloadLibraries?: string[];
+  archiveWal?: boolean;
+  settings?: Record<string, string>;
+  ttlSeconds?: number;
+  version?: string;
+  extensions?: string[];
+  initSql?: string;
+  owner?: string;
+  password?: string;
+  labels?: Record<string, string>;
+}
+
+export interface ForkPlan {
+  filesystem: string;
+  method: "snapshot" | "reflink" | "hardlink" | "copy";
+  bytes: number;
+  linkedBytes: number;
+}
+
+export interface ForkOptions {
+  mode?: "physical" | "logical" | "hardlink" | "online";
+  schemaOnly?: boolean;
+  stopTemplate?: boolean;
+}
+
+const descriptor = (dbname: string, options: CreateOptions) =>
+  JSON.stringify({
+    id: options.id,
+    dbname,
+    durability: options.durability,
+    synchronous_commit: options.synchronousCommit,
+    listen_addresses: options.listenAddresses,
+    max_connections: options.maxConnections,
+    shared_buffers: options.sharedBuffers,
+    max_wal_size: options.maxWalSize,
+    min_wal_size: options.minWalSize,
+    template_database: options.templateDatabase,
+    encoding: options.encoding,
+    locale: options.locale,
+    lc_collate: options.lcCollate,
+    lc_ctype: options.lcCtype,
+    shared_preload_libraries: options.sharedPreloadLibraries,
+    archive_wal: options.archiveWal,
+    settings: options.settings,
+    ttl_seconds: options.ttlSeconds,
+    version: options.version,
+    extensions: options.extensions,
+    init_sql: options.initSql,
+    owner: options.owner,
+    password: options.password,
+    labels: options.labels,
+  });
+
 export class QuickPgClient {
-  constructor(readonly host: string) {}
+  // token is sent as a bearer token, for servers started with QUICKPG_API_TOKEN
+  constructor(readonly host: string, readonly token?: string) {}
+
+  private authorization(): Record<string, string> {
+    return this.token ? { authorization: `Bearer ${this.token}` } : {};
+  }
+
+  async list(
+    labels: Record<string, string> = {},
+    withSize = false,
+  ): Promise<Instance[]> {
+    const params = new URLSearchParams();
+    if (withSize) {
+      params.set("with_size", "true");
+    }
+    for (const [key, value] of Object.entries(labels)) {
+      params.append("label", `${key}=${value}`);
+    }
 
-  async list(): Promise<Instance[]> {
     const { instances } = await this.api<{ instances: RawInstance[] }>(
       "GET",
-      "pg/instance",
+      `pg/instance?${params}`,
     );
 
     return instances.map(parseInstance);
   }
 
-  async create(dbname: string): Promise<Instance> {
+  async create(
+    dbname: string,
+    options: CreateOptions = {},
+  ): Promise<Instance> {
     const instance = await this.api<RawInstance>(
       "POST",
       "pg/instance",
-      JSON.stringify({ dbname }),
+      descriptor(dbname, options),
     );
 
     return parseInstance(instance);
   }
 
-  async status(id: string): Promise<Instance> {
+  // Labels and the TTL of an existing instance are replaced by the given ones
+  async upsert(
+    id: string,
+    dbname: string,
+    options: Omit<CreateOptions, "id"> = {},
+  ): Promise<Instance> {
     const instance = await this.api<RawInstance>(
-      "GET",
+      "PUT",
       `pg/instance/${id}`,
+      descriptor(dbname, options),
+    );
+
+    return parseInstance(instance);
+  }
+
+  async status(
+    id: string,
+    withSize = false,
+    withConnections = false,
+  ): Promise<Instance> {
+    const params = new URLSearchParams();
+    if (withSize) {
+      params.set("with_size", "true");
+    }
+    if (withConnections) {
+      params.set("with_connections", "true");
+    }
+
+    const instance = await this.api<RawInstance>(
+      "GET",
+      `pg/instance/${id}?${params}`,
     );
 
     return parseInstance(instance);
@@ -88,24 +283,275 @@ export class QuickPgClient {
     return parseInstance(instance);
   }
 
-  async stop(id: string): Promise<void> {
+  async restart(id: string): Promise<Instance> {
+    const instance = await this.api<RawInstance>(
+      "POST",
+      `pg/instance/${id}/restart`,
+    );
+
+    return parseInstance(instance);
+  }
+
+  async reload(id: string): Promise<Instance> {
+    const instance = await this.api<RawInstance>(
+      "POST",
+      `pg/instance/${id}/reload`,
+    );
+
+    return parseInstance(instance);
+  }
+
+  async stop(
+    id: string,
+    mode: "smart" | "fast" | "immediate" = "fast",
+  ): Promise<void> {
     await this.api(
       "POST",
-      `pg/instance/${id}/stop`,
+      `pg/instance/${id}/stop?mode=${mode}`,
     );
   }
 
-  async fork(template: string): Promise<Instance> {
+  async kill(id: string): Promise<void> {
+    await this.api(
+      "POST",
+      `pg/instance/${id}/kill`,
+    );
+  }
+
+  async fork(
+    template: string,
+    options: ForkOptions = {},
+  ): Promise<Instance> {
+    const params = new URLSearchParams();
+    if (options.mode) {
+      params.set("mode", options.mode);
+    }
+    if (options.schemaOnly) {
+      params.set("schema_only", "true");
+    }
+    if (options.stopTemplate) {
+      params.set("stop_template", "true");
+    }
+
     const instance = await this.api<RawInstance>(
       "POST",
-      `pg/instance/${template}/fork`,
+      `pg/instance/${template}/fork?${params}`,
     );
 
     return parseInstance(instance);
   }
 
-  async destroy(id: string): Promise<void> {
-    return await this.api("DELETE", `pg/instance/${id}`);
+  async forkPlan(
+    template: string,
+    mode: "physical" | "hardlink" = "physical",
+  ): Promise<ForkPlan> {
+    const plan = await this.api<{
+      filesystem: string;
+      method: ForkPlan["method"];
+      bytes: number;
+      linked_bytes: number;
+    }>("GET", `pg/instance/${template}/fork/plan?mode=${mode}`);
+
+    return {
+      filesystem: plan.filesystem,
+      method: plan.method,
+      bytes: plan.bytes,
+      linkedBytes: plan.linked_bytes,
+    };
+  }
+
+  async replica(primary: string): Promise<Instance> {
+    const instance = await this.api<RawInstance>(
+      "POST",
+      `pg/instance/${primary}/replica`,
+    );
+
+    return parseInstance(instance);
+  }
+
+  async promote(id: string): Promise<Instance> {
+    const instance = await this.api<RawInstance>(
+      "POST",
+      `pg/instance/${id}/promote`,
+    );
+
+    return parseInstance(instance);
+  }
+
+  async writeFile(id: string, path: string, content: Uint8Array): Promise<void> {
+    await this.api(
+      "POST",
+      `pg/instance/${id}/file`,
+      JSON.stringify({
+        path,
+        content_base64: btoa(String.fromCharCode(...content)),
+      }),
+    );
+  }
+
+  async tables(id: string, dbname?: string): Promise<Table[]> {
+    const params = new URLSearchParams();
+    if (dbname) {
+      params.set("dbname", dbname);
+    }
+
+    const { tables } = await this.api<{ tables: RawTable[] }>(
+      "GET",
+      `pg/instance/${id}/tables?${params}`,
+    );
+
+    return tables.map((raw) => ({
+      schema: raw.schema,
+      name: raw.name,
+      estimatedRows: raw.estimated_rows,
+      sizeBytes: raw.size_bytes,
+    }));
+  }
+
+  // needs QUICKPG_ENABLE_QUERY=true on the server
+  async query(id: string, sql: string): Promise<QueryResult[]> {
+    const { results } = await this.api<{ results: RawQueryResult[] }>(
+      "POST",
+      `pg/instance/${id}/query`,
+      JSON.stringify({ sql }),
+    );
+
+    return results.map((raw) => ({
+      columns: raw.columns,
+      rows: raw.rows,
+      rowsAffected: raw.rows_affected,
+    }));
+  }
+
+  async logs(id: string, lines?: number): Promise<string> {
+    const params = new URLSearchParams();
+    if (lines) {
+      params.set("lines", lines.toString());
+    }
+
+    const response = await fetch(
+      `http://${this.host}/pg/instance/${id}/logs?${params}`,
+      { headers: this.authorization() },
+    );
+    if (!response.ok) {
+      throw new Error(`${response.status}: ${(await response.text())}`);
+    }
+
+    return response.text();
+  }
+
+  // the body streams, so large dumps don't have to fit in memory
+  async dump(
+    id: string,
+    format: "plain" | "custom" = "plain",
+  ): Promise<ReadableStream<Uint8Array>> {
+    const response = await fetch(
+      `http://${this.host}/pg/instance/${id}/dump?format=${format}`,
+      { headers: this.authorization() },
+    );
+    if (!response.ok || !response.body) {
+      throw new Error(`${response.status}: ${(await response.text())}`);
+    }
+
+    return response.body;
+  }
+
+  // a tarball of the stopped instance's data dir, stop checkpoints and stops it for the archive
+  async archive(
+    id: string,
+    format: "tar" | "zst" = "tar",
+    stop = false,
+  ): Promise<ReadableStream<Uint8Array>> {
+    const params = new URLSearchParams({ format });
+    if (stop) {
+      params.set("stop", "true");
+    }
+    const response = await fetch(
+      `http://${this.host}/pg/instance/${id}/archive?${params}`,
+      { headers: this.authorization() },
+    );
+    if (!response.ok || !response.body) {
+      throw new Error(`${response.status}: ${(await response.text())}`);
+    }
+
+    return response.body;
+  }
+
+  // creates an instance from what archive returned, uploaded as it's read
+  async import(
+    body: BodyInit,
+    format: "tar" | "zst" = "tar",
+  ): Promise<Instance> {
+    const response = await fetch(
+      `http://${this.host}/pg/instance/import?format=${format}`,
+      { method: "POST", headers: this.authorization(), body },
+    );
+    if (!response.ok) {
+      throw new Error(`${response.status}: ${(await response.text())}`);
+    }
+
+    return parseInstance(await response.json());
+  }
+
+  // plain is SQL for psql, custom is a pg_dump archive for pg_restore
+  async restore(
+    id: string,
+    body: BodyInit,
+    format: "plain" | "custom" = "plain",
+  ): Promise<void> {
+    const response = await fetch(
+      `http://${this.host}/pg/instance/${id}/restore?format=${format}`,
+      { method: "POST", headers: this.authorization(), body },
+    );
+    if (!response.ok) {
+      throw new Error(`${response.status}: ${(await response.text())}`);
+    }
+  }
+
+  async swap(a: string, b: string): Promise<void> {
+    await this.api("POST", "pg/instance/swap", JSON.stringify({ a, b }));
+  }
+
+  async rename(id: string, newId: string): Promise<Instance> {
+    const instance = await this.api<RawInstance>(
+      "POST",
+      `pg/instance/${id}/rename`,
+      JSON.stringify({ id: newId }),
+    );
+
+    return parseInstance(instance);
+  }
+
+  async validateTemplate(
+    id: string,
+  ): Promise<{ valid: boolean; reasons: string[] }> {
+    return await this.api("POST", `pg/instance/${id}/validate-template`);
+  }
+
+  async destroy(id: string, strict = false): Promise<void> {
+    const query = strict ? "?strict=true" : "";
+    return await this.api("DELETE", `pg/instance/${id}${query}`);
+  }
+
+  async destroyMany(
+    ids: string[],
+    all = false,
+  ): Promise<{ id: string; destroyed: boolean; error?: string }[]> {
+    const query = all ? "?all=true" : "";
+    return await this.api(
+      "DELETE",
+      `pg/instance${query}`,
+      JSON.stringify({ ids }),
+    );
+  }
+
+  // new instances are refused with a 503 until undrain
+  async drain(): Promise<{ draining: boolean }> {
+    return await this.api("POST", "pg/admin/drain");
+  }
+
+  async undrain(): Promise<{ draining: boolean }> {
+    return await this.api("POST", "pg/admin/undrain");
   }
 
   async api<T>(
@@ -117,6 +563,7 @@ export class QuickPgClient {
       method,
       headers: {
         "content-type": "application/json;charset=UTF-8",
+        ...this.authorization(),
       },
       body,
     });
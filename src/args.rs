@@ -0,0 +1,83 @@
+use std::{env, net::SocketAddr, path::PathBuf};
+
+use clap::Parser;
+
+use crate::copy;
+
+/// Serves disposable Postgres instances over HTTP. Every flag can also be set with its
+/// environment variable.
+#[derive(Debug, Parser)]
+#[command(name = "quickpg", version)]
+struct Cli {
+    /// Address to listen on
+    #[arg(
+        long,
+        value_name = "ADDR",
+        env = "QUICKPG_BIND",
+        default_value = "127.0.0.1:8000"
+    )]
+    bind: SocketAddr,
+    /// Holds bin, data, logs and sockets [default: the current directory]
+    #[arg(long, value_name = "DIR", env = "QUICKPG_ROOT")]
+    root: Option<PathBuf>,
+    /// Directory with pg_ctl and the other Postgres binaries
+    #[arg(long, value_name = "DIR", env = "QUICKPG_PG_BIN")]
+    pg_bin: Option<PathBuf>,
+    /// Where instances put their Unix sockets [default: ROOT/sockets]
+    #[arg(long, value_name = "DIR", env = "QUICKPG_SOCKET_DIR")]
+    socket_dir: Option<PathBuf>,
+    /// Octal mode of created dirs, 700 or 750
+    #[arg(
+        long = "data-dir-mode",
+        value_name = "MODE",
+        env = "QUICKPG_DATA_DIR_MODE",
+        default_value = "700",
+        value_parser = parse_dir_mode
+    )]
+    dir_mode: u32,
+}
+
+fn parse_dir_mode(mode: &str) -> Result<u32, String> {
+    let mode = u32::from_str_radix(mode, 8)
+        .map_err(|err| format!("invalid data dir mode {}: {}", mode, err))?;
+    copy::validate_dir_mode(mode)?;
+    Ok(mode)
+}
+
+/// Server options, flags take precedence over their environment variables.
+#[derive(Debug)]
+pub struct Args {
+    pub bind: SocketAddr,
+    pub root: PathBuf,
+    /// An explicit `pg_ctl`, otherwise it's discovered under the root or `$PATH`
+    pub pg_ctl: Option<PathBuf>,
+    /// Absolute, Unix socket paths are limited to 107 bytes so deep roots need a shorter one
+    pub socket_dir: Option<PathBuf>,
+    /// Mode of the data dirs and the directories around them
+    pub dir_mode: u32,
+}
+
+impl Args {
+    /// Exits with clap's usage message on invalid flags, and after printing `--help`.
+    pub fn parse() -> Result<Args, String> {
+        let cli = Cli::parse();
+
+        let current_dir = env::current_dir()
+            .map_err(|err| format!("failed to read the current directory: {}", err))?;
+        let root = cli.root.unwrap_or_else(|| current_dir.clone());
+        let socket_dir = cli.socket_dir.map(|dir| current_dir.join(dir));
+
+        let pg_ctl = match cli.pg_bin {
+            Some(dir) => Some(dir.join("pg_ctl")),
+            None => env::var_os("QUICKPG_PG_CTL").map(PathBuf::from),
+        };
+
+        Ok(Args {
+            bind: cli.bind,
+            root,
+            pg_ctl,
+            socket_dir,
+            dir_mode: cli.dir_mode,
+        })
+    }
+}
@@ -0,0 +1,66 @@
+use std::{env, sync::Arc};
+
+use axum::{
+    extract::State,
+    http::{header, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::json;
+
+/// Probes have to keep working without the token.
+const OPEN_PATHS: &[&str] = &["/health", "/ready"];
+
+/// Shared secret every request has to present as `Authorization: Bearer <token>`.
+#[derive(Debug)]
+pub struct ApiToken(String);
+
+impl ApiToken {
+    /// Reads `QUICKPG_API_TOKEN`, the API is left open when it's unset or empty.
+    pub fn from_env() -> Option<Arc<ApiToken>> {
+        env::var("QUICKPG_API_TOKEN")
+            .ok()
+            .filter(|token| !token.is_empty())
+            .map(|token| Arc::new(ApiToken(token)))
+    }
+
+    fn matches(&self, presented: &str) -> bool {
+        constant_time_eq(self.0.as_bytes(), presented.as_bytes())
+    }
+}
+
+/// Looks at every byte regardless of where the first mismatch is, only the length can leak.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Rejects requests without the token with `401 Unauthorized`.
+pub async fn require_token<B>(
+    State(token): State<Arc<ApiToken>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    if OPEN_PATHS.contains(&request.uri().path()) {
+        return next.run(request).await;
+    }
+
+    let presented = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    match presented {
+        Some(presented) if token.matches(presented.trim()) => next.run(request).await,
+        _ => (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, "Bearer")],
+            Json(json!({ "error": "missing or invalid API token" })),
+        )
+            .into_response(),
+    }
+}
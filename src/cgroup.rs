@@ -0,0 +1,146 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+const CGROUP_PARENT: &str = "quickpg";
+const CPU_PERIOD_USEC: u64 = 100_000;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize, Serialize)]
+pub struct ResourceLimits {
+    pub memory_bytes: Option<u64>,
+    pub cpu_cores: Option<f64>,
+}
+
+impl ResourceLimits {
+    pub fn is_empty(&self) -> bool {
+        self.memory_bytes.is_none() && self.cpu_cores.is_none()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+pub struct ResourceUsage {
+    pub memory_bytes: u64,
+    pub cpu_usec: u64,
+}
+
+pub fn is_available() -> bool {
+    Path::new(CGROUP_ROOT).join("cgroup.controllers").is_file()
+}
+
+fn parent() -> PathBuf {
+    Path::new(CGROUP_ROOT).join(CGROUP_PARENT)
+}
+
+fn group(id: &str) -> PathBuf {
+    parent().join(id)
+}
+
+/// Moves the postmaster and its current children into a cgroup v2 group with the given limits.
+pub async fn apply(id: &str, pid: u32, limits: &ResourceLimits) -> io::Result<()> {
+    if !is_available() {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "cgroup v2 is not mounted",
+        ));
+    }
+
+    tokio::fs::create_dir_all(parent()).await?;
+    tokio::fs::write(
+        Path::new(CGROUP_ROOT).join("cgroup.subtree_control"),
+        "+memory +cpu",
+    )
+    .await?;
+    tokio::fs::write(parent().join("cgroup.subtree_control"), "+memory +cpu").await?;
+
+    let group = group(id);
+    tokio::fs::create_dir_all(&group).await?;
+
+    let memory_max = match limits.memory_bytes {
+        Some(bytes) => bytes.to_string(),
+        None => "max".to_string(),
+    };
+    tokio::fs::write(group.join("memory.max"), memory_max).await?;
+
+    let cpu_max = match limits.cpu_cores {
+        Some(cores) => format!(
+            "{} {}",
+            (cores * CPU_PERIOD_USEC as f64).round() as u64,
+            CPU_PERIOD_USEC
+        ),
+        None => format!("max {}", CPU_PERIOD_USEC),
+    };
+    tokio::fs::write(group.join("cpu.max"), cpu_max).await?;
+
+    // Auxiliary processes (checkpointer, walwriter, ...) are already forked by the time
+    // pg_ctl returns, so they are moved alongside the postmaster
+    let mut pids = vec![pid];
+    pids.extend(children(pid).await?);
+    for pid in pids {
+        tokio::fs::write(group.join("cgroup.procs"), pid.to_string()).await?;
+    }
+
+    Ok(())
+}
+
+pub async fn usage(id: &str) -> Option<ResourceUsage> {
+    let group = group(id);
+
+    let memory = tokio::fs::read_to_string(group.join("memory.current"))
+        .await
+        .ok()?;
+    let cpu = tokio::fs::read_to_string(group.join("cpu.stat"))
+        .await
+        .ok()?;
+
+    let cpu_usec = cpu
+        .lines()
+        .find_map(|line| line.strip_prefix("usage_usec "))
+        .and_then(|usec| usec.trim().parse().ok())?;
+
+    Some(ResourceUsage {
+        memory_bytes: memory.trim().parse().ok()?,
+        cpu_usec,
+    })
+}
+
+pub async fn remove(id: &str) -> io::Result<()> {
+    let group = group(id);
+    if group.is_dir() {
+        tokio::fs::remove_dir(group).await?;
+    }
+    Ok(())
+}
+
+async fn children(pid: u32) -> io::Result<Vec<u32>> {
+    let mut proc = tokio::fs::read_dir("/proc").await?;
+    let mut children = vec![];
+
+    while let Some(entry) = proc.next_entry().await? {
+        let child = match entry.file_name().to_string_lossy().parse::<u32>() {
+            Ok(child) => child,
+            Err(_) => continue,
+        };
+
+        // The process may have exited between listing and reading
+        let stat = match tokio::fs::read_to_string(entry.path().join("stat")).await {
+            Ok(stat) => stat,
+            Err(_) => continue,
+        };
+
+        // Fields after the parenthesized command name are: state ppid ...
+        let ppid = stat
+            .rsplit_once(')')
+            .and_then(|(_, rest)| rest.split_whitespace().nth(1))
+            .and_then(|ppid| ppid.parse::<u32>().ok());
+
+        if ppid == Some(pid) {
+            children.push(child);
+        }
+    }
+
+    Ok(children)
+}
@@ -1,51 +1,81 @@
-use std::{fmt, io, path::Path};
+use std::{borrow::Cow, fmt, io, path::Path};
 
 use byte_unit::Byte;
-use tokio::{self, io::AsyncWriteExt};
+use serde::{Deserialize, Serialize};
+
+use crate::copy;
+
+/// Holds archived WAL segments, relative to the data dir since `archive_command` runs there.
+pub const ARCHIVE_DIR: &str = "quickpg_archive";
 
 enum Value<'a> {
     Byte(Byte),
     Int(u32),
+    Float(f64),
+    Millis(u32),
     Str(&'a str),
+    /// Passed through from the API, quoted since any setting accepts a quoted value
+    Text(String),
 }
 
 impl<'a> fmt::Display for Value<'a> {
     fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-        match *self {
-            Value::Byte(b) => b
-                .get_appropriate_unit(false)
-                .format(0)
-                .replace(" ", "")
-                .fmt(formatter),
+        match self {
+            // Postgres only knows binary units, and kB keeps every size exact enough
+            Value::Byte(b) => format!("{}kB", b.get_bytes().div_ceil(1024)).fmt(formatter),
             Value::Int(i) => i.fmt(formatter),
+            Value::Float(f) => f.fmt(formatter),
+            Value::Millis(ms) => format!("{}ms", ms).fmt(formatter),
             Value::Str(s) => format!("'{}'", s).fmt(formatter),
+            Value::Text(s) => format!("'{}'", s.replace('\'', "''")).fmt(formatter),
         }
     }
 }
 
 struct KeyVal<'a> {
-    key: &'a str,
+    key: Cow<'a, str>,
     val: Value<'a>,
 }
 
 impl<'a> KeyVal<'a> {
     fn byte(key: &'a str, val: Byte) -> KeyVal<'a> {
         KeyVal {
-            key,
+            key: Cow::Borrowed(key),
             val: Value::Byte(val),
         }
     }
 
     fn int(key: &'a str, val: u32) -> KeyVal<'a> {
         KeyVal {
-            key,
+            key: Cow::Borrowed(key),
             val: Value::Int(val),
         }
     }
 
+    fn float(key: &'a str, val: f64) -> KeyVal<'a> {
+        KeyVal {
+            key: Cow::Borrowed(key),
+            val: Value::Float(val),
+        }
+    }
+
+    fn millis(key: &'a str, val: u32) -> KeyVal<'a> {
+        KeyVal {
+            key: Cow::Borrowed(key),
+            val: Value::Millis(val),
+        }
+    }
+
+    fn text(key: &'a str, val: String) -> KeyVal<'a> {
+        KeyVal {
+            key: Cow::Borrowed(key),
+            val: Value::Text(val),
+        }
+    }
+
     fn str(key: &'a str, val: &'a str) -> KeyVal<'a> {
         KeyVal {
-            key,
+            key: Cow::Borrowed(key),
             val: Value::Str(val),
         }
     }
@@ -57,15 +87,13 @@ pub struct Config<'a> {
 
 impl<'a> Config<'a> {
     pub async fn to_file(&self, path: &Path) -> io::Result<()> {
-        let mut file = tokio::fs::File::create(path).await?;
-
+        let mut contents = String::new();
         for row in self.to_strings() {
-            file.write_all(&row.as_bytes()).await?;
-            file.write_all(b"\n").await?;
+            contents.push_str(&row);
+            contents.push('\n');
         }
 
-        file.flush().await?;
-        Ok(())
+        copy::write_atomic(path.to_path_buf(), contents.into_bytes()).await
     }
 
     fn to_strings(&self) -> Vec<String> {
@@ -76,9 +104,104 @@ impl<'a> Config<'a> {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SynchronousCommit {
+    On,
+    #[default]
+    Off,
+    Local,
+    RemoteWrite,
+    RemoteApply,
+}
+
+impl SynchronousCommit {
+    fn as_str(&self) -> &'static str {
+        match self {
+            SynchronousCommit::On => "on",
+            SynchronousCommit::Off => "off",
+            SynchronousCommit::Local => "local",
+            SynchronousCommit::RemoteWrite => "remote_write",
+            SynchronousCommit::RemoteApply => "remote_apply",
+        }
+    }
+}
+
+/// Whether an instance trades crash safety for speed.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Durability {
+    /// No fsync, full page writes or WAL beyond crash recovery, a crash can corrupt the data dir
+    #[default]
+    Fast,
+    /// Postgres' own durable defaults
+    Safe,
+}
+
+/// Encoding and locale of the cluster, fixed by initdb so the `lc_*` settings can't change them.
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+pub struct InitdbOptions {
+    pub encoding: Option<String>,
+    pub locale: Option<String>,
+    /// Overrides `locale` for sorting
+    pub lc_collate: Option<String>,
+    /// Overrides `locale` for character classification
+    pub lc_ctype: Option<String>,
+}
+
+impl InitdbOptions {
+    pub fn is_empty(&self) -> bool {
+        self.flags().is_empty()
+    }
+
+    /// Only checks the names are safe to pass on, initdb decides whether they go together.
+    pub fn validate(&self) -> Result<(), String> {
+        for (flag, value) in self.flags() {
+            let valid = !value.is_empty()
+                && value
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.' | '@'));
+            if !valid {
+                return Err(format!("invalid {}: {}", flag, value));
+            }
+        }
+        Ok(())
+    }
+
+    /// Options for `pg_ctl init -o`, which runs initdb through the shell.
+    pub fn to_options(&self) -> String {
+        let mut options = "--no-sync".to_string();
+        for (flag, value) in self.flags() {
+            options.push_str(&format!(" --{}={}", flag.replace('_', "-"), value));
+        }
+        options
+    }
+
+    fn flags(&self) -> Vec<(&'static str, &str)> {
+        [
+            ("encoding", &self.encoding),
+            ("locale", &self.locale),
+            ("lc_collate", &self.lc_collate),
+            ("lc_ctype", &self.lc_ctype),
+        ]
+        .into_iter()
+        .filter_map(|(flag, value)| Some((flag, value.as_deref()?)))
+        .collect()
+    }
+}
+
+/// Certificate and key the server uses when `ssl` is on.
+#[derive(Debug, Clone)]
+pub struct SslFiles {
+    pub cert_file: String,
+    pub key_file: String,
+}
+
+#[derive(Debug, Clone)]
 pub struct PostgresqlConf<'a> {
-    listen_addresses: &'a str,
+    /// Requires a restart, `localhost` unless more interfaces are opted into, empty for only the
+    /// Unix socket
+    pub listen_addresses: Vec<String>,
     pub port: u32,
     max_connections: u32,
     shared_buffers: Byte,
@@ -86,47 +209,407 @@ pub struct PostgresqlConf<'a> {
     min_wal_size: Byte,
     locale: &'a str,
     timezone: &'a str,
+    durability: Durability,
+    pub synchronous_commit: SynchronousCommit,
+    /// Applied on reload
+    pub checkpoint_timeout_ms: Option<u32>,
+    /// Applied on reload
+    pub checkpoint_completion_target: Option<f64>,
+    /// Requires a restart
+    pub wal_buffers: Option<Byte>,
+    /// Applied on reload
+    pub wal_writer_delay_ms: Option<u32>,
+    /// Turns on `ssl` when set
+    pub ssl: Option<SslFiles>,
+    /// Requires a restart
+    pub shared_preload_libraries: Vec<String>,
+    /// Requires a restart, copies every finished WAL segment into `ARCHIVE_DIR`
+    pub archive: bool,
+    /// Arbitrary settings for anything the generated rows leave out, see `MANAGED_SETTINGS`
+    pub settings: Vec<(String, String)>,
 }
 
 impl<'a> PostgresqlConf<'a> {
     pub fn default(port: u32) -> PostgresqlConf<'a> {
         PostgresqlConf {
-            listen_addresses: "*",
+            listen_addresses: vec!["localhost".to_string()],
             port,
             max_connections: 100,
-            shared_buffers: Byte::from_string("128MB").unwrap(),
-            max_wal_size: Byte::from_string("1GB").unwrap(),
-            min_wal_size: Byte::from_string("80MB").unwrap(),
+            shared_buffers: Byte::from_bytes(128 * MB),
+            max_wal_size: Byte::from_bytes(1024 * MB),
+            min_wal_size: Byte::from_bytes(80 * MB),
             timezone: "America/Toronto",
             locale: "en_US.UTF-8",
+            durability: Durability::default(),
+            synchronous_commit: SynchronousCommit::default(),
+            checkpoint_timeout_ms: None,
+            checkpoint_completion_target: None,
+            wal_buffers: None,
+            wal_writer_delay_ms: None,
+            ssl: None,
+            shared_preload_libraries: vec![],
+            archive: false,
+            settings: vec![],
         }
     }
 
+    /// Also makes `synchronous_commit` match, set it afterwards to override.
+    pub fn with_durability(mut self, durability: Durability) -> PostgresqlConf<'a> {
+        self.durability = durability;
+        self.synchronous_commit = match durability {
+            Durability::Fast => SynchronousCommit::Off,
+            Durability::Safe => SynchronousCommit::On,
+        };
+        self
+    }
+
+    /// Requires a restart
+    pub fn with_max_connections(mut self, max_connections: u32) -> PostgresqlConf<'a> {
+        self.max_connections = max_connections;
+        self
+    }
+
+    /// Requires a restart
+    pub fn with_shared_buffers(mut self, size: &str) -> Result<PostgresqlConf<'a>, String> {
+        self.shared_buffers = parse_byte("shared_buffers", size)?;
+        Ok(self)
+    }
+
+    pub fn with_max_wal_size(mut self, size: &str) -> Result<PostgresqlConf<'a>, String> {
+        self.max_wal_size = parse_byte("max_wal_size", size)?;
+        Ok(self)
+    }
+
+    pub fn with_min_wal_size(mut self, size: &str) -> Result<PostgresqlConf<'a>, String> {
+        self.min_wal_size = parse_byte("min_wal_size", size)?;
+        Ok(self)
+    }
+
+    /// Checks optional tuning values against the ranges Postgres accepts.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_connections == 0 {
+            return Err("max_connections must be at least 1".to_string());
+        }
+
+        if self.shared_buffers.get_bytes() < 128 * 1024 {
+            return Err("shared_buffers must be at least 128kB".to_string());
+        }
+
+        if self.min_wal_size.get_bytes() > self.max_wal_size.get_bytes() {
+            return Err("min_wal_size can't be larger than max_wal_size".to_string());
+        }
+
+        if let Some(timeout) = self.checkpoint_timeout_ms {
+            if !(30_000..=86_400_000).contains(&timeout) {
+                return Err("checkpoint_timeout must be between 30s and 1d".to_string());
+            }
+        }
+
+        if let Some(target) = self.checkpoint_completion_target {
+            if !(0.0..=1.0).contains(&target) {
+                return Err("checkpoint_completion_target must be between 0 and 1".to_string());
+            }
+        }
+
+        if let Some(buffers) = self.wal_buffers {
+            if buffers.get_bytes() < 32 * 1024 {
+                return Err("wal_buffers must be at least 32kB".to_string());
+            }
+        }
+
+        if let Some(delay) = self.wal_writer_delay_ms {
+            if !(1..=10_000).contains(&delay) {
+                return Err("wal_writer_delay must be between 1ms and 10s".to_string());
+            }
+        }
+
+        for address in &self.listen_addresses {
+            if address.is_empty() || address.contains([',', '\'', '"', '\n', '\r']) {
+                return Err(format!("invalid listen_addresses entry: {}", address));
+            }
+        }
+        // `conn_info` points clients at 127.0.0.1, none at all leaves only the Unix socket
+        let loopback = ["*", "0.0.0.0", "localhost", "127.0.0.1"];
+        if !self.listen_addresses.is_empty()
+            && !self
+                .listen_addresses
+                .iter()
+                .any(|address| loopback.contains(&address.as_str()))
+        {
+            return Err(
+                "listen_addresses must include localhost, or be empty to disable TCP".to_string(),
+            );
+        }
+
+        for library in &self.shared_preload_libraries {
+            if library.is_empty() || library.contains([',', '\'', '"', '\n', '\r']) {
+                return Err(format!(
+                    "invalid shared_preload_libraries entry: {}",
+                    library
+                ));
+            }
+        }
+
+        for (key, value) in &self.settings {
+            let valid_key = key.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+                && key
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.');
+            if !valid_key {
+                return Err(format!("invalid setting name: {}", key));
+            }
+            if key.eq_ignore_ascii_case("port") {
+                return Err("port is assigned by quickpg".to_string());
+            }
+            // Names are case-insensitive to Postgres
+            if MANAGED_SETTINGS
+                .iter()
+                .any(|managed| key.eq_ignore_ascii_case(managed))
+            {
+                return Err(format!(
+                    "{} is managed by quickpg, use its own field or durability instead",
+                    key
+                ));
+            }
+            if value.contains(['\n', '\r']) {
+                return Err(format!("setting {} contains a newline", key));
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn to_config(&self) -> Config<'a> {
-        Config {
-            rows: vec![
-                KeyVal::str("listen_addresses", self.listen_addresses),
-                KeyVal::int("port", self.port),
-                KeyVal::int("max_connections", self.max_connections),
-                KeyVal::byte("shared_buffers", self.shared_buffers),
-                KeyVal::str("dynamic_shared_memory_type", "posix"),
-                KeyVal::byte("max_wal_size", self.max_wal_size),
-                KeyVal::byte("min_wal_size", self.min_wal_size),
-                KeyVal::str("log_timezone", self.timezone),
-                KeyVal::str("datestyle", "iso, mdy"),
-                KeyVal::str("timezone", self.timezone),
-                KeyVal::str("lc_messages", self.locale),
-                KeyVal::str("lc_monetary", self.locale),
-                KeyVal::str("lc_numeric", self.locale),
-                KeyVal::str("lc_time", self.locale),
-                KeyVal::str("default_text_search_config", "pg_catalog.english"),
-                // Crash unsafe performance settings
+        let mut rows = self.tunable_rows();
+        rows.extend(self.performance_rows());
+
+        if let Some(timeout) = self.checkpoint_timeout_ms {
+            rows.push(KeyVal::millis("checkpoint_timeout", timeout));
+        }
+        if let Some(target) = self.checkpoint_completion_target {
+            rows.push(KeyVal::float("checkpoint_completion_target", target));
+        }
+        if let Some(buffers) = self.wal_buffers {
+            rows.push(KeyVal::byte("wal_buffers", buffers));
+        }
+        if let Some(delay) = self.wal_writer_delay_ms {
+            rows.push(KeyVal::millis("wal_writer_delay", delay));
+        }
+
+        if self.archive {
+            rows.push(KeyVal::str("archive_mode", "on"));
+            rows.push(KeyVal::text(
+                "archive_command",
+                format!("test ! -f {dir}/%f && cp %p {dir}/%f", dir = ARCHIVE_DIR),
+            ));
+        }
+
+        if !self.shared_preload_libraries.is_empty() {
+            rows.push(KeyVal::text(
+                "shared_preload_libraries",
+                self.shared_preload_libraries.join(","),
+            ));
+        }
+
+        if let Some(ssl) = &self.ssl {
+            rows.push(KeyVal::str("ssl", "on"));
+            rows.push(KeyVal::text("ssl_cert_file", ssl.cert_file.clone()));
+            rows.push(KeyVal::text("ssl_key_file", ssl.key_file.clone()));
+        }
+
+        for (key, value) in &self.settings {
+            rows.push(KeyVal {
+                key: Cow::Owned(key.clone()),
+                val: Value::Text(value.clone()),
+            });
+        }
+
+        Config { rows }
+    }
+
+    /// Rows set through the `with_*` builders and public fields.
+    fn tunable_rows(&self) -> Vec<KeyVal<'a>> {
+        vec![
+            KeyVal::text("listen_addresses", self.listen_addresses.join(",")),
+            KeyVal::int("port", self.port),
+            KeyVal::int("max_connections", self.max_connections),
+            KeyVal::byte("shared_buffers", self.shared_buffers),
+            KeyVal::str("dynamic_shared_memory_type", "posix"),
+            KeyVal::byte("max_wal_size", self.max_wal_size),
+            KeyVal::byte("min_wal_size", self.min_wal_size),
+            KeyVal::str("log_timezone", self.timezone),
+            KeyVal::str("datestyle", "iso, mdy"),
+            KeyVal::str("timezone", self.timezone),
+            KeyVal::str("lc_messages", self.locale),
+            KeyVal::str("lc_monetary", self.locale),
+            KeyVal::str("lc_numeric", self.locale),
+            KeyVal::str("lc_time", self.locale),
+            KeyVal::str("default_text_search_config", "pg_catalog.english"),
+            KeyVal::str("synchronous_commit", self.synchronous_commit.as_str()),
+        ]
+    }
+
+    /// Only toggled as a whole by the durability mode.
+    fn performance_rows(&self) -> Vec<KeyVal<'a>> {
+        match self.durability {
+            // Archived WAL needs `replica`, and full pages to replay over a backup taken while running
+            Durability::Fast if self.archive => vec![
+                KeyVal::str("fsync", "off"),
+                KeyVal::str("full_page_writes", "on"),
+                KeyVal::str("wal_level", "replica"),
+                KeyVal::int("max_wal_senders", 10),
+            ],
+            // Crash unsafe performance settings
+            Durability::Fast => vec![
                 KeyVal::str("fsync", "off"),
                 KeyVal::str("full_page_writes", "off"),
-                KeyVal::str("synchronous_commit", "off"),
                 KeyVal::str("wal_level", "minimal"),
                 KeyVal::int("max_wal_senders", 0),
             ],
+            Durability::Safe => vec![
+                KeyVal::str("fsync", "on"),
+                KeyVal::str("full_page_writes", "on"),
+                KeyVal::str("wal_level", "replica"),
+                KeyVal::int("max_wal_senders", 10),
+            ],
+        }
+    }
+}
+
+/// Every row `to_config` can generate, which `settings` may not change behind quickpg's back.
+const MANAGED_SETTINGS: &[&str] = &[
+    "listen_addresses",
+    "port",
+    "max_connections",
+    "shared_buffers",
+    "dynamic_shared_memory_type",
+    "max_wal_size",
+    "min_wal_size",
+    "log_timezone",
+    "datestyle",
+    "timezone",
+    "lc_messages",
+    "lc_monetary",
+    "lc_numeric",
+    "lc_time",
+    "default_text_search_config",
+    "synchronous_commit",
+    "fsync",
+    "full_page_writes",
+    "wal_level",
+    "max_wal_senders",
+    "checkpoint_timeout",
+    "checkpoint_completion_target",
+    "wal_buffers",
+    "wal_writer_delay",
+    "archive_mode",
+    "archive_command",
+    "shared_preload_libraries",
+    "ssl",
+    "ssl_cert_file",
+    "ssl_key_file",
+];
+
+const KB: u128 = 1024;
+const MB: u128 = 1024 * KB;
+
+/// Reads a size the way Postgres does, `kB`, `MB`, `GB` and `TB` are powers of 1024. A bare
+/// number is in bytes.
+pub fn parse_byte(key: &str, size: &str) -> Result<Byte, String> {
+    let invalid = || {
+        format!(
+            "{}: invalid size {:?}, expected a number of B, kB, MB, GB or TB",
+            key, size
+        )
+    };
+
+    let size = size.trim();
+    let split = size
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(size.len());
+    let (number, unit) = size.split_at(split);
+    let number: f64 = number.parse().map_err(|_| invalid())?;
+    let multiplier = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "kb" | "kib" => KB,
+        "mb" | "mib" => MB,
+        "gb" | "gib" => 1024 * MB,
+        "tb" | "tib" => 1024 * 1024 * MB,
+        _ => return Err(invalid()),
+    };
+
+    let bytes = (number * multiplier as f64).round();
+    if !bytes.is_finite() || bytes > u64::MAX as f64 {
+        return Err(invalid());
+    }
+    Ok(Byte::from_bytes(bytes as u128))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shared_preload_libraries_are_one_quoted_list() {
+        let mut conf = PostgresqlConf::default(5432);
+        assert!(!conf
+            .to_config()
+            .to_strings()
+            .iter()
+            .any(|row| row.starts_with("shared_preload_libraries")));
+
+        conf.shared_preload_libraries =
+            vec!["pg_stat_statements".to_string(), "auto_explain".to_string()];
+        conf.validate().unwrap();
+        assert!(conf
+            .to_config()
+            .to_strings()
+            .contains(&"shared_preload_libraries = 'pg_stat_statements,auto_explain'".to_string()));
+    }
+
+    #[test]
+    fn listens_on_localhost_by_default() {
+        let rows = PostgresqlConf::default(5432).to_config().to_strings();
+        assert!(rows.contains(&"listen_addresses = 'localhost'".to_string()));
+
+        let mut conf = PostgresqlConf::default(5432);
+        conf.listen_addresses = vec!["*".to_string()];
+        conf.validate().unwrap();
+        assert!(conf
+            .to_config()
+            .to_strings()
+            .contains(&"listen_addresses = '*'".to_string()));
+
+        conf.listen_addresses = vec![];
+        conf.validate().unwrap();
+        assert!(conf
+            .to_config()
+            .to_strings()
+            .contains(&"listen_addresses = ''".to_string()));
+
+        conf.listen_addresses = vec!["10.0.0.1".to_string()];
+        assert!(conf.validate().is_err());
+    }
+
+    #[test]
+    fn settings_leave_managed_rows_alone() {
+        let mut conf = PostgresqlConf::default(5432);
+        conf.settings = vec![("work_mem".to_string(), "64MB".to_string())];
+        conf.validate().unwrap();
+        assert!(conf
+            .to_config()
+            .to_strings()
+            .contains(&"work_mem = '64MB'".to_string()));
+
+        for key in [
+            "fsync",
+            "Full_Page_Writes",
+            "wal_level",
+            "listen_addresses",
+            "PORT",
+        ] {
+            conf.settings = vec![(key.to_string(), "on".to_string())];
+            assert!(conf.validate().is_err(), "{} was accepted", key);
         }
     }
 }
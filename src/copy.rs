@@ -1,15 +1,33 @@
-use std::{io, path::PathBuf};
+use std::{
+    ffi::CString,
+    fs::File,
+    io::{self, Read, Write},
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{lchown, MetadataExt},
+        io::AsRawFd,
+    },
+    path::{Path, PathBuf},
+    process::Output,
+    sync::Arc,
+};
 
 use async_recursion::async_recursion;
-use tokio::task::JoinSet;
+use tokio::{
+    process::Command,
+    sync::{OwnedSemaphorePermit, Semaphore},
+    task::JoinSet,
+};
 
-const ROOT_FILES: &'static [&'static str] = &[
+const ROOT_FILES: &[&str] = &[
     "pg_hba.conf",
     "pg_ident.conf",
     "PG_VERSION",
     "postmaster.opts",
 ];
-const EMPTY_DIRS: &'static [&'static str] = &[
+/// Replication slots are left behind on purpose, a fork has no replicas and a copied slot would
+/// hold back WAL removal forever. `pg_tblspc` is only created empty, see `check_tablespaces`.
+const EMPTY_DIRS: &[&str] = &[
     "pg_commit_ts",
     "pg_dynshmem",
     "pg_notify",
@@ -20,7 +38,7 @@ const EMPTY_DIRS: &'static [&'static str] = &[
     "pg_tblspc",
     "pg_twophase",
 ];
-const SMALL_DIRS: &'static [&'static str] = &[
+const SMALL_DIRS: &[&str] = &[
     "global",
     "pg_logical",
     "pg_multixact",
@@ -29,11 +47,221 @@ const SMALL_DIRS: &'static [&'static str] = &[
     "pg_wal",
     "pg_xact",
 ];
-const LARGE_DIRS: &'static [&'static str] = &["base"];
+const LARGE_DIRS: &[&str] = &["base"];
+/// Written per fork, but an archive has to start on another machine as is
+const ARCHIVE_FILES: &[&str] = &["postgresql.conf", "postgresql.auto.conf", "quickpg.json"];
 
+/// `_IOW(0x94, 9, int)` from linux/fs.h
+const FICLONE: libc::c_ulong = 0x4004_9409;
+const BTRFS_SUPER_MAGIC: libc::__fsword_t = 0x9123_683E;
+const XFS_SUPER_MAGIC: libc::__fsword_t = 0x5846_5342;
+const EXT4_SUPER_MAGIC: libc::__fsword_t = 0xEF53;
+const TMPFS_MAGIC: libc::__fsword_t = 0x0102_1994;
+const OVERLAYFS_SUPER_MAGIC: libc::__fsword_t = 0x794C_7630;
+const ZFS_SUPER_MAGIC: libc::__fsword_t = 0x2FC1_2FC1;
+/// Inode number of the root directory of every Btrfs subvolume
+const BTRFS_SUBVOLUME_INO: u64 = 256;
+
+/// How files are duplicated into a fork's data dir.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum CopyStrategy {
+    /// Share extents copy-on-write where the filesystem supports it, copying otherwise
+    #[default]
+    Reflink,
+    Copy,
+    /// Snapshot the template's subvolume, falling back to `Reflink` when it isn't one
+    BtrfsSnapshot,
+}
+
+impl CopyStrategy {
+    /// Picks the fastest strategy for data dirs stored under `path`.
+    pub fn detect(path: &Path) -> CopyStrategy {
+        if is_btrfs(path) {
+            CopyStrategy::BtrfsSnapshot
+        } else {
+            CopyStrategy::Reflink
+        }
+    }
+}
+
+pub fn is_btrfs(path: &Path) -> bool {
+    filesystem_type(path) == Some(BTRFS_SUPER_MAGIC)
+}
+
+fn filesystem_type(path: &Path) -> Option<libc::__fsword_t> {
+    let path = CString::new(path.as_os_str().as_bytes()).ok()?;
+
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statfs(path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    Some(stat.f_type)
+}
+
+/// Name of the filesystem `path` lives on, for the ones quickpg is likely to run on.
+pub fn filesystem_name(path: &Path) -> &'static str {
+    match filesystem_type(path) {
+        Some(BTRFS_SUPER_MAGIC) => "btrfs",
+        Some(XFS_SUPER_MAGIC) => "xfs",
+        Some(EXT4_SUPER_MAGIC) => "ext4",
+        Some(TMPFS_MAGIC) => "tmpfs",
+        Some(OVERLAYFS_SUPER_MAGIC) => "overlayfs",
+        Some(ZFS_SUPER_MAGIC) => "zfs",
+        _ => "unknown",
+    }
+}
+
+fn is_subvolume(path: &Path) -> bool {
+    is_btrfs(path)
+        && std::fs::metadata(path)
+            .map(|meta| meta.ino() == BTRFS_SUBVOLUME_INO)
+            .unwrap_or(false)
+}
+
+fn check_btrfs(output: Output) -> io::Result<()> {
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(io::Error::other(
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ))
+    }
+}
+
+/// Creates `path` as an empty Btrfs subvolume, so forks of it can be snapshots.
+pub async fn create_subvolume(path: &Path) -> io::Result<()> {
+    let output = Command::new("btrfs")
+        .args(["subvolume", "create"])
+        .arg(path)
+        .output()
+        .await?;
+    check_btrfs(output)
+}
+
+async fn snapshot(source: &Path, destination: &Path) -> io::Result<()> {
+    let output = Command::new("btrfs")
+        .args(["subvolume", "snapshot"])
+        .arg(source)
+        .arg(destination)
+        .output()
+        .await?;
+    check_btrfs(output)
+}
+
+/// How a physical fork would duplicate the template's relation files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CopyMethod {
+    /// One copy-on-write snapshot of the whole data dir
+    Snapshot,
+    /// Shared extents, copied on write
+    Reflink,
+    /// Hardlinks for `base/`, reflinks or copies for the rest
+    Hardlink,
+    Copy,
+}
+
+impl CopyMethod {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CopyMethod::Snapshot => "snapshot",
+            CopyMethod::Reflink => "reflink",
+            CopyMethod::Hardlink => "hardlink",
+            CopyMethod::Copy => "copy",
+        }
+    }
+}
+
+/// What `copy_pgdata` would do with a template, without copying anything.
+#[derive(Debug)]
+pub struct CopyPlan {
+    pub filesystem: &'static str,
+    pub method: CopyMethod,
+    /// Apparent size of everything the fork gets from the template
+    pub bytes: u64,
+    /// Part of `bytes` that is hardlinked rather than copied
+    pub linked_bytes: u64,
+}
+
+/// Whether a physical fork gets its own copy of the template's relation files.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ForkMode {
+    #[default]
+    FullCopy,
+    /// Hardlinks everything under `base/`, so the template must never be started again
+    Hardlink,
+}
+
+fn reflink(source: &std::path::Path, destination: &std::path::Path) -> io::Result<()> {
+    let source_file = File::open(source)?;
+    let destination_file = File::create(destination)?;
+
+    if unsafe {
+        libc::ioctl(
+            destination_file.as_raw_fd(),
+            FICLONE,
+            source_file.as_raw_fd(),
+        )
+    } == -1
+    {
+        drop(destination_file);
+        let err = io::Error::last_os_error();
+        let _ = std::fs::remove_file(destination);
+        return Err(err);
+    }
+
+    destination_file.set_permissions(source_file.metadata()?.permissions())?;
+    Ok(())
+}
+
+/// Clones `source` with `FICLONE`, falling back to a full copy when the filesystem can't.
+async fn reflink_or_copy(source: PathBuf, destination: PathBuf) -> io::Result<()> {
+    let result = tokio::task::spawn_blocking({
+        let source = source.clone();
+        let destination = destination.clone();
+        move || reflink(&source, &destination)
+    })
+    .await
+    .map_err(|join_err| io::Error::other(format!("join_err: {}", join_err)))?;
+
+    match result {
+        Ok(()) => Ok(()),
+        Err(err)
+            if matches!(
+                err.raw_os_error(),
+                Some(libc::EOPNOTSUPP | libc::EXDEV | libc::EINVAL | libc::ENOTTY)
+            ) =>
+        {
+            tokio::fs::copy(source, destination).await.map(|_| ())
+        }
+        Err(err) => Err(err),
+    }
+}
+
+async fn copy_file(
+    strategy: CopyStrategy,
+    source: PathBuf,
+    destination: PathBuf,
+) -> io::Result<()> {
+    match strategy {
+        CopyStrategy::Reflink | CopyStrategy::BtrfsSnapshot => {
+            reflink_or_copy(source, destination).await
+        }
+        CopyStrategy::Copy => tokio::fs::copy(source, destination).await.map(|_| ()),
+    }
+}
+
+/// Copies the files of each directory in parallel, every file holds one of `permits` while it's
+/// copied. The walk itself holds none, so nested directories can't starve each other.
 #[async_recursion]
-async fn copy_internal(source: PathBuf, destination: PathBuf) -> io::Result<()> {
+async fn copy_internal(
+    source: PathBuf,
+    destination: PathBuf,
+    strategy: CopyStrategy,
+    dir_mode: u32,
+    permits: Arc<Semaphore>,
+) -> io::Result<()> {
     let mut dir = tokio::fs::read_dir(source).await?;
+    let mut set = JoinSet::new();
 
     while let Some(entry) = dir.next_entry().await? {
         let filetype = entry.file_type().await?;
@@ -41,33 +269,357 @@ async fn copy_internal(source: PathBuf, destination: PathBuf) -> io::Result<()>
 
         if filetype.is_dir() {
             tokio::fs::DirBuilder::new()
-                .mode(0o700)
+                .mode(dir_mode)
                 .create(&new_path)
                 .await?;
-            copy_internal(entry.path(), new_path).await?;
+            copy_internal(entry.path(), new_path, strategy, dir_mode, permits.clone()).await?;
         } else {
-            tokio::fs::copy(entry.path(), new_path).await?;
+            let permit = acquire(&permits).await?;
+            set.spawn(async move {
+                let _permit = permit;
+                copy_file(strategy, entry.path(), new_path).await
+            });
+        }
+    }
+
+    join_all(set).await
+}
+
+#[async_recursion]
+async fn link_internal(source: PathBuf, destination: PathBuf, dir_mode: u32) -> io::Result<()> {
+    let mut dir = tokio::fs::read_dir(source).await?;
+
+    while let Some(entry) = dir.next_entry().await? {
+        let filetype = entry.file_type().await?;
+        let new_path = destination.join(entry.file_name());
+
+        if filetype.is_dir() {
+            tokio::fs::DirBuilder::new()
+                .mode(dir_mode)
+                .create(&new_path)
+                .await?;
+            link_internal(entry.path(), new_path, dir_mode).await?;
+        } else {
+            tokio::fs::hard_link(entry.path(), new_path).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Sums the apparent size of every file under `path`.
+#[async_recursion]
+pub async fn dir_size(path: PathBuf) -> io::Result<u64> {
+    let mut dir = tokio::fs::read_dir(path).await?;
+    let mut total = 0;
+
+    while let Some(entry) = dir.next_entry().await? {
+        let filetype = entry.file_type().await?;
+
+        if filetype.is_dir() {
+            total += dir_size(entry.path()).await?;
+        } else if filetype.is_file() {
+            total += entry.metadata().await?.len();
+        }
+    }
+
+    Ok(total)
+}
+
+fn chown_internal(path: &std::path::Path, uid: u32, gid: u32) -> io::Result<()> {
+    lchown(path, Some(uid), Some(gid))?;
+
+    if std::fs::symlink_metadata(path)?.is_dir() {
+        for entry in std::fs::read_dir(path)? {
+            chown_internal(&entry?.path(), uid, gid)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn same_contents(a: &Path, b: &Path) -> io::Result<bool> {
+    let mut a = File::open(a)?;
+    let mut b = File::open(b)?;
+    if a.metadata()?.len() != b.metadata()?.len() {
+        return Ok(false);
+    }
+
+    let mut a_buf = vec![0; 64 * 1024];
+    let mut b_buf = vec![0; 64 * 1024];
+    loop {
+        let read = a.read(&mut a_buf)?;
+        if read == 0 {
+            return Ok(true);
+        }
+        b.read_exact(&mut b_buf[..read])?;
+        if a_buf[..read] != b_buf[..read] {
+            return Ok(false);
+        }
+    }
+}
+
+fn verify_internal(source: &Path, destination: &Path) -> io::Result<Option<PathBuf>> {
+    for entry in std::fs::read_dir(destination)? {
+        let entry = entry?;
+        let filetype = entry.file_type()?;
+        let source = source.join(entry.file_name());
+
+        if filetype.is_dir() {
+            if let Some(mismatch) = verify_internal(&source, &entry.path())? {
+                return Ok(Some(mismatch));
+            }
+        } else if filetype.is_file() && !same_contents(&source, &entry.path())? {
+            return Ok(Some(entry.path()));
         }
     }
 
+    Ok(None)
+}
+
+/// Compares every file copied into `destination` with its original, returning the first that
+/// differs. Reads both sides in full, so it doubles the I/O of a fork.
+pub async fn verify_copy(source: PathBuf, destination: PathBuf) -> io::Result<Option<PathBuf>> {
+    tokio::task::spawn_blocking(move || verify_internal(&source, &destination))
+        .await
+        .map_err(|join_err| io::Error::other(format!("join_err: {}", join_err)))?
+}
+
+fn sync_internal(path: &Path) -> io::Result<()> {
+    let metadata = std::fs::symlink_metadata(path)?;
+
+    if metadata.is_dir() {
+        for entry in std::fs::read_dir(path)? {
+            sync_internal(&entry?.path())?;
+        }
+    } else if !metadata.is_file() {
+        return Ok(());
+    }
+
+    // Directories are opened read only too, fsync on them flushes their entries
+    File::open(path)?.sync_all()
+}
+
+/// Flushes every file and directory under `path` to disk, then the entry for `path` itself in
+/// its parent, so a fork survives a power failure once this returns.
+pub async fn sync_recursive(path: PathBuf) -> io::Result<()> {
+    tokio::task::spawn_blocking(move || {
+        sync_internal(&path)?;
+        match path.parent() {
+            Some(parent) => File::open(parent)?.sync_all(),
+            None => Ok(()),
+        }
+    })
+    .await
+    .map_err(|join_err| io::Error::other(format!("join_err: {}", join_err)))?
+}
+
+/// Recursively hands ownership of `path` to `uid`/`gid`, without following symlinks.
+pub async fn chown_recursive(path: PathBuf, uid: u32, gid: u32) -> io::Result<()> {
+    tokio::task::spawn_blocking(move || chown_internal(&path, uid, gid))
+        .await
+        .map_err(|join_err| io::Error::other(format!("join_err: {}", join_err)))?
+}
+
+fn write_atomic_internal(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "path has no file name"))?;
+    let mut temp_name = std::ffi::OsString::from(".");
+    temp_name.push(name);
+    temp_name.push(".tmp");
+    let temp = path.with_file_name(temp_name);
+
+    let mut file = File::create(&temp)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+
+    // A file created by this process would otherwise take over from a chowned original
+    if let Ok(existing) = std::fs::metadata(path) {
+        std::fs::set_permissions(&temp, existing.permissions())?;
+        let created = file.metadata()?;
+        if (created.uid(), created.gid()) != (existing.uid(), existing.gid()) {
+            lchown(&temp, Some(existing.uid()), Some(existing.gid()))?;
+        }
+    }
+
+    std::fs::rename(&temp, path)?;
+    if let Some(parent) = path.parent() {
+        File::open(parent)?.sync_all()?;
+    }
     Ok(())
 }
 
-pub async fn copy_pgdata(source: PathBuf, destination: PathBuf) -> io::Result<()> {
+/// Replaces `path` through a temporary file and a rename, so a crash leaves either the old or
+/// the new contents and never a truncated file.
+pub async fn write_atomic(path: PathBuf, contents: Vec<u8>) -> io::Result<()> {
+    tokio::task::spawn_blocking(move || write_atomic_internal(&path, &contents))
+        .await
+        .map_err(|join_err| io::Error::other(format!("join_err: {}", join_err)))?
+}
+
+/// Tablespaces live outside the data dir behind symlinks in `pg_tblspc`. Copying the symlinks
+/// would have the fork write into the template's files, so such templates can't be forked.
+pub async fn check_tablespaces(source: &Path) -> io::Result<()> {
+    let mut dir = tokio::fs::read_dir(source.join("pg_tblspc")).await?;
+    if let Some(entry) = dir.next_entry().await? {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!(
+                "template uses tablespace {}, which forks can't copy",
+                entry.file_name().to_string_lossy()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+pub const DEFAULT_DIR_MODE: u32 = 0o700;
+
+/// Postgres only accepts data dirs its owner can fully access and nobody else can write to.
+pub fn validate_dir_mode(mode: u32) -> Result<(), String> {
+    if mode & 0o700 != 0o700 || mode & 0o7027 != 0 {
+        return Err(format!(
+            "invalid data dir mode {:o}, Postgres needs 700, or 750 for group access",
+            mode
+        ));
+    }
+    Ok(())
+}
+
+/// Default number of files copied at once, enough to keep a fast disk busy.
+pub fn default_concurrency() -> usize {
+    std::thread::available_parallelism()
+        .map(|cpus| cpus.get() * 4)
+        .unwrap_or(16)
+}
+
+async fn acquire(permits: &Arc<Semaphore>) -> io::Result<OwnedSemaphorePermit> {
+    permits
+        .clone()
+        .acquire_owned()
+        .await
+        .map_err(|err| io::Error::other(format!("acquire_err: {}", err)))
+}
+
+/// Waits for every task, returning the first error. Dropping the set aborts the rest.
+async fn join_all(mut set: JoinSet<io::Result<()>>) -> io::Result<()> {
+    while let Some(value) = set.join_next().await {
+        match value {
+            Err(join_err) => return Err(io::Error::other(format!("join_err: {}", join_err))),
+            Ok(Err(io_err)) => return Err(io_err),
+            Ok(_) => (),
+        }
+    }
+
+    Ok(())
+}
+
+/// `tar` arguments for what `copy_pgdata` would copy out of `source`, relative to it, plus the
+/// config and metadata. Empty dirs are added without their contents.
+pub fn tar_entries(source: &Path) -> Vec<String> {
+    let files = ROOT_FILES.iter().chain(ARCHIVE_FILES);
+    let dirs = SMALL_DIRS.iter().chain(LARGE_DIRS);
+
+    let mut entries: Vec<String> = files
+        .chain(dirs)
+        .filter(|entry| source.join(entry).exists())
+        .map(|entry| entry.to_string())
+        .collect();
+    entries.push("--no-recursion".to_string());
+    entries.extend(
+        EMPTY_DIRS
+            .iter()
+            .filter(|dir| source.join(dir).is_dir())
+            .map(|dir| dir.to_string()),
+    );
+    entries
+}
+
+/// Walks `source` the way `copy_pgdata` would, to report its size and how it would be copied.
+pub async fn plan_pgdata(
+    source: PathBuf,
+    strategy: CopyStrategy,
+    mode: ForkMode,
+) -> io::Result<CopyPlan> {
+    check_tablespaces(&source).await?;
+
+    let filesystem = filesystem_name(&source);
+    let mut bytes = 0;
+    for file in ROOT_FILES {
+        bytes += tokio::fs::metadata(source.join(file)).await?.len();
+    }
+    for dir in SMALL_DIRS {
+        bytes += dir_size(source.join(dir)).await?;
+    }
+    let mut large_bytes = 0;
+    for dir in LARGE_DIRS {
+        large_bytes += dir_size(source.join(dir)).await?;
+    }
+
+    let reflinks = matches!(filesystem, "btrfs" | "xfs");
+    let method = if strategy == CopyStrategy::BtrfsSnapshot && is_subvolume(&source) {
+        CopyMethod::Snapshot
+    } else if mode == ForkMode::Hardlink {
+        CopyMethod::Hardlink
+    } else if strategy != CopyStrategy::Copy && reflinks {
+        CopyMethod::Reflink
+    } else {
+        CopyMethod::Copy
+    };
+
+    Ok(CopyPlan {
+        filesystem,
+        method,
+        bytes: bytes + large_bytes,
+        linked_bytes: match method {
+            CopyMethod::Hardlink => large_bytes,
+            _ => 0,
+        },
+    })
+}
+
+/// Copies `source` into `destination`, with at most `concurrency` files, or hardlinked
+/// directories, in flight.
+pub async fn copy_pgdata(
+    source: PathBuf,
+    destination: PathBuf,
+    strategy: CopyStrategy,
+    mode: ForkMode,
+    concurrency: usize,
+    dir_mode: u32,
+) -> io::Result<()> {
+    check_tablespaces(&source).await?;
+
+    // Snapshots are copy-on-write, so they are also safe to use in place of hardlinks
+    if strategy == CopyStrategy::BtrfsSnapshot && is_subvolume(&source) {
+        match snapshot(&source, &destination).await {
+            Ok(()) => return Ok(()),
+            Err(err) => tracing::warn!(
+                "btrfs snapshot of {} failed, copying instead: {}",
+                source.display(),
+                err
+            ),
+        }
+    }
+
     tokio::fs::DirBuilder::new()
         .recursive(true)
-        .mode(0o700)
+        .mode(dir_mode)
         .create(&destination)
         .await?;
 
+    let permits = Arc::new(Semaphore::new(concurrency.max(1)));
     let mut set = JoinSet::new();
 
     set.spawn({
         let source = source.clone();
         let destination = destination.clone();
+        let permits = permits.clone();
         async move {
+            let _permit = acquire(&permits).await?;
             for file in ROOT_FILES {
-                tokio::fs::copy(source.join(file), destination.join(file)).await?;
+                copy_file(strategy, source.join(file), destination.join(file)).await?;
             }
             Result::<(), io::Error>::Ok(())
         }
@@ -75,10 +627,12 @@ pub async fn copy_pgdata(source: PathBuf, destination: PathBuf) -> io::Result<()
 
     set.spawn({
         let destination = destination.clone();
+        let permits = permits.clone();
         async move {
+            let _permit = acquire(&permits).await?;
             for dir in EMPTY_DIRS {
                 tokio::fs::DirBuilder::new()
-                    .mode(0o700)
+                    .mode(dir_mode)
                     .create(destination.join(dir))
                     .await?;
             }
@@ -89,47 +643,253 @@ pub async fn copy_pgdata(source: PathBuf, destination: PathBuf) -> io::Result<()
     for dir in SMALL_DIRS {
         let source = source.join(dir);
         let destination = destination.join(dir);
+        let permits = permits.clone();
         set.spawn(async move {
             tokio::fs::DirBuilder::new()
-                .mode(0o700)
+                .mode(dir_mode)
                 .create(&destination)
                 .await?;
-            copy_internal(source, destination).await
+            copy_internal(source, destination, strategy, dir_mode, permits).await
         });
     }
 
     for dir in LARGE_DIRS {
         let mut reader = tokio::fs::read_dir(source.join(dir)).await?;
         tokio::fs::DirBuilder::new()
-            .mode(0o700)
+            .mode(dir_mode)
             .create(&destination.join(dir))
             .await?;
 
         while let Some(entry) = reader.next_entry().await? {
             let nested_source = source.join(dir).join(entry.file_name());
             let nested_destination = destination.join(dir).join(entry.file_name());
+            let permits = permits.clone();
             set.spawn(async move {
                 tokio::fs::DirBuilder::new()
-                    .mode(0o700)
+                    .mode(dir_mode)
                     .create(&nested_destination)
                     .await?;
-                copy_internal(nested_source, nested_destination).await
+                match mode {
+                    ForkMode::FullCopy => {
+                        copy_internal(
+                            nested_source,
+                            nested_destination,
+                            strategy,
+                            dir_mode,
+                            permits,
+                        )
+                        .await
+                    }
+                    ForkMode::Hardlink => {
+                        let _permit = acquire(&permits).await?;
+                        link_internal(nested_source, nested_destination, dir_mode).await
+                    }
+                }
             });
         }
     }
 
-    while let Some(value) = set.join_next().await {
-        match value {
-            Err(join_err) => {
-                return Err(io::Error::new(
-                    io::ErrorKind::Other,
-                    format!("join_err: {}", join_err),
-                ))
+    join_all(set).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A data dir with every file and directory `copy_pgdata` expects, and two databases.
+    fn fake_pgdata(root: &Path) -> PathBuf {
+        let source = root.join("template");
+        std::fs::create_dir(&source).unwrap();
+        for file in ROOT_FILES {
+            std::fs::write(source.join(file), file).unwrap();
+        }
+        for dir in EMPTY_DIRS {
+            std::fs::create_dir(source.join(dir)).unwrap();
+        }
+        for dir in SMALL_DIRS {
+            std::fs::create_dir(source.join(dir)).unwrap();
+            std::fs::write(source.join(dir).join("0000"), dir).unwrap();
+        }
+        std::fs::write(source.join("global/pg_control"), "pg_control").unwrap();
+        for database in ["1", "5"] {
+            let dir = source.join("base").join(database);
+            std::fs::create_dir_all(&dir).unwrap();
+            for relation in ["1259", "2608"] {
+                std::fs::write(dir.join(relation), relation.repeat(1024)).unwrap();
             }
-            Ok(Err(io_err)) => return Err(io_err),
-            Ok(_) => (),
         }
+        source
     }
 
-    Ok(())
+    fn ino(path: &Path) -> u64 {
+        std::fs::metadata(path).unwrap().ino()
+    }
+
+    #[tokio::test]
+    async fn hardlink_fork_shares_relation_files() {
+        let root = tempfile::tempdir().unwrap();
+        let source = fake_pgdata(root.path());
+        let destination = root.path().join("fork");
+
+        copy_pgdata(
+            source.clone(),
+            destination.clone(),
+            CopyStrategy::Copy,
+            ForkMode::Hardlink,
+            default_concurrency(),
+            DEFAULT_DIR_MODE,
+        )
+        .await
+        .unwrap();
+
+        for relation in ["base/1/1259", "base/5/2608"] {
+            assert_eq!(
+                ino(&source.join(relation)),
+                ino(&destination.join(relation))
+            );
+        }
+        // The fork writes to these, so they must be its own
+        for file in ["global/pg_control", "pg_wal/0000", "PG_VERSION"] {
+            assert_ne!(ino(&source.join(file)), ino(&destination.join(file)));
+            assert_eq!(
+                std::fs::read(source.join(file)).unwrap(),
+                std::fs::read(destination.join(file)).unwrap()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn fork_with_one_file_at_a_time() {
+        let root = tempfile::tempdir().unwrap();
+        let source = fake_pgdata(root.path());
+
+        for (name, mode) in [("copy", ForkMode::FullCopy), ("link", ForkMode::Hardlink)] {
+            let destination = root.path().join(name);
+            // Would hang rather than fail if a task waited on a permit its parent holds
+            tokio::time::timeout(
+                std::time::Duration::from_secs(10),
+                copy_pgdata(
+                    source.clone(),
+                    destination.clone(),
+                    CopyStrategy::Copy,
+                    mode,
+                    1,
+                    DEFAULT_DIR_MODE,
+                ),
+            )
+            .await
+            .unwrap()
+            .unwrap();
+
+            assert_eq!(
+                verify_copy(source.clone(), destination).await.unwrap(),
+                None
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_write_keeps_the_old_file() {
+        let root = tempfile::tempdir().unwrap();
+        let path = root.path().join("quickpg.json");
+        write_atomic(path.clone(), b"old".to_vec()).await.unwrap();
+
+        // What a crash halfway through a write leaves behind
+        let temp = root.path().join(".quickpg.json.tmp");
+        std::fs::write(&temp, b"ne").unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"old");
+
+        // A directory in the temp file's place makes the next write fail before the rename
+        std::fs::remove_file(&temp).unwrap();
+        std::fs::create_dir(&temp).unwrap();
+        assert!(write_atomic(path.clone(), b"new".to_vec()).await.is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), b"old");
+
+        std::fs::remove_dir(&temp).unwrap();
+        write_atomic(path.clone(), b"new".to_vec()).await.unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"new");
+        assert!(!temp.exists());
+    }
+
+    #[test]
+    fn dir_mode_must_keep_others_out() {
+        assert!(validate_dir_mode(0o700).is_ok());
+        assert!(validate_dir_mode(0o750).is_ok());
+        for mode in [0o755, 0o770, 0o600, 0o777, 0o2750] {
+            assert!(validate_dir_mode(mode).is_err(), "{:o} was accepted", mode);
+        }
+    }
+
+    #[tokio::test]
+    async fn forked_dirs_get_the_configured_mode() {
+        let root = tempfile::tempdir().unwrap();
+        let source = fake_pgdata(root.path());
+        let destination = root.path().join("fork");
+
+        copy_pgdata(
+            source,
+            destination.clone(),
+            CopyStrategy::Copy,
+            ForkMode::FullCopy,
+            default_concurrency(),
+            0o750,
+        )
+        .await
+        .unwrap();
+
+        for dir in ["", "base", "base/1", "global", "pg_wal", "pg_tblspc"] {
+            let mode = std::fs::metadata(destination.join(dir)).unwrap().mode();
+            assert_eq!(mode & 0o7777, 0o750, "{} has mode {:o}", dir, mode);
+        }
+    }
+
+    /// One database of many relations, the case per-database parallelism can't help with.
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "benchmark, run with cargo test -- --ignored --nocapture"]
+    async fn parallel_copy_of_one_large_database() {
+        let root = tempfile::tempdir().unwrap();
+        let source = fake_pgdata(root.path());
+        let database = source.join("base/16384");
+        std::fs::create_dir(&database).unwrap();
+        let relation = vec![7; 1024 * 1024];
+        for file in 0..512 {
+            std::fs::write(database.join(file.to_string()), &relation).unwrap();
+        }
+
+        let mut elapsed = vec![];
+        for concurrency in [1, default_concurrency()] {
+            let destination = root.path().join(format!("fork-{}", concurrency));
+            let started = std::time::Instant::now();
+            copy_pgdata(
+                source.clone(),
+                destination.clone(),
+                CopyStrategy::Copy,
+                ForkMode::FullCopy,
+                concurrency,
+                DEFAULT_DIR_MODE,
+            )
+            .await
+            .unwrap();
+            elapsed.push(started.elapsed());
+
+            assert_eq!(
+                verify_copy(source.clone(), destination).await.unwrap(),
+                None
+            );
+        }
+
+        println!("serial: {:?}, parallel: {:?}", elapsed[0], elapsed[1]);
+    }
+
+    #[tokio::test]
+    async fn sync_walks_a_whole_data_dir() {
+        let root = tempfile::tempdir().unwrap();
+        let source = fake_pgdata(root.path());
+        // Only files and dirs are opened, a dangling symlink would fail otherwise
+        std::os::unix::fs::symlink("/nonexistent", source.join("pg_wal/dangling")).unwrap();
+
+        sync_recursive(source.clone()).await.unwrap();
+        sync_recursive(source.join("PG_VERSION")).await.unwrap();
+        assert!(sync_recursive(root.path().join("missing")).await.is_err());
+    }
 }
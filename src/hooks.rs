@@ -0,0 +1,91 @@
+use std::{env, fmt, io, path::PathBuf, time::Duration};
+
+use tokio::process::Command;
+
+use crate::pg_ctl::Status;
+
+const HOOK_ENV: &str = "QUICKPG_POST_START_HOOK";
+const HOOK_STRICT_ENV: &str = "QUICKPG_POST_START_HOOK_STRICT";
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Failed(Option<i32>, String),
+    Timeout(Duration),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(formatter, "io: {}", err),
+            Error::Failed(Some(code), stderr) => {
+                write!(formatter, "exited with {}: {}", code, stderr)
+            }
+            Error::Failed(None, stderr) => write!(formatter, "killed by signal: {}", stderr),
+            Error::Timeout(timeout) => write!(formatter, "timed out after {:?}", timeout),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+/// An external command run after an instance successfully starts.
+///
+/// The command receives the instance id, port and dbname both as arguments and as
+/// `QUICKPG_INSTANCE_ID`, `QUICKPG_PORT` and `QUICKPG_DBNAME` env vars.
+#[derive(Debug)]
+pub struct PostStartHook {
+    command: PathBuf,
+    pub strict: bool,
+}
+
+impl PostStartHook {
+    pub fn from_env() -> Option<PostStartHook> {
+        let command = env::var_os(HOOK_ENV)?;
+        let strict = env::var(HOOK_STRICT_ENV)
+            .map(|value| value == "true" || value == "1")
+            .unwrap_or(false);
+
+        Some(PostStartHook {
+            command: command.into(),
+            strict,
+        })
+    }
+
+    /// Kills the command once it runs longer than `timeout`.
+    pub async fn run(&self, status: &Status, timeout: Duration) -> Result<(), Error> {
+        let port = status.port.to_string();
+        let output = Command::new(&self.command)
+            .args([&status.id, &port, &status.dbname])
+            .env("QUICKPG_INSTANCE_ID", &status.id)
+            .env("QUICKPG_PORT", &port)
+            .env("QUICKPG_DBNAME", &status.dbname)
+            .kill_on_drop(true)
+            .output();
+        let output = tokio::time::timeout(timeout, output)
+            .await
+            .map_err(|_| Error::Timeout(timeout))??;
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        tracing::info!(
+            "post-start hook for {}: stdout={:?} stderr={:?}",
+            status.id,
+            stdout.trim(),
+            stderr.trim()
+        );
+
+        if output.status.success() {
+            Ok(())
+        } else {
+            Err(Error::Failed(
+                output.status.code(),
+                stderr.trim().to_string(),
+            ))
+        }
+    }
+}
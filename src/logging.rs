@@ -0,0 +1,87 @@
+use std::env;
+
+use axum::{
+    http::{HeaderValue, Request},
+    middleware::Next,
+    response::Response,
+};
+use rand::distributions::{Alphanumeric, DistString};
+use tracing::{field, Span};
+use tracing_subscriber::EnvFilter;
+
+const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Sets up `tracing-subscriber` filtered by `RUST_LOG`, writing one JSON object per line when
+/// `QUICKPG_LOG_FORMAT=json`. Records from the `log` crate are forwarded too.
+pub fn init() {
+    let subscriber = tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::from_default_env())
+        .with_writer(std::io::stderr);
+
+    if env::var("QUICKPG_LOG_FORMAT").as_deref() == Ok("json") {
+        subscriber
+            .json()
+            .with_current_span(true)
+            .with_span_list(false)
+            .init();
+    } else {
+        subscriber.init();
+    }
+}
+
+/// Tags the rest of the request with an instance whose id wasn't in its path.
+pub fn set_instance(id: &str) {
+    Span::current().record("instance_id", id);
+}
+
+/// The `:id` of `/pg/instance/:id/...` routes.
+fn instance_from_path(path: &str) -> Option<&str> {
+    let id = path.strip_prefix("/pg/instance/")?.split('/').next()?;
+    match id {
+        "" | "swap" | "import" => None,
+        id => Some(id),
+    }
+}
+
+/// The span every event logged while handling `request` belongs to, carrying its
+/// `request_id` and, once known, the `instance_id` it works on.
+pub fn request_span<B>(request: &Request<B>) -> Span {
+    let request_id = request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+
+    let span = tracing::info_span!(
+        "request",
+        method = %request.method(),
+        uri = %request.uri(),
+        request_id,
+        instance_id = field::Empty,
+    );
+    if let Some(id) = instance_from_path(request.uri().path()) {
+        span.record("instance_id", id);
+    }
+    span
+}
+
+/// Gives each request an id, reusing the caller's `x-request-id` when there is one, and echoes
+/// it back in the response. Runs outside the trace layer, so `request_span` sees the id.
+pub async fn request_context<B>(mut request: Request<B>, next: Next<B>) -> Response {
+    let request_id = request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .filter(|value| !value.is_empty() && value.to_str().is_ok())
+        .cloned()
+        .unwrap_or_else(|| {
+            let id = Alphanumeric.sample_string(&mut rand::thread_rng(), 16);
+            HeaderValue::from_str(&id).unwrap()
+        });
+    request
+        .headers_mut()
+        .insert(REQUEST_ID_HEADER, request_id.clone());
+
+    let mut response = next.run(request).await;
+    response.headers_mut().insert(REQUEST_ID_HEADER, request_id);
+    response
+}
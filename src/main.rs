@@ -1,15 +1,50 @@
+mod args;
+mod auth;
+mod cgroup;
 mod config;
 mod copy;
+mod hooks;
+mod logging;
+mod metrics;
 mod pg_ctl;
+mod rate_limit;
+mod reaper;
+mod timestamp;
+mod tls;
 
-use axum::{extract::Path, http::StatusCode, response::IntoResponse, routing, Json, Router};
-use portpicker;
+use std::{
+    collections::HashMap,
+    env,
+    future::Future,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    time::Duration,
+};
+
+use axum::{
+    body::{Body, Bytes, HttpBody},
+    extract::{Path, Query, RawBody, State},
+    http::{header, HeaderMap, HeaderValue, Method, StatusCode},
+    middleware,
+    response::{IntoResponse, Response},
+    routing, Json, Router,
+};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
 use rand::distributions::{Alphanumeric, DistString};
+use regex::Regex;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
+use tokio::{sync::OwnedMutexGuard, task::JoinSet};
+use tracing::Instrument;
 
 use pg_ctl::Status;
-use tower_http::trace::TraceLayer;
+use tower_http::{
+    cors::{AllowOrigin, CorsLayer},
+    trace::TraceLayer,
+};
 
 #[derive(Debug, Deserialize, Serialize)]
 struct InstanceId {
@@ -24,15 +59,208 @@ impl InstanceId {
 
 #[derive(Debug, Deserialize, Serialize)]
 struct InstanceDescriptor {
+    /// Makes `create` idempotent, a random id is generated when missing
+    id: Option<String>,
     dbname: String,
+    /// `fast` by default, `safe` keeps the data dir intact through crashes
+    #[serde(default)]
+    durability: config::Durability,
+    synchronous_commit: Option<config::SynchronousCommit>,
+    memory_limit: Option<String>,
+    cpu_limit: Option<f64>,
+    max_connections: Option<u32>,
+    shared_buffers: Option<String>,
+    max_wal_size: Option<String>,
+    min_wal_size: Option<String>,
+    checkpoint_timeout_seconds: Option<u32>,
+    checkpoint_completion_target: Option<f64>,
+    wal_buffers: Option<String>,
+    wal_writer_delay_ms: Option<u32>,
+    /// Interfaces Postgres listens on, only `localhost` when missing, `["*"]` opens it to the network
+    listen_addresses: Option<Vec<String>>,
+    /// Libraries such as `pg_stat_statements` loaded at server start
+    #[serde(default)]
+    shared_preload_libraries: Vec<String>,
+    /// Keeps every WAL segment in the data dir, turning on `wal_level = replica`
+    #[serde(default)]
+    archive_wal: bool,
+    /// Raw `postgresql.conf` settings for anything the generated config leaves out
+    settings: Option<HashMap<String, String>>,
+    /// Destroys the instance automatically once this many seconds have passed
+    ttl_seconds: Option<u64>,
+    /// Postgres major version, resolved to `bin/<version>/pg_ctl`
+    version: Option<String>,
+    #[serde(default)]
+    extensions: Vec<String>,
+    /// Seed script run against the new database
+    init_sql: Option<String>,
+    /// Login role created to own the database, the server's user when missing
+    owner: Option<String>,
+    /// Only with `owner`, connecting as it then requires the password
+    password: Option<pg_ctl::Password>,
+    #[serde(default)]
+    labels: HashMap<String, String>,
+    /// Database `dbname` is copied from, such as `template0`, instead of `template1`
+    template_database: Option<String>,
+    /// `encoding`, `locale`, `lc_collate` and `lc_ctype` for initdb
+    #[serde(flatten)]
+    initdb: config::InitdbOptions,
+}
+
+impl InstanceDescriptor {
+    fn conf<'a>(&self, port: u32) -> Result<config::PostgresqlConf<'a>> {
+        let mut conf = config::PostgresqlConf::default(port).with_durability(self.durability);
+        conf.ssl = ssl_files();
+
+        if let Some(max_connections) = self.max_connections {
+            conf = conf.with_max_connections(max_connections);
+        }
+        if let Some(size) = &self.shared_buffers {
+            conf = conf
+                .with_shared_buffers(size)
+                .map_err(ApiError::InvalidParams)?;
+        }
+        if let Some(size) = &self.max_wal_size {
+            conf = conf
+                .with_max_wal_size(size)
+                .map_err(ApiError::InvalidParams)?;
+        }
+        if let Some(size) = &self.min_wal_size {
+            conf = conf
+                .with_min_wal_size(size)
+                .map_err(ApiError::InvalidParams)?;
+        }
+
+        if let Some(synchronous_commit) = self.synchronous_commit {
+            conf.synchronous_commit = synchronous_commit;
+        }
+
+        conf.checkpoint_timeout_ms = self
+            .checkpoint_timeout_seconds
+            .map(|seconds| seconds.saturating_mul(1000));
+        conf.checkpoint_completion_target = self.checkpoint_completion_target;
+        conf.wal_buffers = match &self.wal_buffers {
+            Some(buffers) => {
+                Some(config::parse_byte("wal_buffers", buffers).map_err(ApiError::InvalidParams)?)
+            }
+            None => None,
+        };
+        conf.wal_writer_delay_ms = self.wal_writer_delay_ms;
+        if let Some(addresses) = &self.listen_addresses {
+            conf.listen_addresses = addresses.clone();
+        }
+        conf.shared_preload_libraries = self.shared_preload_libraries.clone();
+        conf.archive = self.archive_wal;
+        if let Some(settings) = &self.settings {
+            conf.settings = settings
+                .iter()
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect();
+            conf.settings.sort();
+        }
+
+        conf.validate().map_err(ApiError::InvalidParams)?;
+        Ok(conf)
+    }
+
+    fn limits(&self) -> Result<Option<cgroup::ResourceLimits>> {
+        let memory_bytes = match &self.memory_limit {
+            Some(limit) => Some(
+                config::parse_byte("memory_limit", limit)
+                    .map_err(ApiError::InvalidParams)?
+                    .get_bytes() as u64,
+            ),
+            None => None,
+        };
+
+        if let Some(cores) = self.cpu_limit {
+            if !cores.is_finite() || cores <= 0.0 {
+                return Err(ApiError::InvalidParams(
+                    "cpu_limit must be a positive number of cores".to_string(),
+                ));
+            }
+        }
+
+        let limits = cgroup::ResourceLimits {
+            memory_bytes,
+            cpu_cores: self.cpu_limit,
+        };
+
+        if limits.is_empty() {
+            return Ok(None);
+        }
+        if !cgroup::is_available() {
+            tracing::warn!("cgroup v2 is unavailable, resource limits will not be enforced");
+        }
+
+        Ok(Some(limits))
+    }
+
+    fn owner(&self) -> Result<Option<pg_ctl::Role>> {
+        match (&self.owner, &self.password) {
+            (Some(name), password) if !name.is_empty() => Ok(Some(pg_ctl::Role {
+                name: name.clone(),
+                password: password.clone(),
+            })),
+            (Some(_), _) => Err(ApiError::InvalidParams("owner is empty".to_string())),
+            (None, Some(_)) => Err(ApiError::InvalidParams(
+                "password requires an owner".to_string(),
+            )),
+            (None, None) => Ok(None),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum ForkMode {
+    #[default]
+    Physical,
+    Logical,
+    /// Physical fork that hardlinks the template's relation files instead of copying them
+    Hardlink,
+    /// Physical fork of a running template using `pg_basebackup`
+    Online,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct ForkParams {
+    #[serde(default)]
+    mode: ForkMode,
+    #[serde(default)]
+    schema_only: bool,
+    /// Stops a running template for the duration of a physical fork instead of erroring
+    #[serde(default)]
+    stop_template: bool,
+    #[serde(default, rename = "async")]
+    background: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct CreateParams {
+    #[serde(default, rename = "async")]
+    background: bool,
 }
 
 #[derive(Debug)]
 enum ApiError {
     PgCtl(pg_ctl::Error),
     NotFound(Json<InstanceId>),
-    FailedToStart(Json<InstanceId>),
+    /// Carries the tail of the instance's log when one was written
+    FailedToStart(Json<InstanceId>, Option<String>),
     TemplateStillRunning(Json<InstanceId>),
+    TemplateNotRunning(Json<InstanceId>),
+    InvalidParams(String),
+    InvalidId(Json<InstanceId>),
+    DescriptorMismatch(Json<InstanceId>),
+    InstanceRunning(Json<InstanceId>),
+    InstanceNotRunning(Json<InstanceId>),
+    JobPending(Json<InstanceId>),
+    JobFailed(Json<InstanceId>, String),
+    HookFailed(Json<InstanceId>, String),
+    TooManyInstances(usize),
+    QueryDisabled,
+    Draining,
 }
 
 impl From<pg_ctl::Error> for ApiError {
@@ -41,25 +269,108 @@ impl From<pg_ctl::Error> for ApiError {
     }
 }
 
-impl IntoResponse for ApiError {
-    fn into_response(self) -> axum::response::Response {
-        let (status, message) = match &self {
+impl ApiError {
+    fn status_and_message(&self) -> (StatusCode, String) {
+        match self {
+            ApiError::PgCtl(
+                err @ (pg_ctl::Error::InvalidPath(_)
+                | pg_ctl::Error::VersionNotInstalled(_)
+                | pg_ctl::Error::Extension(_, _)
+                | pg_ctl::Error::InitSql(_)
+                | pg_ctl::Error::TemplateNotCleanlyStopped(_, _)
+                | pg_ctl::Error::NotInRecovery(_)
+                | pg_ctl::Error::TemplateDatabase(_, _)
+                | pg_ctl::Error::Initdb(_)
+                | pg_ctl::Error::Query(_)
+                | pg_ctl::Error::Restore(_)
+                | pg_ctl::Error::Import(_)
+                | pg_ctl::Error::ReplicationDisabled(_, _, _)),
+            ) => (StatusCode::BAD_REQUEST, format!("pg_ctl: {}", err)),
+            ApiError::PgCtl(err @ pg_ctl::Error::DataDirNotFound(_)) => {
+                (StatusCode::NOT_FOUND, format!("pg_ctl: {}", err))
+            }
+            ApiError::PgCtl(err @ pg_ctl::Error::DataDirExists(_)) => {
+                (StatusCode::CONFLICT, format!("pg_ctl: {}", err))
+            }
             ApiError::PgCtl(err) => (
                 StatusCode::INTERNAL_SERVER_ERROR,
-                format!("pg_ctl: {:?}", err),
+                format!("pg_ctl: {}", err),
             ),
             ApiError::NotFound(id) => (StatusCode::NOT_FOUND, format!("Not found: {}", id.id)),
-            ApiError::FailedToStart(id) => (
+            ApiError::FailedToStart(id, log) => (
                 StatusCode::INTERNAL_SERVER_ERROR,
-                format!("Instance {} failed to start", id.id),
+                match log.as_deref().and_then(pg_ctl::last_fatal) {
+                    Some(cause) => format!("Instance {} failed to start: {}", id.id, cause),
+                    None => format!("Instance {} failed to start", id.id),
+                },
             ),
             ApiError::TemplateStillRunning(id) => (
                 StatusCode::BAD_REQUEST,
                 format!("Instance {} is still running", id.id),
             ),
-        };
+            ApiError::TemplateNotRunning(id) => (
+                StatusCode::BAD_REQUEST,
+                format!("Instance {} is not running", id.id),
+            ),
+            ApiError::InvalidParams(message) => (
+                StatusCode::BAD_REQUEST,
+                format!("Invalid parameters: {}", message),
+            ),
+            ApiError::InvalidId(id) => (
+                StatusCode::BAD_REQUEST,
+                format!(
+                    "Invalid instance id {:?}, ids must be 1 to 64 letters, digits, '_' or '-'",
+                    id.id
+                ),
+            ),
+            ApiError::DescriptorMismatch(id) => (
+                StatusCode::CONFLICT,
+                format!("Instance {} exists with a different descriptor", id.id),
+            ),
+            ApiError::InstanceRunning(id) => (
+                StatusCode::BAD_REQUEST,
+                format!("Instance {} must be stopped", id.id),
+            ),
+            ApiError::InstanceNotRunning(id) => (
+                StatusCode::BAD_REQUEST,
+                format!("Instance {} must be running", id.id),
+            ),
+            ApiError::JobPending(id) => (
+                StatusCode::CONFLICT,
+                format!("Instance {} is still being created", id.id),
+            ),
+            ApiError::JobFailed(id, message) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Instance {} failed to be created: {}", id.id, message),
+            ),
+            ApiError::HookFailed(id, message) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Post-start hook failed for {}: {}", id.id, message),
+            ),
+            ApiError::TooManyInstances(max) => (
+                StatusCode::TOO_MANY_REQUESTS,
+                format!("Instance limit of {} reached", max),
+            ),
+            ApiError::QueryDisabled => (
+                StatusCode::FORBIDDEN,
+                "Running SQL is disabled, set QUICKPG_ENABLE_QUERY=true to allow it".to_string(),
+            ),
+            ApiError::Draining => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Draining, new instances can't be created or started".to_string(),
+            ),
+        }
+    }
+}
 
-        let body = Json(json!({ "error": message }));
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let (status, message) = self.status_and_message();
+        let body = match self {
+            ApiError::FailedToStart(_, Some(log)) => Json(json!({ "error": message, "log": log })),
+            ApiError::Draining => Json(json!({ "error": message, "code": "draining" })),
+            _ => Json(json!({ "error": message })),
+        };
 
         (status, body).into_response()
     }
@@ -69,6 +380,7 @@ type Result<T> = std::result::Result<T, ApiError>;
 
 #[derive(Debug, Deserialize, Serialize)]
 enum InstanceState {
+    Creating,
     Stopped,
     Running,
 }
@@ -76,6 +388,16 @@ enum InstanceState {
 #[derive(Debug, Deserialize, Serialize)]
 struct ProcessInfo {
     pid: u32,
+    limits: Option<cgroup::ResourceLimits>,
+    usage: Option<cgroup::ResourceUsage>,
+    /// Seconds since the postmaster started, a low value on a long-lived instance means restarts
+    uptime_seconds: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct DataOwner {
+    uid: u32,
+    gid: u32,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -84,6 +406,8 @@ struct ConnectionInfo {
     host: String,
     port: u32,
     dbname: String,
+    /// Directory of the `.s.PGSQL.<port>` Unix socket, usable as the host by libpq clients
+    socket_dir: PathBuf,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -92,10 +416,39 @@ struct Instance {
     state: InstanceState,
     conn_info: ConnectionInfo,
     proc_info: Option<ProcessInfo>,
+    data_owner: Option<DataOwner>,
+    /// Unix timestamp in seconds after which the instance is destroyed
+    expires_at: Option<u64>,
+    version: Option<String>,
+    /// Major version the data dir was initialized with, read from `PG_VERSION`
+    server_version: Option<String>,
+    labels: HashMap<String, String>,
+    /// RFC3339, missing for instances from before they were recorded
+    #[serde(with = "timestamp")]
+    created_at: Option<u64>,
+    #[serde(with = "timestamp")]
+    last_started_at: Option<u64>,
+    /// `primary` or `standby`, unknown while creating
+    #[serde(skip_serializing_if = "Option::is_none")]
+    role: Option<pg_ctl::ReplicationRole>,
+    /// Instance a replica was created from
+    #[serde(skip_serializing_if = "Option::is_none")]
+    primary: Option<String>,
+    /// Apparent size of the data dir, which counts extents shared with reflinked forks in full
+    #[serde(skip_serializing_if = "Option::is_none")]
+    disk_bytes: Option<u64>,
+    /// Other backends connected to `dbname`, only reported by `status` with `with_connections`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    connections: Option<i64>,
+    /// Only reported by `start`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    recovered: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    recovery_duration_ms: Option<u64>,
 }
 
 impl Instance {
-    fn new(user: impl Into<String>, status: Status) -> Instance {
+    fn new(status: Status) -> Instance {
         let state = match status.pid {
             Some(_) => InstanceState::Running,
             None => InstanceState::Stopped,
@@ -104,18 +457,439 @@ impl Instance {
             id: status.id,
             state,
             conn_info: ConnectionInfo {
-                user: user.into(),
+                user: status.owner.unwrap_or(status.user),
                 host: "127.0.0.1".to_string(),
                 port: status.port,
                 dbname: status.dbname,
+                socket_dir: status.socket_dir,
+            },
+            proc_info: status.pid.map(|p| ProcessInfo {
+                pid: p,
+                limits: status.limits,
+                usage: status.usage,
+                uptime_seconds: status
+                    .started_at
+                    .map(|started_at| reaper::now().saturating_sub(started_at)),
+            }),
+            data_owner: status.data_owner.map(|owner| DataOwner {
+                uid: owner.uid,
+                gid: owner.gid,
+            }),
+            expires_at: status.expires_at,
+            version: status.version,
+            server_version: status.server_version,
+            labels: status.labels,
+            created_at: status.created_at,
+            last_started_at: status.last_started_at,
+            role: Some(status.role),
+            primary: status.primary,
+            disk_bytes: None,
+            connections: None,
+            recovered: None,
+            recovery_duration_ms: None,
+        }
+    }
+
+    fn with_connections(mut self, connections: Option<i64>) -> Instance {
+        self.connections = connections;
+        self
+    }
+
+    fn with_recovery(mut self, recovery: pg_ctl::Recovery) -> Instance {
+        self.recovered = Some(recovery.recovered);
+        self.recovery_duration_ms = recovery.duration_ms;
+        self
+    }
+
+    /// Walks the data dir, so only done when asked for with `with_size`.
+    async fn with_size(mut self, ctl: &pg_ctl::PgCtl) -> Instance {
+        if !matches!(self.state, InstanceState::Creating) {
+            match ctl.disk_usage(&self.id).await {
+                Ok(bytes) => self.disk_bytes = Some(bytes),
+                Err(err) => tracing::warn!("failed to measure {}: {}", self.id, err),
+            }
+        }
+        self
+    }
+
+    fn creating(ctl: &pg_ctl::PgCtl, id: impl Into<String>, job: &PendingJob) -> Instance {
+        Instance {
+            id: id.into(),
+            state: InstanceState::Creating,
+            conn_info: ConnectionInfo {
+                user: ctl.user.clone(),
+                host: "127.0.0.1".to_string(),
+                port: job.port,
+                dbname: job.dbname.clone(),
+                socket_dir: ctl.socket_dir().to_path_buf(),
+            },
+            proc_info: None,
+            data_owner: None,
+            expires_at: None,
+            version: None,
+            server_version: None,
+            labels: HashMap::new(),
+            created_at: None,
+            last_started_at: None,
+            role: None,
+            primary: None,
+            disk_bytes: None,
+            connections: None,
+            recovered: None,
+            recovery_duration_ms: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct PendingJob {
+    dbname: String,
+    port: u32,
+}
+
+#[derive(Debug, Clone)]
+enum Job {
+    Pending(PendingJob),
+    Failed(String),
+}
+
+type Locks = Arc<Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>>;
+
+/// Exclusive access to an instance, see `AppState::lock`.
+struct InstanceGuard {
+    id: String,
+    locks: Locks,
+    guard: Option<OwnedMutexGuard<()>>,
+}
+
+impl Drop for InstanceGuard {
+    /// Forgets the instance's lock once nobody holds or waits for it, so `locks` only has
+    /// entries for ids in use rather than every id ever seen.
+    fn drop(&mut self) {
+        // New waiters clone the lock with `locks` held, so none can show up in between
+        let mut locks = self.locks.lock().unwrap();
+        drop(self.guard.take());
+        if locks
+            .get(&self.id)
+            .is_some_and(|lock| Arc::strong_count(lock) == 1)
+        {
+            locks.remove(&self.id);
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct AppState {
+    ctl: Arc<pg_ctl::PgCtl>,
+    /// Instances whose create or fork is still running in the background
+    jobs: Arc<Mutex<HashMap<String, Job>>>,
+    /// Serializes lifecycle operations on the same instance
+    locks: Locks,
+    metrics: Arc<metrics::Metrics>,
+    /// Looked up once at startup for `/version`
+    installations: Arc<Vec<pg_ctl::Installation>>,
+    limit: Option<Arc<InstanceLimit>>,
+    /// Set ahead of maintenance, refuses anything that would add or start an instance
+    draining: Arc<AtomicBool>,
+}
+
+/// Caps how many instances may exist, or only run, at the same time.
+#[derive(Debug)]
+struct InstanceLimit {
+    max: usize,
+    running_only: bool,
+    /// Creates, forks and starts that were admitted but may not be listed yet
+    in_flight: AtomicUsize,
+    /// Makes counting and admitting one step, so concurrent requests can't overshoot
+    admission: tokio::sync::Mutex<()>,
+}
+
+impl InstanceLimit {
+    /// Reads `QUICKPG_MAX_INSTANCES` and `QUICKPG_MAX_INSTANCES_RUNNING_ONLY`.
+    fn from_env() -> Option<InstanceLimit> {
+        let max = env::var("QUICKPG_MAX_INSTANCES").ok()?.parse().ok()?;
+        Some(InstanceLimit {
+            max,
+            running_only: env::var("QUICKPG_MAX_INSTANCES_RUNNING_ONLY").as_deref() == Ok("true"),
+            in_flight: AtomicUsize::new(0),
+            admission: tokio::sync::Mutex::new(()),
+        })
+    }
+}
+
+/// Counts as an instance until the operation it was admitted for is done.
+struct Admission(Arc<InstanceLimit>);
+
+impl Drop for Admission {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl AppState {
+    fn new(ctl: pg_ctl::PgCtl, installations: Vec<pg_ctl::Installation>) -> AppState {
+        AppState {
+            ctl: Arc::new(ctl),
+            installations: Arc::new(installations),
+            jobs: Arc::default(),
+            locks: Arc::default(),
+            metrics: Arc::default(),
+            limit: None,
+            draining: Arc::default(),
+        }
+    }
+
+    fn with_limit(mut self, limit: Option<InstanceLimit>) -> AppState {
+        self.limit = limit.map(Arc::new);
+        self
+    }
+
+    fn check_draining(&self) -> Result<()> {
+        if self.draining.load(Ordering::SeqCst) {
+            return Err(ApiError::Draining);
+        }
+        Ok(())
+    }
+
+    /// Reserves room for one more instance, hold the admission until it's listed or running.
+    async fn admit(&self) -> Result<Option<Admission>> {
+        self.check_draining()?;
+        let limit = match &self.limit {
+            Some(limit) => limit,
+            None => return Ok(None),
+        };
+
+        let _admission = limit.admission.lock().await;
+        let listed = self
+            .ctl
+            .list()
+            .await?
+            .iter()
+            .filter(|status| !limit.running_only || status.is_running())
+            .count();
+
+        // In-flight operations may already be listed, which only errs on the side of refusing
+        if listed + limit.in_flight.load(Ordering::SeqCst) >= limit.max {
+            return Err(ApiError::TooManyInstances(limit.max));
+        }
+
+        limit.in_flight.fetch_add(1, Ordering::SeqCst);
+        Ok(Some(Admission(limit.clone())))
+    }
+
+    /// Starting only needs room when stopped instances don't count.
+    async fn admit_start(&self, id: &str) -> Result<Option<Admission>> {
+        self.check_draining()?;
+        match &self.limit {
+            Some(limit) if limit.running_only && !self.ctl.is_running(id) => self.admit().await,
+            _ => Ok(None),
+        }
+    }
+
+    fn spawn_job<F>(&self, id: String, dbname: String, port: u32, work: F)
+    where
+        F: Future<Output = Result<()>> + Send + 'static,
+    {
+        // Replaces whatever an earlier job of the same id left behind
+        self.jobs
+            .lock()
+            .unwrap()
+            .insert(id.clone(), Job::Pending(PendingJob { dbname, port }));
+
+        let jobs = self.jobs.clone();
+        tokio::spawn(
+            async move {
+                let result = work.await;
+                let mut jobs = jobs.lock().unwrap();
+                match result {
+                    Ok(()) => {
+                        jobs.remove(&id);
+                    }
+                    Err(err) => {
+                        let (_, message) = err.status_and_message();
+                        tracing::error!("background job for {} failed: {}", id, message);
+                        jobs.insert(id, Job::Failed(message));
+                    }
+                }
+            }
+            .in_current_span(),
+        );
+    }
+
+    fn job(&self, id: &str) -> Option<Job> {
+        self.jobs.lock().unwrap().get(id).cloned()
+    }
+
+    fn pending_jobs(&self) -> Vec<(String, PendingJob)> {
+        self.jobs
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|(id, job)| match job {
+                Job::Pending(pending) => Some((id.clone(), pending.clone())),
+                Job::Failed(_) => None,
+            })
+            .collect()
+    }
+
+    fn remove_job(&self, id: &str) {
+        self.jobs.lock().unwrap().remove(id);
+    }
+
+    /// Waits for exclusive access to `id`, held until the guard is dropped.
+    async fn lock(&self, id: &str) -> InstanceGuard {
+        let lock = self
+            .locks
+            .lock()
+            .unwrap()
+            .entry(id.to_string())
+            .or_default()
+            .clone();
+        let guard = lock.lock_owned().await;
+        InstanceGuard {
+            id: id.to_string(),
+            locks: self.locks.clone(),
+            guard: Some(guard),
+        }
+    }
+}
+
+fn create_ctl(args: &args::Args) -> std::result::Result<pg_ctl::PgCtl, pg_ctl::Error> {
+    let root = &args.root;
+
+    let mut ctl = pg_ctl::PgCtl::new(whoami::username(), root, args.pg_ctl.clone())?
+        .with_owner(data_owner())
+        .with_copy_strategy(copy_strategy(root))
+        .with_dir_mode(args.dir_mode)
+        .with_subvolumes(env::var("QUICKPG_BTRFS_SUBVOLUMES").as_deref() == Ok("true"))
+        .with_verify_copies(env::var("QUICKPG_VERIFY_COPIES").as_deref() == Ok("true"))
+        .with_sync_copies(env::var("QUICKPG_SYNC_COPIES").as_deref() == Ok("true"));
+
+    if let Some(secs) = env::var("QUICKPG_COMMAND_TIMEOUT_SECS")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+    {
+        ctl = ctl.with_command_timeout(Duration::from_secs(secs));
+    }
+    if let Some(concurrency) = env::var("QUICKPG_COPY_CONCURRENCY")
+        .ok()
+        .and_then(|concurrency| concurrency.parse().ok())
+    {
+        ctl = ctl.with_copy_concurrency(concurrency);
+    }
+    if let Some(dir) = &args.socket_dir {
+        ctl = ctl.with_socket_dir(dir.clone());
+    }
+
+    Ok(ctl)
+}
+
+/// Reads `QUICKPG_PG_SSL_CERT_FILE` and `QUICKPG_PG_SSL_KEY_FILE`, turning on SSL for new instances.
+fn ssl_files() -> Option<config::SslFiles> {
+    Some(config::SslFiles {
+        cert_file: env::var("QUICKPG_PG_SSL_CERT_FILE").ok()?,
+        key_file: env::var("QUICKPG_PG_SSL_KEY_FILE").ok()?,
+    })
+}
+
+/// `QUICKPG_COPY_STRATEGY` overrides the strategy detected from the data dir's filesystem.
+fn copy_strategy(root: &std::path::Path) -> copy::CopyStrategy {
+    match env::var("QUICKPG_COPY_STRATEGY").as_deref() {
+        Ok("copy") => copy::CopyStrategy::Copy,
+        Ok("reflink") => copy::CopyStrategy::Reflink,
+        Ok("btrfs") => copy::CopyStrategy::BtrfsSnapshot,
+        _ => copy::CopyStrategy::detect(&root.join("data")),
+    }
+}
+
+/// Reads `QUICKPG_DATA_UID`/`QUICKPG_DATA_GID`, which only take effect when running as root.
+fn data_owner() -> Option<pg_ctl::Ownership> {
+    let uid = env::var("QUICKPG_DATA_UID").ok()?.parse().ok()?;
+    let gid = env::var("QUICKPG_DATA_GID")
+        .ok()
+        .and_then(|gid| gid.parse().ok())
+        .unwrap_or(uid);
+
+    if unsafe { libc::geteuid() } != 0 {
+        tracing::warn!(
+            "ignoring QUICKPG_DATA_UID/QUICKPG_DATA_GID, quickpg is not running as root"
+        );
+        return None;
+    }
+
+    Some(pg_ctl::Ownership { uid, gid })
+}
+
+/// Applies `QUICKPG_UMASK` (octal) to quickpg and the Postgres processes it spawns.
+fn apply_umask() {
+    if let Ok(umask) = env::var("QUICKPG_UMASK") {
+        match u32::from_str_radix(umask.trim_start_matches("0o"), 8) {
+            Ok(mask) => unsafe {
+                libc::umask(mask as libc::mode_t);
             },
-            proc_info: status.pid.map(|p| ProcessInfo { pid: p }),
+            Err(err) => tracing::warn!("ignoring invalid QUICKPG_UMASK {}: {}", umask, err),
         }
     }
 }
 
-fn create_ctl() -> pg_ctl::PgCtl {
-    pg_ctl::PgCtl::new(whoami::username(), std::path::Path::new(""))
+fn generate_id() -> String {
+    Alphanumeric.sample_string(&mut rand::thread_rng(), 12)
+}
+
+fn pick_port() -> u32 {
+    portpicker::pick_unused_port().unwrap().into()
+}
+
+fn location(id: &str) -> String {
+    format!("/pg/instance/{}", id)
+}
+
+fn created(instance: Json<Instance>) -> Response {
+    let location = location(&instance.id);
+    (
+        StatusCode::CREATED,
+        [(header::LOCATION, location)],
+        instance,
+    )
+        .into_response()
+}
+
+fn created_in_background(id: String) -> Response {
+    let location = location(&id);
+    (
+        StatusCode::CREATED,
+        [(header::LOCATION, location)],
+        InstanceId::json(id),
+    )
+        .into_response()
+}
+
+/// Ids and dbnames end up in paths and SQL, so both are kept to a conservative charset.
+fn is_valid_name(name: &str, max_len: usize) -> bool {
+    static NAME_REGEX: OnceLock<Regex> = OnceLock::new();
+    let regex = NAME_REGEX.get_or_init(|| Regex::new(r"^[A-Za-z0-9_-]+$").unwrap());
+
+    !name.is_empty() && name.len() <= max_len && regex.is_match(name)
+}
+
+fn validate_id(id: &str) -> Result<()> {
+    if is_valid_name(id, 64) {
+        Ok(())
+    } else {
+        Err(ApiError::InvalidId(InstanceId::json(id)))
+    }
+}
+
+fn validate_dbname(dbname: &str) -> Result<()> {
+    // Postgres truncates identifiers longer than 63 bytes
+    if is_valid_name(dbname, 63) {
+        Ok(())
+    } else {
+        Err(ApiError::InvalidParams(format!(
+            "dbname {:?} must be 1 to 63 letters, digits, '_' or '-'",
+            dbname
+        )))
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -123,119 +897,1673 @@ struct ListResponse {
     instances: Vec<Instance>,
 }
 
-async fn list() -> Result<Json<ListResponse>> {
-    let ctl = create_ctl();
-    let instances = ctl.list().await?;
-    Ok(Json(ListResponse {
-        instances: instances
-            .into_iter()
-            .map(|status| Instance::new(&ctl.user, status))
-            .collect(),
-    }))
+/// Parses every `label=key=value` query param into selectors that must all match.
+fn label_selectors(params: &[(String, String)]) -> Result<Vec<(&str, &str)>> {
+    params
+        .iter()
+        .filter(|(name, _)| name == "label")
+        .map(|(_, selector)| {
+            selector.split_once('=').ok_or_else(|| {
+                ApiError::InvalidParams(format!("label selector must be key=value: {}", selector))
+            })
+        })
+        .collect()
 }
 
-async fn create(body: Json<InstanceDescriptor>) -> Result<Json<Instance>> {
-    let ctl = create_ctl();
-    let port: u32 = portpicker::pick_unused_port().unwrap().into();
-    let id = Alphanumeric.sample_string(&mut rand::thread_rng(), 12);
+#[derive(Debug, Deserialize, Serialize)]
+struct SizeParams {
+    #[serde(default)]
+    with_size: bool,
+}
 
-    ctl.init(&id, &body.dbname, &config::PostgresqlConf::default(port))
-        .await?;
+#[derive(Debug, Deserialize, Serialize)]
+struct ConnectionsParams {
+    #[serde(default)]
+    with_connections: bool,
+}
 
-    let status = ctl.status(&id).await?;
-    if !status.is_running() {
-        return Err(ApiError::FailedToStart(InstanceId::json(id)));
+async fn list(
+    State(state): State<AppState>,
+    Query(size): Query<SizeParams>,
+    Query(params): Query<Vec<(String, String)>>,
+) -> Result<Json<ListResponse>> {
+    let ctl = &state.ctl;
+    let selectors = label_selectors(&params)?;
+
+    let pending = state.pending_jobs();
+    let mut instances: Vec<Instance> = ctl
+        .list()
+        .await?
+        .into_iter()
+        .filter(|status| !pending.iter().any(|(id, _)| *id == status.id))
+        .map(Instance::new)
+        .collect();
+
+    for (id, job) in &pending {
+        instances.push(Instance::creating(ctl, id, job));
     }
 
-    Ok(Json(Instance::new(&ctl.user, status)))
-}
+    instances.retain(|instance| {
+        selectors
+            .iter()
+            .all(|(key, value)| instance.labels.get(*key).map(String::as_str) == Some(*value))
+    });
+
+    if size.with_size {
+        let mut sized = Vec::with_capacity(instances.len());
+        for instance in instances {
+            sized.push(instance.with_size(ctl).await);
+        }
+        instances = sized;
+    }
 
-async fn status(Path(id): Path<String>) -> Result<Json<Instance>> {
-    let ctl = create_ctl();
-    Ok(Json(Instance::new(&ctl.user, ctl.status(&id).await?)))
+    Ok(Json(ListResponse { instances }))
 }
 
-async fn start(Path(id): Path<String>) -> Result<Json<Instance>> {
-    let ctl = create_ctl();
+const FAILED_START_LOG_LINES: usize = 50;
 
-    if !ctl.exists(&id) {
-        return Err(ApiError::NotFound(InstanceId::json(id)));
-    }
+async fn failed_to_start(ctl: &pg_ctl::PgCtl, id: &str) -> ApiError {
+    let log = match ctl.log_tail(id, FAILED_START_LOG_LINES).await {
+        Ok(log) => log,
+        Err(err) => {
+            tracing::warn!("failed to read the log of {}: {}", id, err);
+            None
+        }
+    };
+    ApiError::FailedToStart(InstanceId::json(id), log)
+}
 
-    ctl.start(&id).await?;
+/// Maps `pg_ctl` giving up on starting the server to `FailedToStart`, with its log.
+async fn start_error(ctl: &pg_ctl::PgCtl, id: &str, err: pg_ctl::Error) -> ApiError {
+    match err {
+        pg_ctl::Error::CliError(_)
+        | pg_ctl::Error::StartTimeout(_, _)
+        | pg_ctl::Error::StartFailed(_, _)
+        | pg_ctl::Error::Timeout(_, _) => failed_to_start(ctl, id).await,
+        err => err.into(),
+    }
+}
 
-    let status = ctl.status(&id).await?;
+/// Confirms a freshly started instance is running and runs the post-start hook.
+async fn started_status(ctl: &pg_ctl::PgCtl, id: &str) -> Result<Status> {
+    let status = ctl.status(id).await?;
     if !status.is_running() {
-        return Err(ApiError::FailedToStart(InstanceId::json(id)));
+        return Err(failed_to_start(ctl, id).await);
+    }
+
+    if let Some(hook) = hooks::PostStartHook::from_env() {
+        if let Err(err) = hook.run(&status, ctl.command_timeout()).await {
+            if hook.strict {
+                return Err(ApiError::HookFailed(InstanceId::json(id), err.to_string()));
+            }
+            tracing::warn!("post-start hook for {} failed: {}", id, err);
+        }
     }
 
-    Ok(Json(Instance::new(&ctl.user, status)))
+    Ok(status)
 }
 
-async fn stop(Path(id): Path<String>) -> Result<Json<()>> {
-    let ctl = create_ctl();
-    ctl.stop(&id, true).await?;
-    Ok(Json(()))
+async fn init_instance(
+    ctl: &pg_ctl::PgCtl,
+    metrics: &metrics::Metrics,
+    id: &str,
+    descriptor: &InstanceDescriptor,
+    port: u32,
+) -> Result<Json<Instance>> {
+    metrics
+        .create_duration
+        .time(async {
+            let conf = descriptor.conf(port)?;
+            descriptor
+                .initdb
+                .validate()
+                .map_err(ApiError::InvalidParams)?;
+            let options = pg_ctl::InitOptions {
+                limits: descriptor.limits()?,
+                expires_at: descriptor
+                    .ttl_seconds
+                    .map(|ttl| reaper::now().saturating_add(ttl)),
+                version: descriptor.version.clone(),
+                extensions: descriptor.extensions.clone(),
+                init_sql: descriptor.init_sql.clone(),
+                owner: descriptor.owner()?,
+                labels: descriptor.labels.clone(),
+                template_database: descriptor.template_database.clone(),
+                initdb: descriptor.initdb.clone(),
+            };
+            ctl.init(id, &descriptor.dbname, &conf, &options).await?;
+
+            let status = started_status(ctl, id).await?;
+            Ok(Json(Instance::new(status)))
+        })
+        .await
 }
 
-async fn fork(Path(template): Path<String>) -> Result<Json<Instance>> {
-    let ctl = create_ctl();
+async fn create(
+    State(state): State<AppState>,
+    Query(params): Query<CreateParams>,
+    Json(body): Json<InstanceDescriptor>,
+) -> Result<Response> {
+    let ctl = &state.ctl;
+    validate_dbname(&body.dbname)?;
 
-    if !ctl.exists(&template) {
-        return Err(ApiError::NotFound(InstanceId::json(&template)));
+    let mut guard = None;
+    let id = match &body.id {
+        Some(id) => {
+            validate_id(id)?;
+            guard = Some(state.lock(id).await);
+
+            if let Some(Job::Pending(job)) = state.job(id) {
+                return Ok(Json(Instance::creating(ctl, id, &job)).into_response());
+            }
+
+            if ctl.exists(id) {
+                let status = ctl.status(id).await?;
+                if status.dbname != body.dbname {
+                    return Err(ApiError::DescriptorMismatch(InstanceId::json(id)));
+                }
+                state.remove_job(id);
+                return Ok(Json(Instance::new(status)).into_response());
+            }
+
+            id.clone()
+        }
+        None => generate_id(),
+    };
+    logging::set_instance(&id);
+    let admission = state.admit().await?;
+    let port = pick_port();
+
+    if params.background {
+        state.spawn_job(id.clone(), body.dbname.clone(), port, {
+            let ctl = ctl.clone();
+            let metrics = state.metrics.clone();
+            let id = id.clone();
+            async move {
+                let _guard = guard;
+                let _admission = admission;
+                init_instance(&ctl, &metrics, &id, &body, port)
+                    .await
+                    .map(|_| ())
+            }
+        });
+        return Ok(created_in_background(id));
     }
 
-    let port: u32 = portpicker::pick_unused_port().unwrap().into();
-    let id = Alphanumeric.sample_string(&mut rand::thread_rng(), 12);
+    let instance = init_instance(ctl, &state.metrics, &id, &body, port).await?;
+    // A failed background create of the same id would otherwise keep answering for it
+    state.remove_job(&id);
+    Ok(created(instance))
+}
+
+async fn upsert(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    body: Json<InstanceDescriptor>,
+) -> Result<Json<Instance>> {
+    let ctl = &state.ctl;
+    validate_id(&id)?;
+    validate_dbname(&body.dbname)?;
+    let _guard = state.lock(&id).await;
 
-    let template_status = ctl.status(&template).await?;
-    if template_status.is_running() {
-        return Err(ApiError::TemplateStillRunning(InstanceId::json(&template)));
+    if let Some(Job::Pending(job)) = state.job(&id) {
+        return Ok(Json(Instance::creating(ctl, id, &job)));
     }
 
-    ctl.fork(
-        &template,
-        &id,
-        &template_status.dbname,
-        &config::PostgresqlConf::default(port),
-    )
-    .await?;
+    if !ctl.exists(&id) {
+        let _admission = state.admit().await?;
+        let instance = init_instance(ctl, &state.metrics, &id, &body, pick_port()).await?;
+        state.remove_job(&id);
+        return Ok(instance);
+    }
+
+    let mut status = ctl.status(&id).await?;
+    // Fixed at creation, unlike the labels and expiry reconciled below
+    if status.dbname != body.dbname || status.version != body.version || status.owner != body.owner
+    {
+        return Err(ApiError::DescriptorMismatch(InstanceId::json(id)));
+    }
+
+    let expires_at = body
+        .ttl_seconds
+        .map(|ttl| reaper::now().saturating_add(ttl));
+    if status.labels != body.labels || status.expires_at != expires_at {
+        ctl.set_labels_and_expiry(&id, &body.labels, expires_at)
+            .await?;
+        status.labels = body.labels.clone();
+        status.expires_at = expires_at;
+    }
 
-    let status = ctl.status(&id).await?;
     if !status.is_running() {
-        return Err(ApiError::FailedToStart(InstanceId::json(id)));
+        state.check_draining()?;
+        if let Err(err) = state.metrics.start_duration.time(ctl.start(&id)).await {
+            return Err(start_error(ctl, &id, err).await);
+        }
+        status = started_status(ctl, &id).await?;
     }
 
-    Ok(Json(Instance::new(&ctl.user, status)))
+    state.remove_job(&id);
+    Ok(Json(Instance::new(status)))
 }
 
-async fn destroy(Path(id): Path<String>) -> Result<Json<()>> {
-    let ctl = create_ctl();
+async fn status(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Query(size): Query<SizeParams>,
+    Query(connections): Query<ConnectionsParams>,
+) -> Result<Json<Instance>> {
+    let ctl = &state.ctl;
+    validate_id(&id)?;
 
-    if ctl.is_running(&id) {
-        ctl.stop(&id, false).await?;
+    match state.job(&id) {
+        Some(Job::Pending(job)) => return Ok(Json(Instance::creating(ctl, id, &job))),
+        Some(Job::Failed(message)) => {
+            return Err(ApiError::JobFailed(InstanceId::json(id), message))
+        }
+        None => (),
     }
 
-    ctl.destroy(&id).await?;
-    Ok(Json(()))
+    if !ctl.exists(&id) {
+        return Err(ApiError::NotFound(InstanceId::json(id)));
+    }
+
+    let status = ctl.status(&id).await?;
+    // Needs a connection, so a failure only leaves the count out
+    let connections = if connections.with_connections && status.is_running() {
+        match ctl.connections(&status).await {
+            Ok(connections) => Some(connections),
+            Err(err) => {
+                tracing::warn!("failed to count connections to {}: {}", id, err);
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    let instance = Instance::new(status).with_connections(connections);
+    if size.with_size {
+        return Ok(Json(instance.with_size(ctl).await));
+    }
+    Ok(Json(instance))
 }
 
-#[tokio::main]
-async fn main() {
-    env_logger::init();
+async fn start(State(state): State<AppState>, Path(id): Path<String>) -> Result<Json<Instance>> {
+    let ctl = &state.ctl;
+    validate_id(&id)?;
+    let _guard = state.lock(&id).await;
 
-    let app = Router::new()
-        .route("/pg/instance", routing::get(list))
-        .route("/pg/instance", routing::post(create))
-        .route("/pg/instance/:id", routing::get(status))
-        .route("/pg/instance/:id/start", routing::post(start))
-        .route("/pg/instance/:id/stop", routing::post(stop))
-        .route("/pg/instance/:id/fork", routing::post(fork))
-        .route("/pg/instance/:id", routing::delete(destroy))
-        .layer(TraceLayer::new_for_http());
+    if !ctl.exists(&id) {
+        return Err(ApiError::NotFound(InstanceId::json(id)));
+    }
+    // Postgres would refuse over its lock file, so a repeated start just reports the instance
+    if ctl.is_running(&id) {
+        let status = ctl.status(&id).await?;
+        return Ok(Json(Instance::new(status)));
+    }
+    let _admission = state.admit_start(&id).await?;
 
-    axum::Server::bind(&"0.0.0.0:8000".parse().unwrap())
+    let log_offset = ctl.log_len(&id).await;
+    if let Err(err) = state.metrics.start_duration.time(ctl.start(&id)).await {
+        return Err(start_error(ctl, &id, err).await);
+    }
+    let recovery = ctl.recovery(&id, log_offset).await?;
+
+    let status = started_status(ctl, &id).await?;
+    Ok(Json(Instance::new(status).with_recovery(recovery)))
+}
+
+async fn restart(State(state): State<AppState>, Path(id): Path<String>) -> Result<Json<Instance>> {
+    let ctl = &state.ctl;
+    validate_id(&id)?;
+    let _guard = state.lock(&id).await;
+
+    if !ctl.exists(&id) {
+        return Err(ApiError::NotFound(InstanceId::json(id)));
+    }
+
+    if let Err(err) = ctl.restart(&id).await {
+        return Err(start_error(ctl, &id, err).await);
+    }
+
+    let status = started_status(ctl, &id).await?;
+    Ok(Json(Instance::new(status)))
+}
+
+async fn reload(State(state): State<AppState>, Path(id): Path<String>) -> Result<Json<Instance>> {
+    let ctl = &state.ctl;
+    validate_id(&id)?;
+    let _guard = state.lock(&id).await;
+
+    if !ctl.exists(&id) {
+        return Err(ApiError::NotFound(InstanceId::json(id)));
+    }
+
+    if !ctl.is_running(&id) {
+        return Err(ApiError::InstanceNotRunning(InstanceId::json(id)));
+    }
+
+    ctl.reload(&id).await?;
+    Ok(Json(Instance::new(ctl.status(&id).await?)))
+}
+
+async fn promote(State(state): State<AppState>, Path(id): Path<String>) -> Result<Json<Instance>> {
+    let ctl = &state.ctl;
+    validate_id(&id)?;
+    let _guard = state.lock(&id).await;
+
+    if !ctl.exists(&id) {
+        return Err(ApiError::NotFound(InstanceId::json(id)));
+    }
+
+    if !ctl.is_running(&id) {
+        return Err(ApiError::InstanceNotRunning(InstanceId::json(id)));
+    }
+
+    ctl.promote(&id).await?;
+    Ok(Json(Instance::new(ctl.status(&id).await?)))
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct StopParams {
+    #[serde(default)]
+    mode: pg_ctl::StopMode,
+}
+
+async fn stop(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Query(params): Query<StopParams>,
+) -> Result<Json<()>> {
+    let ctl = &state.ctl;
+    validate_id(&id)?;
+    let _guard = state.lock(&id).await;
+
+    if !ctl.exists(&id) {
+        return Err(ApiError::NotFound(InstanceId::json(id)));
+    }
+    // Like destroy, stopping something already stopped is fine
+    if !ctl.is_running(&id) {
+        return Ok(Json(()));
+    }
+
+    state
+        .metrics
+        .stop_duration
+        .time(ctl.stop(&id, params.mode, true))
+        .await?;
+    Ok(Json(()))
+}
+
+async fn kill(State(state): State<AppState>, Path(id): Path<String>) -> Result<Json<()>> {
+    let ctl = &state.ctl;
+    validate_id(&id)?;
+    let _guard = state.lock(&id).await;
+
+    if !ctl.exists(&id) {
+        return Err(ApiError::NotFound(InstanceId::json(id)));
+    }
+
+    if !ctl.kill(&id).await? {
+        return Err(ApiError::InstanceNotRunning(InstanceId::json(id)));
+    }
+    Ok(Json(()))
+}
+
+async fn fork_instance(
+    ctl: &pg_ctl::PgCtl,
+    metrics: &metrics::Metrics,
+    template_status: &Status,
+    id: &str,
+    params: &ForkParams,
+    port: u32,
+) -> Result<Json<Instance>> {
+    let result = metrics
+        .fork_duration
+        .time(async {
+            let mut conf = config::PostgresqlConf::default(port);
+            conf.ssl = ssl_files();
+
+            match params.mode {
+                ForkMode::Physical | ForkMode::Hardlink => {
+                    let mode = match params.mode {
+                        ForkMode::Hardlink => copy::ForkMode::Hardlink,
+                        _ => copy::ForkMode::FullCopy,
+                    };
+
+                    let stop_template = params.stop_template && template_status.is_running();
+                    if stop_template {
+                        // Only makes the forks start faster, so it's not worth failing over
+                        if let Err(err) = ctl.checkpoint(&template_status.id).await {
+                            tracing::warn!("checkpoint of {} failed: {}", template_status.id, err);
+                        }
+                        ctl.stop(&template_status.id, pg_ctl::StopMode::Fast, true)
+                            .await?;
+                    }
+
+                    let forked = ctl
+                        .fork(
+                            &template_status.id,
+                            id,
+                            &template_status.dbname,
+                            &conf,
+                            mode,
+                        )
+                        .await;
+
+                    // Restore the template's prior running state, even when the fork failed. The
+                    // fork's own result matters more, a template left stopped can be started again
+                    if stop_template {
+                        if let Err(err) = ctl.start(&template_status.id).await {
+                            tracing::warn!(
+                                "failed to restart template {} after forking: {}",
+                                template_status.id,
+                                err
+                            );
+                        }
+                    }
+                    forked?
+                }
+                ForkMode::Online => ctl.fork_online(template_status, id, &conf).await?,
+                ForkMode::Logical => {
+                    ctl.fork_logical(template_status, id, &conf, params.schema_only)
+                        .await?
+                }
+            }
+
+            let status = started_status(ctl, id).await?;
+            Ok(Json(Instance::new(status)))
+        })
+        .await;
+
+    if result.is_err() {
+        metrics.fork_failed();
+    }
+    result
+}
+
+async fn fork(
+    State(state): State<AppState>,
+    Path(template): Path<String>,
+    Query(params): Query<ForkParams>,
+) -> Result<Response> {
+    let ctl = &state.ctl;
+    validate_id(&template)?;
+    let guard = state.lock(&template).await;
+
+    if !ctl.exists(&template) {
+        return Err(ApiError::NotFound(InstanceId::json(&template)));
+    }
+
+    let template_status = ctl.status(&template).await?;
+
+    match params.mode {
+        ForkMode::Physical | ForkMode::Hardlink => {
+            if params.schema_only {
+                return Err(ApiError::InvalidParams(
+                    "schema_only requires mode=logical".to_string(),
+                ));
+            }
+            if template_status.is_running() && !params.stop_template {
+                return Err(ApiError::TemplateStillRunning(InstanceId::json(&template)));
+            }
+        }
+        ForkMode::Online => {
+            if params.schema_only {
+                return Err(ApiError::InvalidParams(
+                    "schema_only requires mode=logical".to_string(),
+                ));
+            }
+            if !template_status.is_running() {
+                return Err(ApiError::TemplateNotRunning(InstanceId::json(&template)));
+            }
+        }
+        ForkMode::Logical => {
+            if !template_status.is_running() {
+                return Err(ApiError::TemplateNotRunning(InstanceId::json(&template)));
+            }
+        }
+    }
+
+    let id = generate_id();
+    let port = pick_port();
+    logging::set_instance(&id);
+    let admission = state.admit().await?;
+
+    if params.background {
+        state.spawn_job(id.clone(), template_status.dbname.clone(), port, {
+            let ctl = ctl.clone();
+            let metrics = state.metrics.clone();
+            let id = id.clone();
+            async move {
+                let _guard = guard;
+                let _admission = admission;
+                fork_instance(&ctl, &metrics, &template_status, &id, &params, port)
+                    .await
+                    .map(|_| ())
+            }
+        });
+        return Ok(created_in_background(id));
+    }
+
+    Ok(created(
+        fork_instance(ctl, &state.metrics, &template_status, &id, &params, port).await?,
+    ))
+}
+
+/// Creates a streaming standby of a running instance, which needs to allow replication
+/// connections like an online fork.
+async fn replica(State(state): State<AppState>, Path(primary): Path<String>) -> Result<Response> {
+    let ctl = &state.ctl;
+    validate_id(&primary)?;
+    let _guard = state.lock(&primary).await;
+
+    if !ctl.exists(&primary) {
+        return Err(ApiError::NotFound(InstanceId::json(&primary)));
+    }
+
+    let primary_status = ctl.status(&primary).await?;
+    if !primary_status.is_running() {
+        return Err(ApiError::InstanceNotRunning(InstanceId::json(&primary)));
+    }
+
+    let id = generate_id();
+    let port = pick_port();
+    logging::set_instance(&id);
+    let _admission = state.admit().await?;
+
+    let mut conf = config::PostgresqlConf::default(port);
+    conf.ssl = ssl_files();
+    ctl.replica(&primary_status, &id, &conf).await?;
+
+    let status = started_status(ctl, &id).await?;
+    Ok(created(Json(Instance::new(status))))
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct ForkPlanParams {
+    #[serde(default)]
+    mode: ForkMode,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct ForkPlan {
+    filesystem: String,
+    /// `snapshot`, `reflink`, `hardlink` or `copy`
+    method: String,
+    /// Apparent size of the fork, reflinked and snapshotted extents are shared until written
+    bytes: u64,
+    /// Part of `bytes` hardlinked from the template
+    linked_bytes: u64,
+}
+
+/// Reports what a physical fork would copy without creating anything.
+async fn fork_plan(
+    State(state): State<AppState>,
+    Path(template): Path<String>,
+    Query(params): Query<ForkPlanParams>,
+) -> Result<Json<ForkPlan>> {
+    let ctl = &state.ctl;
+    validate_id(&template)?;
+
+    if !ctl.exists(&template) {
+        return Err(ApiError::NotFound(InstanceId::json(&template)));
+    }
+
+    let mode = match params.mode {
+        ForkMode::Physical => copy::ForkMode::FullCopy,
+        ForkMode::Hardlink => copy::ForkMode::Hardlink,
+        ForkMode::Logical | ForkMode::Online => {
+            return Err(ApiError::InvalidParams(
+                "plans are only available for mode=physical and mode=hardlink".to_string(),
+            ))
+        }
+    };
+
+    let plan = ctl.fork_plan(&template, mode).await?;
+    Ok(Json(ForkPlan {
+        filesystem: plan.filesystem.to_string(),
+        method: plan.method.as_str().to_string(),
+        bytes: plan.bytes,
+        linked_bytes: plan.linked_bytes,
+    }))
+}
+
+const DEFAULT_LOG_LINES: usize = 200;
+const MAX_LOG_LINES: usize = 10_000;
+
+#[derive(Debug, Deserialize, Serialize)]
+struct LogsParams {
+    lines: Option<usize>,
+}
+
+async fn logs(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Query(params): Query<LogsParams>,
+) -> Result<Response> {
+    validate_id(&id)?;
+    let lines = params.lines.unwrap_or(DEFAULT_LOG_LINES).min(MAX_LOG_LINES);
+
+    match state.ctl.log_tail(&id, lines).await? {
+        Some(tail) => {
+            Ok(([(header::CONTENT_TYPE, "text/plain; charset=utf-8")], tail).into_response())
+        }
+        None => Err(ApiError::NotFound(InstanceId::json(id))),
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct TablesParams {
+    dbname: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Table {
+    schema: String,
+    name: String,
+    estimated_rows: i64,
+    size_bytes: i64,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct TablesResponse {
+    tables: Vec<Table>,
+}
+
+async fn tables(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Query(params): Query<TablesParams>,
+) -> Result<Json<TablesResponse>> {
+    let ctl = &state.ctl;
+    validate_id(&id)?;
+
+    if !ctl.exists(&id) {
+        return Err(ApiError::NotFound(InstanceId::json(id)));
+    }
+
+    if !ctl.is_running(&id) {
+        return Err(ApiError::InstanceNotRunning(InstanceId::json(id)));
+    }
+
+    if let Some(dbname) = &params.dbname {
+        validate_dbname(dbname)?;
+    }
+
+    let tables = ctl.tables(&id, params.dbname.as_deref()).await?;
+    Ok(Json(TablesResponse {
+        tables: tables
+            .into_iter()
+            .map(|table| Table {
+                schema: table.schema,
+                name: table.name,
+                estimated_rows: table.estimated_rows,
+                size_bytes: table.size_bytes,
+            })
+            .collect(),
+    }))
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct DumpParams {
+    #[serde(default)]
+    format: pg_ctl::DumpFormat,
+}
+
+/// Streams `pg_dump` output, a failure halfway through aborts the response instead of ending it.
+async fn dump(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Query(params): Query<DumpParams>,
+) -> Result<Response> {
+    let ctl = &state.ctl;
+    validate_id(&id)?;
+
+    if !ctl.exists(&id) {
+        return Err(ApiError::NotFound(InstanceId::json(id)));
+    }
+
+    if !ctl.is_running(&id) {
+        return Err(ApiError::InstanceNotRunning(InstanceId::json(id)));
+    }
+
+    let mut dump = ctl.dump(&id, params.format).await?;
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(
+        async move {
+            loop {
+                match dump.next_chunk().await {
+                    Ok(Some(chunk)) => {
+                        // The client went away, dropping the dump kills pg_dump
+                        if sender.send_data(Bytes::from(chunk)).await.is_err() {
+                            return;
+                        }
+                    }
+                    Ok(None) => return,
+                    Err(err) => {
+                        tracing::error!("dump failed: {}", err);
+                        sender.abort();
+                        return;
+                    }
+                }
+            }
+        }
+        .in_current_span(),
+    );
+
+    let (content_type, extension) = match params.format {
+        pg_ctl::DumpFormat::Plain => ("application/sql", "sql"),
+        pg_ctl::DumpFormat::Custom => ("application/octet-stream", "dump"),
+    };
+    let disposition = format!("attachment; filename=\"{}.{}\"", id, extension);
+    Ok((
+        [
+            (header::CONTENT_TYPE, content_type.to_string()),
+            (header::CONTENT_DISPOSITION, disposition),
+        ],
+        axum::body::boxed(body),
+    )
+        .into_response())
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct ArchiveParams {
+    #[serde(default)]
+    format: pg_ctl::ArchiveFormat,
+    /// Checkpoints and stops a running instance for the archive, starting it again afterwards
+    #[serde(default)]
+    stop: bool,
+}
+
+/// Restores the instance's prior running state, whether or not the archive was sent.
+async fn restart_after_archive(ctl: &pg_ctl::PgCtl, id: &str) {
+    if let Err(err) = ctl.start(id).await {
+        tracing::error!("failed to restart {} after archiving it: {}", id, err);
+    }
+}
+
+/// Streams a tarball of a stopped instance's data dir, holding its lock until it's sent.
+async fn archive(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Query(params): Query<ArchiveParams>,
+) -> Result<Response> {
+    let ctl = &state.ctl;
+    validate_id(&id)?;
+    let guard = state.lock(&id).await;
+
+    if !ctl.exists(&id) {
+        return Err(ApiError::NotFound(InstanceId::json(id)));
+    }
+
+    let stop = ctl.is_running(&id);
+    if stop {
+        if !params.stop {
+            return Err(ApiError::TemplateStillRunning(InstanceId::json(id)));
+        }
+        // Only makes the stop faster, so it's not worth failing over
+        if let Err(err) = ctl.checkpoint(&id).await {
+            tracing::warn!("checkpoint of {} failed: {}", id, err);
+        }
+        ctl.stop(&id, pg_ctl::StopMode::Fast, true).await?;
+    }
+
+    let mut archive = match ctl.archive(&id, params.format).await {
+        Ok(archive) => archive,
+        Err(err) => {
+            if stop {
+                restart_after_archive(ctl, &id).await;
+            }
+            return Err(err.into());
+        }
+    };
+
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(
+        {
+            let ctl = ctl.clone();
+            let id = id.clone();
+            async move {
+                let _guard = guard;
+                loop {
+                    match archive.next_chunk().await {
+                        Ok(Some(chunk)) => {
+                            // The client went away, dropping the archive kills tar
+                            if sender.send_data(Bytes::from(chunk)).await.is_err() {
+                                break;
+                            }
+                        }
+                        Ok(None) => break,
+                        Err(err) => {
+                            tracing::error!("archive failed: {}", err);
+                            sender.abort();
+                            break;
+                        }
+                    }
+                }
+                drop(archive);
+                if stop {
+                    restart_after_archive(&ctl, &id).await;
+                }
+            }
+        }
+        .in_current_span(),
+    );
+
+    let content_type = match params.format {
+        pg_ctl::ArchiveFormat::Tar => "application/x-tar",
+        pg_ctl::ArchiveFormat::Zst => "application/zstd",
+    };
+    let disposition = format!(
+        "attachment; filename=\"{}.{}\"",
+        id,
+        params.format.extension()
+    );
+    Ok((
+        [
+            (header::CONTENT_TYPE, content_type.to_string()),
+            (header::CONTENT_DISPOSITION, disposition),
+        ],
+        axum::body::boxed(body),
+    )
+        .into_response())
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct ImportParams {
+    #[serde(default)]
+    format: pg_ctl::ArchiveFormat,
+}
+
+/// Creates an instance from a data dir archive, unpacked as the upload arrives.
+async fn import(
+    State(state): State<AppState>,
+    Query(params): Query<ImportParams>,
+    RawBody(mut body): RawBody,
+) -> Result<Response> {
+    let ctl = &state.ctl;
+    let id = generate_id();
+    let port = pick_port();
+    logging::set_instance(&id);
+    let _guard = state.lock(&id).await;
+    let _admission = state.admit().await?;
+
+    let mut conf = config::PostgresqlConf::default(port);
+    conf.ssl = ssl_files();
+
+    let result = import_archive(ctl, &id, params.format, &mut body, &conf).await;
+    if result.is_err() {
+        ctl.discard(&id).await;
+    }
+    result?;
+
+    let status = started_status(ctl, &id).await?;
+    Ok(created(Json(Instance::new(status))))
+}
+
+async fn import_archive(
+    ctl: &pg_ctl::PgCtl,
+    id: &str,
+    format: pg_ctl::ArchiveFormat,
+    body: &mut Body,
+    conf: &config::PostgresqlConf<'_>,
+) -> Result<()> {
+    let mut extract = ctl.extract(id, format).await?;
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|err| ApiError::InvalidParams(format!("body: {}", err)))?;
+        extract = extract.write(&chunk).await?;
+    }
+    extract.finish().await?;
+
+    ctl.import(id, conf).await?;
+    Ok(())
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct RestoreParams {
+    /// Falls back to `custom` for `application/octet-stream` bodies and `plain` otherwise
+    format: Option<pg_ctl::DumpFormat>,
+}
+
+/// Pipes the request body into `psql` or `pg_restore` as it arrives.
+async fn restore(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Query(params): Query<RestoreParams>,
+    headers: HeaderMap,
+    RawBody(mut body): RawBody,
+) -> Result<Json<()>> {
+    let ctl = &state.ctl;
+    validate_id(&id)?;
+    let _guard = state.lock(&id).await;
+
+    if !ctl.exists(&id) {
+        return Err(ApiError::NotFound(InstanceId::json(id)));
+    }
+
+    if !ctl.is_running(&id) {
+        return Err(ApiError::InstanceNotRunning(InstanceId::json(id)));
+    }
+
+    let format = params.format.unwrap_or_else(|| {
+        match headers.get(header::CONTENT_TYPE).map(HeaderValue::as_bytes) {
+            Some(b"application/octet-stream") => pg_ctl::DumpFormat::Custom,
+            _ => pg_ctl::DumpFormat::Plain,
+        }
+    });
+
+    let mut restore = ctl.restore(&id, format).await?;
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|err| ApiError::InvalidParams(format!("body: {}", err)))?;
+        restore = restore.write(&chunk).await?;
+    }
+    restore.finish().await?;
+
+    Ok(Json(()))
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct QueryRequest {
+    sql: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct QueryResult {
+    columns: Vec<String>,
+    rows: Vec<Vec<Option<String>>>,
+    rows_affected: u64,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct QueryResponse {
+    /// One per statement in `sql`
+    results: Vec<QueryResult>,
+}
+
+/// Runs arbitrary SQL with superuser rights, so it's off unless `QUICKPG_ENABLE_QUERY=true`.
+async fn query(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    body: Json<QueryRequest>,
+) -> Result<Json<QueryResponse>> {
+    let ctl = &state.ctl;
+    validate_id(&id)?;
+
+    if env::var("QUICKPG_ENABLE_QUERY").as_deref() != Ok("true") {
+        return Err(ApiError::QueryDisabled);
+    }
+
+    if !ctl.exists(&id) {
+        return Err(ApiError::NotFound(InstanceId::json(id)));
+    }
+
+    if !ctl.is_running(&id) {
+        return Err(ApiError::InstanceNotRunning(InstanceId::json(id)));
+    }
+
+    let results = ctl.query(&id, &body.sql).await?;
+    Ok(Json(QueryResponse {
+        results: results
+            .into_iter()
+            .map(|result| QueryResult {
+                columns: result.columns,
+                rows: result.rows,
+                rows_affected: result.rows_affected,
+            })
+            .collect(),
+    }))
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct LockWait {
+    pid: i32,
+    dbname: Option<String>,
+    locktype: String,
+    mode: Option<String>,
+    relation: Option<String>,
+    query: Option<String>,
+    blocked_by: Vec<i32>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct Backend {
+    pid: i32,
+    dbname: Option<String>,
+    state: Option<String>,
+    query: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct LocksResponse {
+    waiting: Vec<LockWait>,
+    blocking: Vec<Backend>,
+}
+
+async fn locks(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<LocksResponse>> {
+    let ctl = &state.ctl;
+    validate_id(&id)?;
+
+    if !ctl.exists(&id) {
+        return Err(ApiError::NotFound(InstanceId::json(id)));
+    }
+
+    if !ctl.is_running(&id) {
+        return Err(ApiError::InstanceNotRunning(InstanceId::json(id)));
+    }
+
+    let (waiting, blocking) = ctl.locks(&id).await?;
+    Ok(Json(LocksResponse {
+        waiting: waiting
+            .into_iter()
+            .map(|wait| LockWait {
+                pid: wait.pid,
+                dbname: wait.dbname,
+                locktype: wait.locktype,
+                mode: wait.mode,
+                relation: wait.relation,
+                query: wait.query,
+                blocked_by: wait.blocked_by,
+            })
+            .collect(),
+        blocking: blocking
+            .into_iter()
+            .map(|backend| Backend {
+                pid: backend.pid,
+                dbname: backend.dbname,
+                state: backend.state,
+                query: backend.query,
+            })
+            .collect(),
+    }))
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct TemplateValidation {
+    valid: bool,
+    reasons: Vec<String>,
+}
+
+async fn validate_template(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<TemplateValidation>> {
+    let ctl = &state.ctl;
+    validate_id(&id)?;
+
+    if !ctl.exists(&id) {
+        return Err(ApiError::NotFound(InstanceId::json(id)));
+    }
+
+    let reasons = ctl.validate_template(&id).await?;
+    Ok(Json(TemplateValidation {
+        valid: reasons.is_empty(),
+        reasons,
+    }))
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct FileDescriptor {
+    path: String,
+    content_base64: String,
+}
+
+async fn write_file(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    body: Json<FileDescriptor>,
+) -> Result<Json<()>> {
+    let ctl = &state.ctl;
+    validate_id(&id)?;
+    let _guard = state.lock(&id).await;
+
+    if !ctl.exists(&id) {
+        return Err(ApiError::NotFound(InstanceId::json(id)));
+    }
+
+    if ctl.is_running(&id) {
+        return Err(ApiError::InstanceRunning(InstanceId::json(id)));
+    }
+
+    let content = BASE64
+        .decode(&body.content_base64)
+        .map_err(|err| ApiError::InvalidParams(format!("content_base64: {}", err)))?;
+
+    ctl.write_file(&id, std::path::Path::new(&body.path), &content)
+        .await?;
+    Ok(Json(()))
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct SwapRequest {
+    a: String,
+    b: String,
+}
+
+async fn swap(State(state): State<AppState>, body: Json<SwapRequest>) -> Result<Json<()>> {
+    let ctl = &state.ctl;
+
+    validate_id(&body.a)?;
+    validate_id(&body.b)?;
+
+    if body.a == body.b {
+        return Err(ApiError::InvalidParams(
+            "cannot swap an instance with itself".to_string(),
+        ));
+    }
+
+    // Always lock in the same order so two opposite swaps can't deadlock
+    let (first, second) = if body.a < body.b {
+        (&body.a, &body.b)
+    } else {
+        (&body.b, &body.a)
+    };
+    let _first = state.lock(first).await;
+    let _second = state.lock(second).await;
+
+    for id in [&body.a, &body.b] {
+        if let Some(Job::Pending(_)) = state.job(id) {
+            return Err(ApiError::JobPending(InstanceId::json(id)));
+        }
+        if !ctl.exists(id) {
+            return Err(ApiError::NotFound(InstanceId::json(id)));
+        }
+        if ctl.is_running(id) {
+            return Err(ApiError::InstanceRunning(InstanceId::json(id)));
+        }
+    }
+
+    ctl.swap(&body.a, &body.b).await?;
+    Ok(Json(()))
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct RenameRequest {
+    id: String,
+}
+
+async fn rename(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    body: Json<RenameRequest>,
+) -> Result<Json<Instance>> {
+    let ctl = &state.ctl;
+    validate_id(&id)?;
+    validate_id(&body.id)?;
+
+    if id == body.id {
+        return Err(ApiError::InvalidParams(
+            "cannot rename an instance to its own id".to_string(),
+        ));
+    }
+
+    // Same order as swap, so renames and swaps of the same pair can't deadlock
+    let (first, second) = if id < body.id {
+        (&id, &body.id)
+    } else {
+        (&body.id, &id)
+    };
+    let _first = state.lock(first).await;
+    let _second = state.lock(second).await;
+
+    for id in [&id, &body.id] {
+        if let Some(Job::Pending(_)) = state.job(id) {
+            return Err(ApiError::JobPending(InstanceId::json(id)));
+        }
+    }
+    if !ctl.exists(&id) {
+        return Err(ApiError::NotFound(InstanceId::json(id)));
+    }
+    if ctl.is_running(&id) {
+        return Err(ApiError::InstanceRunning(InstanceId::json(id)));
+    }
+
+    ctl.rename(&id, &body.id).await?;
+    logging::set_instance(&body.id);
+
+    let status = ctl.status(&body.id).await?;
+    Ok(Json(Instance::new(status)))
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct DestroyParams {
+    #[serde(default)]
+    strict: bool,
+}
+
+async fn destroy(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Query(params): Query<DestroyParams>,
+) -> Result<Json<()>> {
+    let ctl = &state.ctl;
+    validate_id(&id)?;
+    let _guard = state.lock(&id).await;
+
+    match state.job(&id) {
+        Some(Job::Pending(_)) => return Err(ApiError::JobPending(InstanceId::json(id))),
+        Some(Job::Failed(_)) => state.remove_job(&id),
+        None => (),
+    }
+
+    // Destroy is idempotent unless the caller asks to know about missing instances
+    if !ctl.exists(&id) {
+        if params.strict {
+            return Err(ApiError::NotFound(InstanceId::json(id)));
+        }
+        return Ok(Json(()));
+    }
+
+    destroy_instance(ctl, &id).await?;
+    Ok(Json(()))
+}
+
+async fn destroy_instance(ctl: &pg_ctl::PgCtl, id: &str) -> std::result::Result<(), pg_ctl::Error> {
+    if ctl.is_running(id) {
+        // The data dir is removed right after, so there's nothing to shut down cleanly for
+        ctl.stop(id, pg_ctl::StopMode::Immediate, true).await?;
+    }
+
+    ctl.destroy(id).await
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct BulkDestroyRequest {
+    #[serde(default)]
+    ids: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct BulkDestroyParams {
+    /// Required to destroy every instance when no ids are given
+    #[serde(default)]
+    all: bool,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct DestroyResult {
+    id: String,
+    destroyed: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl DestroyResult {
+    fn new(id: String, result: std::result::Result<(), String>) -> DestroyResult {
+        match result {
+            Ok(()) => DestroyResult {
+                id,
+                destroyed: true,
+                error: None,
+            },
+            Err(error) => DestroyResult {
+                id,
+                destroyed: false,
+                error: Some(error),
+            },
+        }
+    }
+}
+
+async fn bulk_destroy(
+    State(state): State<AppState>,
+    Query(params): Query<BulkDestroyParams>,
+    request: Option<Json<BulkDestroyRequest>>,
+) -> Result<Json<Vec<DestroyResult>>> {
+    let ctl = &state.ctl;
+    let Json(request) = request.unwrap_or_default();
+
+    let ids = if request.ids.is_empty() {
+        if !params.all {
+            return Err(ApiError::InvalidParams(
+                "no ids given, pass all=true to destroy every instance".to_string(),
+            ));
+        }
+        ctl.list()
+            .await?
+            .into_iter()
+            .map(|status| status.id)
+            .collect()
+    } else {
+        request.ids
+    };
+
+    let mut results = Vec::with_capacity(ids.len());
+    let mut set = JoinSet::new();
+
+    for id in ids {
+        // Ids end up in paths, so nothing touches disk before they're known to be plain names
+        if let Err(err) = validate_id(&id) {
+            let (_, message) = err.status_and_message();
+            results.push(DestroyResult::new(id, Err(message)));
+            continue;
+        }
+
+        match state.job(&id) {
+            Some(Job::Pending(_)) => {
+                results.push(DestroyResult::new(id, Err("job pending".to_string())));
+                continue;
+            }
+            Some(Job::Failed(_)) => state.remove_job(&id),
+            None => (),
+        }
+
+        let state = state.clone();
+        set.spawn(async move {
+            let _guard = state.lock(&id).await;
+            if !state.ctl.exists(&id) {
+                return DestroyResult::new(id, Ok(()));
+            }
+
+            let result = destroy_instance(&state.ctl, &id)
+                .await
+                .map_err(|err| err.to_string());
+            DestroyResult::new(id, result)
+        });
+    }
+
+    while let Some(value) = set.join_next().await {
+        match value {
+            Ok(result) => results.push(result),
+            Err(join_err) => tracing::error!("bulk destroy task failed: {}", join_err),
+        }
+    }
+
+    results.sort_by(|a, b| a.id.cmp(&b.id));
+    Ok(Json(results))
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct OrphansResponse {
+    logs: Vec<String>,
+    sockets: Vec<String>,
+}
+
+impl From<pg_ctl::Orphans> for OrphansResponse {
+    fn from(orphans: pg_ctl::Orphans) -> Self {
+        OrphansResponse {
+            logs: orphans.logs,
+            sockets: orphans.sockets,
+        }
+    }
+}
+
+async fn orphans(State(state): State<AppState>) -> Result<Json<OrphansResponse>> {
+    let ctl = &state.ctl;
+    Ok(Json(ctl.orphans().await?.into()))
+}
+
+async fn clean_orphans(State(state): State<AppState>) -> Result<Json<OrphansResponse>> {
+    let ctl = &state.ctl;
+    Ok(Json(ctl.clean_orphans().await?.into()))
+}
+
+/// Stops new instances from being created, forked or started ahead of maintenance, everything
+/// else keeps working.
+async fn drain(State(state): State<AppState>) -> Json<serde_json::Value> {
+    state.draining.store(true, Ordering::SeqCst);
+    tracing::info!("draining, new instances are refused");
+    Json(json!({ "draining": true }))
+}
+
+async fn undrain(State(state): State<AppState>) -> Json<serde_json::Value> {
+    state.draining.store(false, Ordering::SeqCst);
+    tracing::info!("no longer draining");
+    Json(json!({ "draining": false }))
+}
+
+async fn version(State(state): State<AppState>) -> Json<serde_json::Value> {
+    let postgres: Vec<serde_json::Value> = state
+        .installations
+        .iter()
+        .map(|installation| {
+            json!({
+                "version": installation.version,
+                "pg_ctl": installation.binary,
+                "server_version": installation.server_version,
+            })
+        })
+        .collect();
+
+    Json(json!({
+        "quickpg": env!("CARGO_PKG_VERSION"),
+        "postgres": postgres,
+    }))
+}
+
+async fn health() -> Json<serde_json::Value> {
+    Json(json!({ "status": "ok" }))
+}
+
+async fn ready(State(state): State<AppState>) -> Response {
+    match state.ctl.readiness().await {
+        Ok(()) => Json(json!({ "ready": true })).into_response(),
+        Err(reason) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(json!({ "ready": false, "reason": reason })),
+        )
+            .into_response(),
+    }
+}
+
+async fn metrics(State(state): State<AppState>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state.metrics.render(),
+    )
+}
+
+/// Builds a CORS layer from the comma separated `QUICKPG_CORS_ORIGINS`, which is off when unset.
+fn cors_layer() -> Option<CorsLayer> {
+    let origins = env::var("QUICKPG_CORS_ORIGINS").ok()?;
+
+    let allow_origin = if origins.trim() == "*" {
+        AllowOrigin::any()
+    } else {
+        let origins: Vec<HeaderValue> = origins
+            .split(',')
+            .map(str::trim)
+            .filter(|origin| !origin.is_empty())
+            .filter_map(|origin| match origin.parse() {
+                Ok(origin) => Some(origin),
+                Err(_) => {
+                    tracing::warn!("ignoring invalid CORS origin: {}", origin);
+                    None
+                }
+            })
+            .collect();
+        AllowOrigin::list(origins)
+    };
+
+    Some(
+        CorsLayer::new()
+            .allow_origin(allow_origin)
+            .allow_methods([Method::GET, Method::POST, Method::PUT, Method::DELETE])
+            .allow_headers([header::CONTENT_TYPE, header::AUTHORIZATION]),
+    )
+}
+
+async fn shutdown_signal() {
+    let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+        .expect("failed to install the SIGTERM handler");
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => (),
+        _ = terminate.recv() => (),
+    }
+    tracing::info!("shutting down");
+}
+
+/// Stops every running instance, giving them `grace` to shut down cleanly before giving up waiting.
+async fn stop_all(ctl: Arc<pg_ctl::PgCtl>, grace: Duration) {
+    let running: Vec<String> = match ctl.list().await {
+        Ok(statuses) => statuses
+            .into_iter()
+            .filter(|status| status.is_running())
+            .map(|status| status.id)
+            .collect(),
+        Err(err) => {
+            tracing::error!("failed to list instances to stop: {}", err);
+            return;
+        }
+    };
+
+    let mut set = JoinSet::new();
+    for id in running.clone() {
+        let ctl = ctl.clone();
+        set.spawn(async move {
+            if let Err(err) = ctl.stop(&id, pg_ctl::StopMode::Fast, true).await {
+                tracing::warn!("failed to stop {}: {}", id, err);
+            }
+        });
+    }
+
+    if tokio::time::timeout(grace, async { while set.join_next().await.is_some() {} })
+        .await
+        .is_err()
+    {
+        set.abort_all();
+        for id in running.iter().filter(|id| ctl.is_running(id)) {
+            tracing::warn!("{} did not stop within {:?}, not waiting for it", id, grace);
+            if let Err(err) = ctl.stop(id, pg_ctl::StopMode::Immediate, false).await {
+                tracing::warn!("failed to stop {}: {}", id, err);
+            }
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    logging::init();
+    apply_umask();
+
+    let args = match args::Args::parse() {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(2);
+        }
+    };
+
+    let ctl = match create_ctl(&args) {
+        Ok(ctl) => ctl,
+        Err(err) => {
+            tracing::error!("{}, pass --pg-bin or add pg_ctl to $PATH", err);
+            std::process::exit(1);
+        }
+    };
+    let tls = match tls::Connector::from_env() {
+        Ok(tls) if tls.requires_ssl() && ssl_files().is_none() => {
+            tracing::error!("SSL requires QUICKPG_PG_SSL_CERT_FILE and QUICKPG_PG_SSL_KEY_FILE");
+            std::process::exit(1);
+        }
+        Ok(tls) => tls,
+        Err(err) => {
+            tracing::error!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    let ctl = ctl.with_tls(tls);
+    if let Err(err) = ctl.ensure_dirs().await {
+        tracing::error!("failed to create the directories under the root: {}", err);
+        std::process::exit(1);
+    }
+    let installations = ctl.installations().await;
+    let state = AppState::new(ctl, installations).with_limit(InstanceLimit::from_env());
+
+    let metrics_interval = env::var("QUICKPG_METRICS_INTERVAL_SECS")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .unwrap_or(15);
+    tokio::spawn(metrics::refresh_loop(
+        state.metrics.clone(),
+        state.ctl.clone(),
+        Duration::from_secs(metrics_interval),
+    ));
+
+    let reaper_interval = env::var("QUICKPG_REAPER_INTERVAL_SECS")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .unwrap_or(60);
+    tokio::spawn(reaper::reap_loop(
+        state.clone(),
+        Duration::from_secs(reaper_interval),
+    ));
+
+    let mut app = Router::new()
+        .route("/pg/instance", routing::get(list))
+        .route("/pg/instance", routing::post(create))
+        .route("/pg/instance", routing::delete(bulk_destroy))
+        .route("/pg/instance/swap", routing::post(swap))
+        .route("/pg/instance/import", routing::post(import))
+        .route("/pg/instance/:id", routing::get(status))
+        .route("/pg/instance/:id", routing::put(upsert))
+        .route("/pg/instance/:id/start", routing::post(start))
+        .route("/pg/instance/:id/restart", routing::post(restart))
+        .route("/pg/instance/:id/reload", routing::post(reload))
+        .route("/pg/instance/:id/stop", routing::post(stop))
+        .route("/pg/instance/:id/kill", routing::post(kill))
+        .route("/pg/instance/:id/fork", routing::post(fork))
+        .route("/pg/instance/:id/fork/plan", routing::get(fork_plan))
+        .route("/pg/instance/:id/replica", routing::post(replica))
+        .route("/pg/instance/:id/promote", routing::post(promote))
+        .route("/pg/instance/:id/file", routing::post(write_file))
+        .route("/pg/instance/:id/rename", routing::post(rename))
+        .route(
+            "/pg/instance/:id/validate-template",
+            routing::post(validate_template),
+        )
+        .route("/pg/instance/:id/logs", routing::get(logs))
+        .route("/pg/instance/:id/tables", routing::get(tables))
+        .route("/pg/instance/:id/query", routing::post(query))
+        .route("/pg/instance/:id/dump", routing::get(dump))
+        .route("/pg/instance/:id/restore", routing::post(restore))
+        .route("/pg/instance/:id/archive", routing::get(archive))
+        .route("/pg/instance/:id/locks", routing::get(locks))
+        .route("/pg/instance/:id", routing::delete(destroy))
+        .route("/pg/admin/orphans", routing::get(orphans))
+        .route("/pg/admin/orphans/clean", routing::post(clean_orphans))
+        .route("/pg/admin/drain", routing::post(drain))
+        .route("/pg/admin/undrain", routing::post(undrain))
+        .route("/version", routing::get(version))
+        .route("/health", routing::get(health))
+        .route("/ready", routing::get(ready))
+        .route("/metrics", routing::get(metrics));
+
+    // Inside the auth layer, so rejected requests don't use up the limit
+    if let Some(limiter) = rate_limit::RateLimiter::from_env() {
+        app = app.layer(middleware::from_fn_with_state(
+            limiter,
+            rate_limit::limit_mutations,
+        ));
+    }
+    // Inside the CORS layer, so preflight requests don't need the token
+    if let Some(token) = auth::ApiToken::from_env() {
+        app = app.layer(middleware::from_fn_with_state(token, auth::require_token));
+    }
+    if let Some(cors) = cors_layer() {
+        app = app.layer(cors);
+    }
+
+    let ctl = state.ctl.clone();
+    let app = app
+        .layer(TraceLayer::new_for_http().make_span_with(logging::request_span))
+        .layer(middleware::from_fn(logging::request_context))
+        .with_state(state);
+
+    tracing::info!("listening on {}", args.bind);
+    axum::Server::bind(&args.bind)
         .serve(app.into_make_service())
+        .with_graceful_shutdown(shutdown_signal())
         .await
         .unwrap();
+
+    let grace = env::var("QUICKPG_SHUTDOWN_GRACE_SECS")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .unwrap_or(30);
+    stop_all(ctl, Duration::from_secs(grace)).await;
 }
@@ -0,0 +1,255 @@
+use std::{
+    fmt::Write,
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+use tokio::{sync::Semaphore, task::JoinSet};
+
+use crate::pg_ctl::{PgCtl, Status};
+
+const REFRESH_CONCURRENCY: usize = 4;
+const DURATION_BUCKETS: &[f64] = &[0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+
+#[derive(Debug, Clone)]
+struct InstanceMetrics {
+    id: String,
+    up: bool,
+    connections: Option<i64>,
+    disk_bytes: Option<u64>,
+}
+
+#[derive(Debug, Default)]
+struct HistogramState {
+    /// Cumulative counts, one per entry in `DURATION_BUCKETS`
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+/// Prometheus histogram of operation durations in seconds.
+#[derive(Debug, Default)]
+pub struct Histogram {
+    state: Mutex<HistogramState>,
+}
+
+impl Histogram {
+    pub fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        let mut state = self.state.lock().unwrap();
+
+        if state.buckets.is_empty() {
+            state.buckets = vec![0; DURATION_BUCKETS.len()];
+        }
+        for (bucket, bound) in state.buckets.iter_mut().zip(DURATION_BUCKETS) {
+            if seconds <= *bound {
+                *bucket += 1;
+            }
+        }
+        state.sum += seconds;
+        state.count += 1;
+    }
+
+    /// Awaits `future`, recording how long it took.
+    pub async fn time<T>(&self, future: impl Future<Output = T>) -> T {
+        let started = Instant::now();
+        let output = future.await;
+        self.observe(started.elapsed());
+        output
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let state = self.state.lock().unwrap();
+
+        writeln!(out, "# HELP {} {}", name, help).unwrap();
+        writeln!(out, "# TYPE {} histogram", name).unwrap();
+        for (i, bound) in DURATION_BUCKETS.iter().enumerate() {
+            let count = state.buckets.get(i).copied().unwrap_or(0);
+            writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, count).unwrap();
+        }
+        writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, state.count).unwrap();
+        writeln!(out, "{}_sum {}", name, state.sum).unwrap();
+        writeln!(out, "{}_count {}", name, state.count).unwrap();
+    }
+}
+
+/// Per-instance gauges, refreshed in the background so scrapes never touch disk or the DBs,
+/// alongside timings recorded by the API handlers.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    instances: RwLock<Vec<InstanceMetrics>>,
+    pub create_duration: Histogram,
+    pub fork_duration: Histogram,
+    pub start_duration: Histogram,
+    pub stop_duration: Histogram,
+    fork_failures: AtomicU64,
+}
+
+impl Metrics {
+    pub fn fork_failed(&self) {
+        self.fork_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn render(&self) -> String {
+        let instances = self.instances.read().unwrap();
+        let mut out = String::new();
+
+        let running = instances.iter().filter(|instance| instance.up).count();
+        writeln!(
+            out,
+            "# HELP quickpg_instances_total Instances known to quickpg, by state."
+        )
+        .unwrap();
+        writeln!(out, "# TYPE quickpg_instances_total gauge").unwrap();
+        writeln!(
+            out,
+            "quickpg_instances_total{{state=\"running\"}} {}",
+            running
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "quickpg_instances_total{{state=\"stopped\"}} {}",
+            instances.len() - running
+        )
+        .unwrap();
+
+        write_family(
+            &mut out,
+            "quickpg_instance_up",
+            "Whether the instance's postmaster is running.",
+            instances
+                .iter()
+                .map(|instance| (&instance.id, Some(instance.up as u64 as f64))),
+        );
+        write_family(
+            &mut out,
+            "quickpg_instance_connections",
+            "Active backends connected to the instance's database.",
+            instances
+                .iter()
+                .map(|instance| (&instance.id, instance.connections.map(|c| c as f64))),
+        );
+        write_family(
+            &mut out,
+            "quickpg_instance_disk_bytes",
+            "Apparent size of the instance's data dir.",
+            instances
+                .iter()
+                .map(|instance| (&instance.id, instance.disk_bytes.map(|b| b as f64))),
+        );
+
+        self.create_duration.render(
+            &mut out,
+            "quickpg_create_duration_seconds",
+            "Time taken to create an instance.",
+        );
+        self.fork_duration.render(
+            &mut out,
+            "quickpg_fork_duration_seconds",
+            "Time taken to fork an instance.",
+        );
+        self.start_duration.render(
+            &mut out,
+            "quickpg_start_duration_seconds",
+            "Time taken to start an instance.",
+        );
+        self.stop_duration.render(
+            &mut out,
+            "quickpg_stop_duration_seconds",
+            "Time taken to stop an instance.",
+        );
+
+        writeln!(
+            out,
+            "# HELP quickpg_fork_failures_total Forks that returned an error."
+        )
+        .unwrap();
+        writeln!(out, "# TYPE quickpg_fork_failures_total counter").unwrap();
+        writeln!(
+            out,
+            "quickpg_fork_failures_total {}",
+            self.fork_failures.load(Ordering::Relaxed)
+        )
+        .unwrap();
+
+        out
+    }
+
+    pub async fn refresh(&self, ctl: Arc<PgCtl>) {
+        let statuses = match ctl.list().await {
+            Ok(statuses) => statuses,
+            Err(err) => {
+                tracing::warn!("metrics refresh failed to list instances: {}", err);
+                return;
+            }
+        };
+
+        let permits = Arc::new(Semaphore::new(REFRESH_CONCURRENCY));
+        let mut set = JoinSet::new();
+
+        for status in statuses {
+            let ctl = ctl.clone();
+            let permits = permits.clone();
+            set.spawn(async move {
+                let _permit = permits.acquire_owned().await.unwrap();
+                collect(&ctl, status).await
+            });
+        }
+
+        let mut instances = vec![];
+        while let Some(result) = set.join_next().await {
+            match result {
+                Ok(instance) => instances.push(instance),
+                Err(err) => tracing::warn!("metrics refresh task failed: {}", err),
+            }
+        }
+        instances.sort_by(|a, b| a.id.cmp(&b.id));
+
+        *self.instances.write().unwrap() = instances;
+    }
+}
+
+pub async fn refresh_loop(metrics: Arc<Metrics>, ctl: Arc<PgCtl>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+
+    loop {
+        ticker.tick().await;
+        metrics.refresh(ctl.clone()).await;
+    }
+}
+
+async fn collect(ctl: &PgCtl, status: Status) -> InstanceMetrics {
+    let connections = if status.is_running() {
+        ctl.connections(&status).await.ok()
+    } else {
+        None
+    };
+
+    InstanceMetrics {
+        up: status.is_running(),
+        connections,
+        disk_bytes: ctl.disk_usage(&status.id).await.ok(),
+        id: status.id,
+    }
+}
+
+fn write_family<'a>(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    samples: impl Iterator<Item = (&'a String, Option<f64>)>,
+) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} gauge", name).unwrap();
+
+    for (id, value) in samples {
+        if let Some(value) = value {
+            writeln!(out, "{}{{id=\"{}\"}} {}", name, id, value).unwrap();
+        }
+    }
+}
@@ -1,23 +1,161 @@
 use std::{
-    env, io,
-    path::{Path, PathBuf},
-    process::Output,
+    collections::HashMap,
+    env,
+    ffi::{CString, OsStr},
+    fmt, io,
+    os::unix::{ffi::OsStrExt, fs::MetadataExt},
+    path::{Component, Path, PathBuf},
+    process::{Output, Stdio},
     str,
+    time::Duration,
 };
 
 use serde::{Deserialize, Serialize};
-use tokio::{self, io::AsyncWriteExt, process::Command};
-use tokio_postgres::{self, Config, NoTls};
+use tokio::{
+    self,
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+    process::{Child, ChildStdin, ChildStdout, Command},
+};
+use tokio_postgres::{self, error::SqlState, Client, Config, SimpleQueryMessage};
+
+use crate::{
+    cgroup::{self, ResourceLimits, ResourceUsage},
+    config::{self, InitdbOptions, PostgresqlConf},
+    copy::{self, CopyPlan, CopyStrategy, ForkMode},
+    reaper::now,
+    timestamp,
+    tls::Connector,
+};
 
-use crate::{config::PostgresqlConf, copy};
+const QUERY_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_INTROSPECTION_ROWS: i64 = 1000;
+const DUMP_CHUNK_BYTES: usize = 64 * 1024;
+const LOG_CHUNK_BYTES: u64 = 64 * 1024;
+const MAX_STDOUT_SNIPPET: usize = 500;
+const PORT_ATTEMPTS: usize = 3;
+const READY_TIMEOUT: Duration = Duration::from_secs(30);
+const READY_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// Provisioning connections back off from 50ms up to 1s, a little over 5s in total
+const CONNECT_ATTEMPTS: u32 = 10;
+const CONNECT_BACKOFF: Duration = Duration::from_millis(50);
+const MAX_CONNECT_BACKOFF: Duration = Duration::from_secs(1);
+/// Longer than the 60 seconds `pg_ctl` itself waits for a start or stop
+const DEFAULT_COMMAND_TIMEOUT: Duration = Duration::from_secs(90);
+/// How long `kill` waits after SIGQUIT before resorting to SIGKILL
+const KILL_GRACE: Duration = Duration::from_secs(5);
+/// `sun_path` is 108 bytes on Linux, including the trailing NUL
+const MAX_SOCKET_PATH: usize = 107;
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
     Io(io::Error),
     Postgres(tokio_postgres::Error),
     CliError(String),
     InvalidPidFile(PathBuf),
     DataDirNotFound(PathBuf),
+    DataDirExists(PathBuf),
+    SocketPathTooLong(PathBuf),
+    InvalidPath(PathBuf),
+    BinaryNotFound(PathBuf),
+    VersionNotInstalled(String),
+    Extension(String, tokio_postgres::Error),
+    InitSql(tokio_postgres::Error),
+    Query(tokio_postgres::Error),
+    /// How `psql` or `pg_restore` exited and what it wrote to stderr
+    Restore(String),
+    /// Why an archive couldn't become an instance, including `tar`'s stderr
+    Import(String),
+    StartTimeout(String, Duration),
+    /// The id and the last `FATAL` or `PANIC` line the server logged before exiting
+    StartFailed(String, String),
+    Timeout(String, Duration),
+    TemplateNotCleanlyStopped(String, String),
+    /// The id of an instance asked to promote that isn't a standby
+    NotInRecovery(String),
+    /// initdb's output when it rejected the requested encoding or locale
+    Initdb(String),
+    /// The name of the template database and why it can't be copied
+    TemplateDatabase(String, String),
+    CopyMismatch(PathBuf),
+    /// The id of a running instance that can't stream a base backup, its `wal_level` and
+    /// `max_wal_senders`
+    ReplicationDisabled(String, String, i64),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(formatter, "io: {}", err),
+            Error::Postgres(err) => write!(formatter, "postgres: {}", err),
+            Error::CliError(stderr) => write!(formatter, "cli: {}", stderr),
+            Error::InvalidPidFile(path) => {
+                write!(formatter, "invalid pid file: {}", path.display())
+            }
+            Error::DataDirNotFound(path) => {
+                write!(formatter, "data dir not found: {}", path.display())
+            }
+            Error::DataDirExists(path) => {
+                write!(formatter, "data dir already exists: {}", path.display())
+            }
+            Error::SocketPathTooLong(path) => write!(
+                formatter,
+                "socket path is longer than the {} bytes Unix sockets allow, pick a shorter --socket-dir: {}",
+                MAX_SOCKET_PATH,
+                path.display()
+            ),
+            Error::InvalidPath(path) => write!(formatter, "invalid path: {}", path.display()),
+            Error::BinaryNotFound(path) => {
+                write!(formatter, "pg_ctl binary not found: {}", path.display())
+            }
+            Error::VersionNotInstalled(version) => {
+                write!(formatter, "postgres version not installed: {}", version)
+            }
+            Error::Extension(name, err) => {
+                write!(formatter, "failed to create extension {}: {}", name, err)
+            }
+            Error::InitSql(err) => {
+                write!(formatter, "init_sql failed: {}", err)?;
+                write_position(formatter, err)
+            }
+            Error::Restore(message) => write!(formatter, "restore failed: {}", message),
+            Error::Import(message) => write!(formatter, "import failed: {}", message),
+            Error::Query(err) => {
+                write!(formatter, "query failed: {}", err)?;
+                write_position(formatter, err)
+            }
+            Error::TemplateNotCleanlyStopped(id, state) => write!(
+                formatter,
+                "template {} was not shut down cleanly (cluster state \"{}\"), start and stop it first",
+                id, state
+            ),
+            Error::NotInRecovery(id) => {
+                write!(formatter, "{} is not in recovery, only standbys can be promoted", id)
+            }
+            Error::Initdb(stderr) => write!(formatter, "initdb failed: {}", stderr),
+            Error::TemplateDatabase(name, reason) => {
+                write!(formatter, "template database {} {}", name, reason)
+            }
+            Error::CopyMismatch(path) => {
+                write!(formatter, "fork differs from its template: {}", path.display())
+            }
+            Error::StartFailed(id, cause) => write!(formatter, "{} failed to start: {}", id, cause),
+            Error::Timeout(program, timeout) => {
+                write!(formatter, "{} timed out after {:?}", program, timeout)
+            }
+            Error::StartTimeout(id, timeout) => write!(
+                formatter,
+                "{} did not accept connections within {:?}",
+                id, timeout
+            ),
+            Error::ReplicationDisabled(id, wal_level, senders) => write!(
+                formatter,
+                "{} runs with wal_level = {} and max_wal_senders = {}, online forks and replicas \
+                 need wal_level = replica and max_wal_senders of at least 2",
+                id, wal_level, senders
+            ),
+        }
+    }
 }
 
 impl From<io::Error> for Error {
@@ -26,6 +164,16 @@ impl From<io::Error> for Error {
     }
 }
 
+/// Points at where in the submitted SQL the server gave up.
+fn write_position(formatter: &mut fmt::Formatter, err: &tokio_postgres::Error) -> fmt::Result {
+    match err.as_db_error().and_then(|db| db.position()) {
+        Some(tokio_postgres::error::ErrorPosition::Original(position)) => {
+            write!(formatter, " (at character {})", position)
+        }
+        _ => Ok(()),
+    }
+}
+
 impl From<tokio_postgres::Error> for Error {
     fn from(err: tokio_postgres::Error) -> Self {
         Error::Postgres(err)
@@ -38,8 +186,38 @@ type Result<T> = std::result::Result<T, Error>;
 pub struct Status {
     pub id: String,
     pub dbname: String,
+    /// Role quickpg connects as
+    pub user: String,
+    /// Role that owns `dbname` when it isn't `user`
+    pub owner: Option<String>,
     pub port: u32,
     pub pid: Option<u32>,
+    pub limits: Option<ResourceLimits>,
+    pub usage: Option<ResourceUsage>,
+    pub data_owner: Option<Ownership>,
+    pub expires_at: Option<u64>,
+    pub version: Option<String>,
+    /// Major version from the data dir's `PG_VERSION`, which may differ from `version`
+    pub server_version: Option<String>,
+    pub labels: HashMap<String, String>,
+    pub created_at: Option<u64>,
+    pub last_started_at: Option<u64>,
+    /// Unix timestamp in seconds the running postmaster started at, from its pid file
+    pub started_at: Option<u64>,
+    /// Absolute directory holding the instance's Unix socket
+    pub socket_dir: PathBuf,
+    pub role: ReplicationRole,
+    /// Instance a standby was created from, kept after it's promoted
+    pub primary: Option<String>,
+}
+
+/// A standby is in recovery, streaming WAL from its primary, until it's promoted.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReplicationRole {
+    #[default]
+    Primary,
+    Standby,
 }
 
 impl Status {
@@ -47,40 +225,369 @@ impl Status {
         self.pid.is_some()
     }
 
-    fn running(id: impl Into<String>, dbname: impl Into<String>, port: u32, pid: u32) -> Status {
+    fn running(
+        id: impl Into<String>,
+        meta: Metadata,
+        pid: u32,
+        usage: Option<ResourceUsage>,
+        data_owner: Option<Ownership>,
+        socket_dir: PathBuf,
+    ) -> Status {
         Status {
             id: id.into(),
-            dbname: dbname.into(),
-            port,
+            dbname: meta.dbname,
+            user: meta.user.unwrap_or_default(),
+            owner: meta.owner,
+            port: meta.port,
             pid: Some(pid),
+            limits: meta.limits,
+            usage,
+            data_owner,
+            expires_at: meta.expires_at,
+            version: meta.version,
+            server_version: None,
+            labels: meta.labels,
+            created_at: meta.created_at,
+            last_started_at: meta.last_started_at,
+            started_at: None,
+            socket_dir,
+            role: ReplicationRole::default(),
+            primary: meta.primary,
         }
     }
 
-    fn stopped(id: impl Into<String>, dbname: impl Into<String>, port: u32) -> Status {
+    fn stopped(
+        id: impl Into<String>,
+        meta: Metadata,
+        data_owner: Option<Ownership>,
+        socket_dir: PathBuf,
+    ) -> Status {
         Status {
             id: id.into(),
-            dbname: dbname.into(),
-            port,
+            dbname: meta.dbname,
+            user: meta.user.unwrap_or_default(),
+            owner: meta.owner,
+            port: meta.port,
             pid: None,
+            limits: meta.limits,
+            usage: None,
+            data_owner,
+            expires_at: meta.expires_at,
+            version: meta.version,
+            server_version: None,
+            labels: meta.labels,
+            created_at: meta.created_at,
+            last_started_at: meta.last_started_at,
+            started_at: None,
+            socket_dir,
+            role: ReplicationRole::default(),
+            primary: meta.primary,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ownership {
+    pub uid: u32,
+    pub gid: u32,
+}
+
+#[derive(Debug)]
+pub struct TableStats {
+    pub schema: String,
+    pub name: String,
+    pub estimated_rows: i64,
+    pub size_bytes: i64,
+}
+
+/// One statement of a `query` batch, rows are in Postgres' text format.
+#[derive(Debug)]
+pub struct QueryResult {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<Option<String>>>,
+    /// Rows returned, or changed by DML
+    pub rows_affected: u64,
+}
+
+#[derive(Debug)]
+pub struct LockWait {
+    pub pid: i32,
+    pub dbname: Option<String>,
+    pub locktype: String,
+    pub mode: Option<String>,
+    pub relation: Option<String>,
+    pub query: Option<String>,
+    pub blocked_by: Vec<i32>,
+}
+
+#[derive(Debug)]
+pub struct Backend {
+    pub pid: i32,
+    pub dbname: Option<String>,
+    pub state: Option<String>,
+    pub query: Option<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct Orphans {
+    pub logs: Vec<String>,
+    pub sockets: Vec<String>,
+}
+
+/// Crash recovery performed by the last start, as reported in the instance's log.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Recovery {
+    pub recovered: bool,
+    pub duration_ms: Option<u64>,
+}
+
+/// A `pg_ctl` quickpg can run instances with.
+#[derive(Debug, Clone)]
+pub struct Installation {
+    /// `None` for the default binary, otherwise the `bin/<version>` it was found in
+    pub version: Option<String>,
+    pub binary: PathBuf,
+    /// As reported by `pg_ctl --version`, `None` if that failed
+    pub server_version: Option<String>,
+}
+
+/// How `pg_ctl stop` treats connected clients, see its `--mode`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StopMode {
+    /// Waits for every client to disconnect
+    Smart,
+    /// Disconnects clients and shuts down cleanly
+    #[default]
+    Fast,
+    /// Aborts without a shutdown checkpoint, the next start runs crash recovery
+    Immediate,
+}
+
+impl StopMode {
+    fn as_str(&self) -> &'static str {
+        match self {
+            StopMode::Smart => "smart",
+            StopMode::Fast => "fast",
+            StopMode::Immediate => "immediate",
+        }
+    }
+}
+
+/// Output format of `pg_dump`, see its `--format`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DumpFormat {
+    /// SQL script for `psql`
+    #[default]
+    Plain,
+    /// Compressed archive for `pg_restore`
+    Custom,
+}
+
+impl DumpFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DumpFormat::Plain => "plain",
+            DumpFormat::Custom => "custom",
+        }
+    }
+}
+
+/// Compression of a data dir archive.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ArchiveFormat {
+    #[default]
+    Tar,
+    /// Compressed with `zstd`, which has to be on `$PATH`
+    Zst,
+}
+
+impl ArchiveFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ArchiveFormat::Tar => "tar",
+            ArchiveFormat::Zst => "tar.zst",
+        }
+    }
+}
+
+/// A running `pg_dump` or `tar`, read with `next_chunk` so the output never has to fit in memory.
+pub struct Dump {
+    head: Option<Vec<u8>>,
+    stdout: Option<ChildStdout>,
+    child: Option<Child>,
+}
+
+impl Dump {
+    /// The next piece of the output, `None` once the process exited successfully.
+    pub async fn next_chunk(&mut self) -> Result<Option<Vec<u8>>> {
+        if let Some(head) = self.head.take() {
+            return Ok(Some(head));
+        }
+
+        let stdout = match self.stdout.as_mut() {
+            Some(stdout) => stdout,
+            None => return Ok(None),
+        };
+
+        let mut chunk = vec![0; DUMP_CHUNK_BYTES];
+        let read = stdout.read(&mut chunk).await?;
+        if read > 0 {
+            chunk.truncate(read);
+            return Ok(Some(chunk));
+        }
+
+        self.stdout = None;
+        if let Some(child) = self.child.take() {
+            PgCtl::check_output(&child.wait_with_output().await?)?;
+        }
+        Ok(None)
+    }
+}
+
+/// A running `psql`, `pg_restore` or `tar --extract` fed with `write`, so its input never has
+/// to fit in memory.
+pub struct Restore {
+    stdin: ChildStdin,
+    child: Child,
+    /// Drained as it's written, a full pipe would otherwise stall the restore
+    stderr: tokio::task::JoinHandle<io::Result<Vec<u8>>>,
+    /// Wraps what the process wrote to stderr when it fails
+    fail: fn(String) -> Error,
+}
+
+impl Restore {
+    fn spawn(command: &mut Command, fail: fn(String) -> Error) -> Result<Restore> {
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+
+        let stdin = child.stdin.take().unwrap();
+        let mut stderr = child.stderr.take().unwrap();
+        let stderr = tokio::spawn(async move {
+            let mut buffer = vec![];
+            stderr.read_to_end(&mut buffer).await?;
+            Ok(buffer)
+        });
+
+        Ok(Restore {
+            stdin,
+            child,
+            stderr,
+            fail,
+        })
+    }
+
+    pub async fn write(mut self, chunk: &[u8]) -> Result<Restore> {
+        match self.stdin.write_all(chunk).await {
+            Ok(()) => Ok(self),
+            // It exited early, its stderr says why
+            Err(err) if err.kind() == io::ErrorKind::BrokenPipe => {
+                self.finish().await?;
+                Err(err.into())
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Closes stdin and waits for the restore to be applied.
+    pub async fn finish(mut self) -> Result<()> {
+        // Unbuffered, so closing it is all there is to do
+        drop(self.stdin);
+
+        let status = self.child.wait().await?;
+        let stderr = self
+            .stderr
+            .await
+            .map_err(|join_err| io::Error::other(format!("join_err: {}", join_err)))??;
+
+        let output = Output {
+            status,
+            stdout: vec![],
+            stderr,
+        };
+        match PgCtl::check_output(&output) {
+            Err(Error::CliError(message)) => Err((self.fail)(message)),
+            result => result,
         }
     }
 }
 
+/// Instance settings recorded in its metadata at init.
+#[derive(Debug, Default, Clone)]
+pub struct InitOptions {
+    pub limits: Option<ResourceLimits>,
+    pub expires_at: Option<u64>,
+    pub version: Option<String>,
+    /// Created in the new database, the instance is destroyed if any of them fail
+    pub extensions: Vec<String>,
+    /// Run as a single batch after the extensions, the instance is destroyed if it fails
+    pub init_sql: Option<String>,
+    /// Role created to own the database instead of the server's user
+    pub owner: Option<Role>,
+    pub labels: HashMap<String, String>,
+    /// Database the new one is copied from with `CREATE DATABASE ... TEMPLATE`
+    pub template_database: Option<String>,
+    pub initdb: InitdbOptions,
+}
+
+#[derive(Debug, Clone)]
+pub struct Role {
+    pub name: String,
+    pub password: Option<Password>,
+}
+
+/// Kept out of `Debug` output so it can't end up in logs.
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(transparent)]
+pub struct Password(String);
+
+impl fmt::Debug for Password {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "Password(..)")
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 struct Metadata {
     dbname: String,
+    /// Role that created the database, instances from before it was recorded use the current user
+    #[serde(default)]
+    user: Option<String>,
+    #[serde(default)]
+    owner: Option<String>,
     port: u32,
+    #[serde(default)]
+    limits: Option<ResourceLimits>,
+    /// Unix timestamp in seconds after which the reaper destroys the instance
+    #[serde(default)]
+    expires_at: Option<u64>,
+    /// Major version from the `bin/<version>` layout, the default binary when missing
+    #[serde(default)]
+    version: Option<String>,
+    /// Free-form tags used to filter the instance list
+    #[serde(default)]
+    labels: HashMap<String, String>,
+    /// Init or fork, missing for instances from before it was recorded
+    #[serde(default, with = "timestamp")]
+    created_at: Option<u64>,
+    /// Last successful start or restart
+    #[serde(default, with = "timestamp")]
+    last_started_at: Option<u64>,
+    /// Id of the instance a replica streams from
+    #[serde(default)]
+    primary: Option<String>,
 }
 
 impl Metadata {
     async fn to_file(&self, path: &Path) -> io::Result<()> {
         let serialized = serde_json::to_vec(self)?;
-
-        let mut file = tokio::fs::File::create(path).await?;
-        file.write_all(&serialized).await?;
-        file.flush().await?;
-
-        Ok(())
+        copy::write_atomic(path.to_path_buf(), serialized).await
     }
 
     async fn from_file(path: &Path) -> io::Result<Metadata> {
@@ -93,207 +600,2282 @@ impl Metadata {
 pub struct PgCtl {
     pub user: String,
     binary: PathBuf,
+    /// Holds one `<version>/pg_ctl` per installed major version
+    versions: PathBuf,
     logs: PathBuf,
     data: PathBuf,
     sockets: PathBuf,
+    owner: Option<Ownership>,
+    copy_strategy: CopyStrategy,
+    subvolumes: bool,
+    command_timeout: Duration,
+    copy_concurrency: usize,
+    verify_copies: bool,
+    sync_copies: bool,
+    /// Mode of the directories quickpg creates, 0700 or 0750 for group access
+    dir_mode: u32,
+    tls: Connector,
 }
 
 impl PgCtl {
-    pub fn new(user: impl Into<String>, root: &Path) -> PgCtl {
-        PgCtl {
+    /// Uses `binary` when given, otherwise `bin/pg_ctl` under `root`, otherwise `pg_ctl` on `$PATH`.
+    pub fn new(user: impl Into<String>, root: &Path, binary: Option<PathBuf>) -> Result<PgCtl> {
+        // Postgres is told where to put its socket, clients need the absolute path to find it
+        let root = env::current_dir()?.join(root);
+
+        let binary = match binary {
+            Some(binary) if binary.is_file() => binary,
+            Some(binary) => return Err(Error::BinaryNotFound(binary)),
+            None => {
+                let local = root.join("bin/pg_ctl");
+                if local.is_file() {
+                    local
+                } else {
+                    find_in_path("pg_ctl").ok_or(Error::BinaryNotFound(local))?
+                }
+            }
+        };
+
+        Ok(PgCtl {
             user: user.into(),
-            binary: root.join("bin/pg_ctl"),
+            binary,
+            versions: root.join("bin"),
             logs: root.join("logs"),
             data: root.join("data"),
             sockets: root.join("sockets"),
-        }
+            owner: None,
+            copy_strategy: CopyStrategy::default(),
+            subvolumes: false,
+            command_timeout: DEFAULT_COMMAND_TIMEOUT,
+            copy_concurrency: copy::default_concurrency(),
+            verify_copies: false,
+            sync_copies: false,
+            dir_mode: copy::DEFAULT_DIR_MODE,
+            tls: Connector::Plain,
+        })
     }
 
-    pub async fn init<'a>(&self, id: &str, dbname: &str, conf: &PostgresqlConf<'a>) -> Result<()> {
-        let output = Command::new(&self.binary)
-            .args(["--pgdata", &join_str(&self.data, id), "-o--no-sync", "init"])
-            .output()
-            .await?;
+    pub fn socket_dir(&self) -> &Path {
+        &self.sockets
+    }
 
-        PgCtl::check_output(&output)?;
+    /// Moves the Unix sockets out of `root/sockets`, `dir` has to be absolute.
+    pub fn with_socket_dir(mut self, dir: PathBuf) -> PgCtl {
+        self.sockets = dir;
+        self
+    }
 
-        conf.to_config()
-            .to_file(&self.data.join(id).join("postgresql.conf"))
-            .await?;
+    pub fn with_copy_strategy(mut self, copy_strategy: CopyStrategy) -> PgCtl {
+        self.copy_strategy = copy_strategy;
+        self
+    }
 
-        let meta = Metadata {
-            dbname: dbname.to_string(),
-            port: conf.port,
-        };
-        meta.to_file(&self.data.join(id).join("quickpg.json"))
-            .await?;
+    pub fn with_tls(mut self, tls: Connector) -> PgCtl {
+        self.tls = tls;
+        self
+    }
 
-        self.start(id).await?;
+    /// Creates each new data dir as a Btrfs subvolume when `data` lives on Btrfs.
+    pub fn with_subvolumes(mut self, subvolumes: bool) -> PgCtl {
+        self.subvolumes = subvolumes;
+        self
+    }
 
-        PgCtl::create_database(dbname, &self.user, conf.port).await?;
+    /// Bounds how many files a physical fork copies at once.
+    pub fn with_copy_concurrency(mut self, concurrency: usize) -> PgCtl {
+        self.copy_concurrency = concurrency;
+        self
+    }
 
-        Ok(())
+    /// Compares each physical fork with its template byte for byte before starting it.
+    pub fn with_verify_copies(mut self, verify_copies: bool) -> PgCtl {
+        self.verify_copies = verify_copies;
+        self
     }
 
-    pub fn exists(&self, id: &str) -> bool {
-        self.data.join(id).is_dir()
+    /// Fsyncs every file and directory of a physical fork before starting it, templates run
+    /// with `fsync` off so nothing else would.
+    pub fn with_sync_copies(mut self, sync_copies: bool) -> PgCtl {
+        self.sync_copies = sync_copies;
+        self
     }
 
-    pub async fn start(&self, id: &str) -> Result<()> {
-        let absolute_sockets = env::current_dir()?
-            .join(&self.sockets)
-            .to_string_lossy()
-            .into_owned();
+    /// Group bits let the owner's group read data dirs, and have initdb allow it too. `mode` has
+    /// to pass `copy::validate_dir_mode`.
+    pub fn with_dir_mode(mut self, mode: u32) -> PgCtl {
+        self.dir_mode = mode;
+        self
+    }
 
-        let output = Command::new(&self.binary)
-            .args([
-                "--pgdata",
-                &join_str(&self.data, id),
-                "--log",
-                &join_str(&self.logs, &*format!("{}.log", id)),
-                "--options",
-                &format!("-k{}", absolute_sockets),
-                "start",
-            ])
-            .output()
-            .await?;
+    /// Bounds how long a `pg_ctl`, `pg_controldata` or `pg_basebackup` invocation, a logical
+    /// fork's `pg_dump | psql` or the post-start hook may run before it's killed.
+    pub fn with_command_timeout(mut self, timeout: Duration) -> PgCtl {
+        self.command_timeout = timeout;
+        self
+    }
 
-        PgCtl::check_output(&output)
+    pub fn command_timeout(&self) -> Duration {
+        self.command_timeout
     }
 
-    pub fn is_running(&self, id: &str) -> bool {
-        let pidfile = self.data.join(id).join("postmaster.pid");
-        pidfile.is_file()
+    /// Runs Postgres binaries as `owner` and hands it the data dirs quickpg creates.
+    ///
+    /// Only useful when quickpg runs as root, since Postgres refuses to run as root itself.
+    pub fn with_owner(mut self, owner: Option<Ownership>) -> PgCtl {
+        self.owner = owner;
+        self
     }
 
-    pub async fn status(&self, id: &str) -> Result<Status> {
-        let data = self.data.join(id);
-        if !data.is_dir() {
-            return Err(Error::DataDirNotFound(data));
+    /// Creates missing `data`, `logs` and socket dirs, handing new ones to the owner since
+    /// Postgres writes its logs and sockets into them. Run once at startup.
+    pub async fn ensure_dirs(&self) -> Result<()> {
+        for dir in [&self.data, &self.logs, &self.sockets] {
+            if dir.is_dir() {
+                continue;
+            }
+
+            self.create_owned_dir(dir).await?;
+            tracing::info!("created {}", dir.display());
         }
 
-        let meta = Metadata::from_file(&data.join("quickpg.json")).await?;
+        Ok(())
+    }
 
-        let pidfile = data.join("postmaster.pid");
-        if !pidfile.is_file() {
-            return Ok(Status::stopped(id, meta.dbname, meta.port));
+    async fn create_owned_dir(&self, dir: &Path) -> Result<()> {
+        tokio::fs::DirBuilder::new()
+            .recursive(true)
+            .mode(self.dir_mode)
+            .create(dir)
+            .await?;
+        if let Some(owner) = self.owner {
+            std::os::unix::fs::chown(dir, Some(owner.uid), Some(owner.gid))?;
         }
 
-        let content = tokio::fs::read_to_string(&pidfile).await?;
+        Ok(())
+    }
+
+    fn versioned_binary(&self, version: Option<&str>) -> Result<PathBuf> {
+        let version = match version {
+            Some(version) => version,
+            None => return Ok(self.binary.clone()),
+        };
+
+        let valid = !version.is_empty() && version.chars().all(|c| c.is_ascii_digit() || c == '.');
+        let binary = self.versions.join(version).join("pg_ctl");
+        if !valid || !binary.is_file() {
+            return Err(Error::VersionNotInstalled(version.to_string()));
+        }
+
+        Ok(binary)
+    }
+
+    /// Runs `pg_ctl --version` for the default binary and every `bin/<version>` one.
+    pub async fn installations(&self) -> Vec<Installation> {
+        let mut versions = read_dir_names(&self.versions).await.unwrap_or_default();
+        versions.retain(|version| self.versioned_binary(Some(version)).is_ok());
+        versions.sort();
 
-        if let Some(pid_end_index) = content.find("\n") {
-            if let Ok(pid) = content[0..pid_end_index].parse::<u32>() {
-                return Ok(Status::running(id, meta.dbname, meta.port, pid));
+        let mut installations = vec![Installation {
+            version: None,
+            binary: self.binary.clone(),
+            server_version: self.binary_version(&self.binary).await,
+        }];
+        for version in versions {
+            let binary = self.versions.join(&version).join("pg_ctl");
+            installations.push(Installation {
+                server_version: self.binary_version(&binary).await,
+                version: Some(version),
+                binary,
+            });
+        }
+
+        installations
+    }
+
+    async fn binary_version(&self, binary: &Path) -> Option<String> {
+        let result = self
+            .run(self.command(binary).arg("--version"))
+            .await
+            .and_then(|output| PgCtl::check_output(&output).map(|_| output));
+
+        match result {
+            Ok(output) => Some(String::from_utf8_lossy(&output.stdout).trim().to_string()),
+            Err(err) => {
+                tracing::warn!("{} --version failed: {}", binary.display(), err);
+                None
             }
         }
+    }
 
-        Err(Error::InvalidPidFile(pidfile))
+    /// Resolves the `pg_ctl` matching the version an instance was created with.
+    async fn instance_binary(&self, id: &str) -> Result<PathBuf> {
+        let meta = Metadata::from_file(&self.data.join(id).join("quickpg.json"))
+            .await
+            .ok();
+        self.versioned_binary(meta.and_then(|meta| meta.version).as_deref())
     }
 
-    pub async fn stop(&self, id: &str, wait: bool) -> Result<()> {
-        let data = join_str(&self.data, id);
-        let mut args = vec!["--pgdata", &data];
-        if !wait {
-            args.push("--no-wait");
+    fn command(&self, program: impl AsRef<OsStr>) -> Command {
+        let mut command = Command::new(program);
+        if let Some(owner) = self.owner {
+            command.uid(owner.uid).gid(owner.gid);
         }
-        args.push("stop");
+        command
+    }
 
-        let output = Command::new(&self.binary).args(args).output().await?;
+    /// Runs `command` to completion, killing it if it outlives `command_timeout`.
+    async fn run(&self, command: &mut Command) -> Result<Output> {
+        command.kill_on_drop(true);
+        match tokio::time::timeout(self.command_timeout, command.output()).await {
+            Ok(output) => Ok(output?),
+            Err(_) => {
+                let program = command.as_std().get_program().to_string_lossy();
+                Err(Error::Timeout(program.into_owned(), self.command_timeout))
+            }
+        }
+    }
 
-        PgCtl::check_output(&output)
+    /// Returns the port the instance ended up on, which differs from `conf.port` if it was taken.
+    /// Anything left behind by a failed init is stopped and removed before returning the error.
+    pub async fn init<'a>(
+        &self,
+        id: &str,
+        dbname: &str,
+        conf: &PostgresqlConf<'a>,
+        options: &InitOptions,
+    ) -> Result<u32> {
+        let binary = self.versioned_binary(options.version.as_deref())?;
+
+        let result = self.build(id, dbname, conf, options, &binary).await;
+        if result.is_err() {
+            self.discard(id).await;
+        }
+        result
     }
 
-    pub async fn fork<'a>(
+    async fn build<'a>(
         &self,
-        template: &str,
-        target: &str,
+        id: &str,
         dbname: &str,
         conf: &PostgresqlConf<'a>,
-    ) -> Result<()> {
-        let template_data = self.data.join(template);
-        if !template_data.is_dir() {
-            return Err(Error::DataDirNotFound(template_data));
+        options: &InitOptions,
+        binary: &Path,
+    ) -> Result<u32> {
+        if self.subvolumes && copy::is_btrfs(&self.data) {
+            let data = self.data.join(id);
+            match copy::create_subvolume(&data).await {
+                Ok(()) => {
+                    if let Some(owner) = self.owner {
+                        copy::chown_recursive(data, owner.uid, owner.gid).await?;
+                    }
+                }
+                Err(err) => tracing::warn!("subvolume not created for {}: {}", id, err),
+            }
+        }
+
+        let mut initdb_options = options.initdb.to_options();
+        // initdb names the superuser after whoever runs it, the data owner when there is one
+        initdb_options.push_str(&format!(" --username={}", self.user));
+        if self.dir_mode & 0o070 != 0 {
+            initdb_options.push_str(" --allow-group-access");
+        }
+        let output = self
+            .run(self.command(binary).args([
+                "--pgdata",
+                &join_str(&self.data, id),
+                &format!("-o{}", initdb_options),
+                "init",
+            ]))
+            .await?;
+
+        match PgCtl::check_output(&output) {
+            // Most likely an encoding that doesn't match the locale, or a locale not installed
+            Err(Error::CliError(stderr)) if !options.initdb.is_empty() => {
+                return Err(Error::Initdb(stderr))
+            }
+            result => result?,
+        }
+
+        if conf.archive {
+            self.create_owned_dir(&self.data.join(id).join(config::ARCHIVE_DIR))
+                .await?;
         }
-        copy::copy_pgdata(template_data, self.data.join(target)).await?;
 
         conf.to_config()
-            .to_file(&self.data.join(target).join("postgresql.conf"))
+            .to_file(&self.data.join(id).join("postgresql.conf"))
             .await?;
 
         let meta = Metadata {
             dbname: dbname.to_string(),
+            user: Some(self.user.clone()),
+            owner: options.owner.as_ref().map(|owner| owner.name.clone()),
             port: conf.port,
+            limits: options.limits,
+            expires_at: options.expires_at,
+            version: options.version.clone(),
+            labels: options.labels.clone(),
+            created_at: Some(now()),
+            last_started_at: None,
+            primary: None,
         };
-        meta.to_file(&self.data.join(target).join("quickpg.json"))
+        meta.to_file(&self.data.join(id).join("quickpg.json"))
             .await?;
 
-        return self.start(target).await;
+        let port = self.start_on_free_port(id, conf).await?;
+
+        let owner = match &options.owner {
+            Some(role) => {
+                self.create_role(id, port, role).await?;
+                &role.name
+            }
+            None => &self.user,
+        };
+        self.create_database(
+            id,
+            dbname,
+            port,
+            owner,
+            options.template_database.as_deref(),
+        )
+        .await?;
+        self.seed(id, dbname, port, options).await?;
+
+        Ok(port)
     }
 
-    pub async fn destroy(&self, id: &str) -> Result<()> {
-        let data = self.data.join(id);
-        if !data.is_dir() {
-            return Err(Error::DataDirNotFound(data));
+    /// Creates the requested extensions and runs `init_sql` in the new database.
+    async fn seed(&self, id: &str, dbname: &str, port: u32, options: &InitOptions) -> Result<()> {
+        if options.extensions.is_empty() && options.init_sql.is_none() {
+            return Ok(());
         }
 
-        tokio::fs::remove_dir_all(data).await?;
+        let client = self.connect_socket(id, port, dbname, &self.user).await?;
+        for extension in &options.extensions {
+            client
+                .batch_execute(&format!(
+                    "CREATE EXTENSION IF NOT EXISTS {}",
+                    quote_identifier(extension)
+                ))
+                .await
+                .map_err(|err| Error::Extension(extension.clone(), err))?;
+        }
 
-        let log = self.logs.join(format!("{}.log", id));
-        if log.is_file() {
-            tokio::fs::remove_file(self.logs.join(format!("{}.log", id))).await?;
+        if let Some(sql) = &options.init_sql {
+            // Objects created by the seed belong to the owner, so it can use them
+            if let Some(owner) = &options.owner {
+                client
+                    .batch_execute(&format!("SET ROLE {}", quote_identifier(&owner.name)))
+                    .await?;
+            }
+            client.batch_execute(sql).await.map_err(Error::InitSql)?;
         }
 
         Ok(())
     }
 
-    pub async fn list(&self) -> Result<Vec<Status>> {
-        let mut dir = tokio::fs::read_dir(&self.data).await?;
-        let mut results = vec![];
-
-        while let Some(entry) = dir.next_entry().await? {
-            let id = entry.file_name().to_string_lossy().into_owned();
-            let status = self.status(&id).await?;
-            results.push(status)
+    /// Refuses to build over an existing instance, which a failure would otherwise discard.
+    fn check_free(&self, id: &str) -> Result<()> {
+        let data = self.data.join(id);
+        if data.exists() {
+            return Err(Error::DataDirExists(data));
         }
-
-        Ok(results)
+        Ok(())
     }
 
-    fn check_output(output: &Output) -> Result<()> {
-        if output.status.success() {
-            Ok(())
+    /// Tears down a half-built instance, logging rather than masking the error that caused it.
+    pub async fn discard(&self, id: &str) {
+        if self.is_running(id) {
+            if let Err(err) = self.stop(id, StopMode::Immediate, true).await {
+                tracing::warn!("failed to stop half-built instance {}: {}", id, err);
+            }
+        }
+        // initdb may have failed before creating the data dir, but its log can still be there
+        let result = if self.data.join(id).is_dir() {
+            self.destroy(id).await
         } else {
-            Err(Error::CliError(
-                str::from_utf8(&output.stderr).unwrap().to_string(),
-            ))
+            self.remove_log(id).await
+        };
+        if let Err(err) = result {
+            tracing::warn!("failed to destroy half-built instance {}: {}", id, err);
         }
     }
 
-    async fn create_database(dbname: &str, user: &str, port: u32) -> Result<()> {
-        let mut config = Config::new();
-        config.host("127.0.0.1");
-        config.port(port as u16);
-        config.dbname("postgres");
-        config.user(user);
+    /// Starts the instance, moving it to a fresh port when another process grabbed its port
+    /// between picking it and Postgres binding it. Returns the port it ended up on.
+    async fn start_on_free_port<'a>(&self, id: &str, conf: &PostgresqlConf<'a>) -> Result<u32> {
+        let mut conf = conf.clone();
 
-        let (client, connection) = config.connect(NoTls).await?;
-        tokio::spawn(async move {
-            if let Err(e) = connection.await {
-                eprintln!("connection error: {}", e);
+        for attempt in 1..=PORT_ATTEMPTS {
+            let log_offset = self.log_len(id).await;
+            let err = match self.start(id).await {
+                Ok(()) => return Ok(conf.port),
+                Err(err @ (Error::CliError(_) | Error::StartFailed(_, _))) => err,
+                Err(err) => return Err(err),
+            };
+
+            if attempt == PORT_ATTEMPTS || !self.port_in_use(id, log_offset).await {
+                return Err(err);
             }
-        });
 
-        client
-            .execute(
-                &format!("CREATE DATABASE {} OWNER {}", dbname, user),
-                &vec![],
-            )
-            .await?;
+            let port: u32 = portpicker::pick_unused_port()
+                .ok_or_else(|| io::Error::other("no free port"))?
+                .into();
+            tracing::warn!(
+                "port {} of {} is in use, retrying on {}",
+                conf.port,
+                id,
+                port
+            );
+            conf.port = port;
+            self.set_port(id, &conf).await?;
+        }
 
-        Ok(())
+        unreachable!("the last attempt always returns")
     }
-}
 
-fn join_str<'a, S: Into<&'a str>>(directory: &Path, id: S) -> String {
-    directory.join(id.into()).to_string_lossy().into_owned()
+    async fn port_in_use(&self, id: &str, log_offset: u64) -> bool {
+        self.log_since(id, log_offset)
+            .await
+            .is_some_and(|log| log.contains("Address already in use"))
+    }
+
+    /// What the instance logged after `log_offset`, `None` when there's no log.
+    async fn log_since(&self, id: &str, log_offset: u64) -> Option<String> {
+        let log = tokio::fs::read(self.logs.join(format!("{}.log", id)))
+            .await
+            .ok()?;
+        let start = (log_offset as usize).min(log.len());
+        Some(String::from_utf8_lossy(&log[start..]).into_owned())
+    }
+
+    /// Rewrites the config and metadata of a stopped instance to use `conf.port`.
+    async fn set_port<'a>(&self, id: &str, conf: &PostgresqlConf<'a>) -> Result<()> {
+        let data = self.data.join(id);
+        conf.to_config()
+            .to_file(&data.join("postgresql.conf"))
+            .await?;
+
+        let mut meta = Metadata::from_file(&data.join("quickpg.json")).await?;
+        meta.port = conf.port;
+        meta.to_file(&data.join("quickpg.json")).await?;
+
+        Ok(())
+    }
+
+    /// Replaces the parts of an instance's metadata that may change after it was created.
+    pub async fn set_labels_and_expiry(
+        &self,
+        id: &str,
+        labels: &HashMap<String, String>,
+        expires_at: Option<u64>,
+    ) -> Result<()> {
+        let path = self.data.join(id).join("quickpg.json");
+        let mut meta = Metadata::from_file(&path).await?;
+        meta.labels = labels.clone();
+        meta.expires_at = expires_at;
+        meta.to_file(&path).await?;
+
+        Ok(())
+    }
+
+    /// Checks the binary is still there and the data dir accepts writes, without touching instances.
+    pub async fn readiness(&self) -> std::result::Result<(), String> {
+        if !self.binary.is_file() {
+            return Err(format!(
+                "pg_ctl binary not found: {}",
+                self.binary.display()
+            ));
+        }
+
+        if !self.data.is_dir() {
+            return Err(format!("data dir not found: {}", self.data.display()));
+        }
+
+        let probe = self.data.join(".quickpg-ready");
+        tokio::fs::write(&probe, b"")
+            .await
+            .map_err(|err| format!("data dir not writable: {}", err))?;
+        let _ = tokio::fs::remove_file(&probe).await;
+
+        Ok(())
+    }
+
+    pub fn exists(&self, id: &str) -> bool {
+        self.data.join(id).is_dir()
+    }
+
+    pub async fn start(&self, id: &str) -> Result<()> {
+        self.launch(id, "start").await
+    }
+
+    /// Restarts a running instance, picking up config changes that require a restart.
+    pub async fn restart(&self, id: &str) -> Result<()> {
+        self.launch(id, "restart").await
+    }
+
+    /// Runs a `pg_ctl` action that (re)starts the postmaster, then applies resource limits to it.
+    async fn launch(&self, id: &str, action: &str) -> Result<()> {
+        let log_offset = self.log_len(id).await;
+        let result = self.launch_and_wait(id, action).await;
+
+        // pg_ctl only says to examine the log, so find out why the server exited from it
+        if let Err(Error::CliError(_) | Error::StartTimeout(_, _)) = &result {
+            let cause = self.log_since(id, log_offset).await;
+            if let Some(cause) = cause.as_deref().and_then(last_fatal) {
+                return Err(Error::StartFailed(id.to_string(), cause.to_string()));
+            }
+        }
+        result
+    }
+
+    async fn launch_and_wait(&self, id: &str, action: &str) -> Result<()> {
+        // Postgres only logs that it couldn't create the socket, so check up front
+        let port = self.status(id).await?.port;
+        let socket = self.sockets.join(format!(".s.PGSQL.{}", port));
+        if socket.as_os_str().len() > MAX_SOCKET_PATH {
+            return Err(Error::SocketPathTooLong(socket));
+        }
+
+        let output = self
+            .run(self.command(self.instance_binary(id).await?).args([
+                "--pgdata",
+                &join_str(&self.data, id),
+                "--log",
+                &join_str(&self.logs, &*format!("{}.log", id)),
+                "--options",
+                &format!("-k{}", self.sockets.display()),
+                action,
+            ]))
+            .await?;
+
+        PgCtl::check_output(&output)?;
+
+        let status = self.status(id).await?;
+        if let (Some(pid), Some(limits)) = (status.pid, status.limits) {
+            if let Err(err) = cgroup::apply(id, pid, &limits).await {
+                tracing::warn!("resource limits not applied to {}: {}", id, err);
+            }
+        }
+
+        self.wait_ready(id, READY_TIMEOUT).await?;
+
+        let path = self.data.join(id).join("quickpg.json");
+        let mut meta = Metadata::from_file(&path).await?;
+        meta.last_started_at = Some(now());
+        meta.to_file(&path).await?;
+
+        Ok(())
+    }
+
+    /// Polls until the instance accepts connections, since a pid file is written before crash
+    /// recovery finishes. Goes through the Unix socket, which every instance listens on.
+    pub async fn wait_ready(&self, id: &str, timeout: Duration) -> Result<()> {
+        let status = self.status(id).await?;
+        let deadline = tokio::time::Instant::now() + timeout;
+        let mut config = self.socket_config(status.port, "postgres", &status.user);
+
+        loop {
+            let attempt = tokio::time::timeout_at(deadline, self.tls.connect(&mut config)).await;
+
+            match attempt {
+                Ok(Ok(_)) => return Ok(()),
+                Ok(Err(err)) => tracing::debug!("{} is not ready yet: {}", id, err),
+                Err(_) => return Err(Error::StartTimeout(id.to_string(), timeout)),
+            }
+
+            // No point waiting out the timeout for a server that already exited
+            if !self.is_running(id) {
+                return Err(Error::CliError(format!("{} exited during startup", id)));
+            }
+
+            if tokio::time::Instant::now() + READY_POLL_INTERVAL >= deadline {
+                return Err(Error::StartTimeout(id.to_string(), timeout));
+            }
+            tokio::time::sleep(READY_POLL_INTERVAL).await;
+        }
+    }
+
+    /// Current size of the instance's log, used to scan only what a subsequent start writes.
+    pub async fn log_len(&self, id: &str) -> u64 {
+        tokio::fs::metadata(self.logs.join(format!("{}.log", id)))
+            .await
+            .map(|meta| meta.len())
+            .unwrap_or(0)
+    }
+
+    /// Reads the last `lines` lines of the instance's log, scanning backwards from the end in chunks.
+    ///
+    /// Returns `None` when the instance has no log.
+    pub async fn log_tail(&self, id: &str, lines: usize) -> Result<Option<String>> {
+        let mut file = match tokio::fs::File::open(self.logs.join(format!("{}.log", id))).await {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+
+        let len = file.metadata().await?.len();
+        let mut start = len;
+        let mut tail: Vec<u8> = vec![];
+
+        // A trailing newline terminates the last line rather than starting a new one
+        while start > 0 && tail.iter().filter(|&&b| b == b'\n').count() <= lines {
+            let chunk_start = start.saturating_sub(LOG_CHUNK_BYTES);
+            let mut chunk = vec![0; (start - chunk_start) as usize];
+            file.seek(io::SeekFrom::Start(chunk_start)).await?;
+            file.read_exact(&mut chunk).await?;
+
+            chunk.extend_from_slice(&tail);
+            tail = chunk;
+            start = chunk_start;
+        }
+
+        let text = String::from_utf8_lossy(&tail);
+        let all: Vec<&str> = text.lines().collect();
+        let skip = all.len().saturating_sub(lines);
+        Ok(Some(join_lines(&all[skip..])))
+    }
+
+    /// Looks for crash recovery messages written to the log after `offset`.
+    pub async fn recovery(&self, id: &str, offset: u64) -> Result<Recovery> {
+        let log = tokio::fs::read(self.logs.join(format!("{}.log", id))).await?;
+        let start = if offset as usize <= log.len() {
+            offset as usize
+        } else {
+            0
+        };
+        let log = String::from_utf8_lossy(&log[start..]);
+
+        let recovered = log.contains("automatic recovery in progress");
+
+        // Postgres 14+ reports "redo done at ... elapsed: 0.12 s"
+        let duration_ms = log
+            .lines()
+            .filter(|line| line.contains("redo done at"))
+            .find_map(|line| line.rsplit_once("elapsed: "))
+            .and_then(|(_, elapsed)| elapsed.trim().trim_end_matches(" s").parse::<f64>().ok())
+            .map(|seconds| (seconds * 1000.0).round() as u64);
+
+        Ok(Recovery {
+            recovered,
+            duration_ms: if recovered { duration_ms } else { None },
+        })
+    }
+
+    pub fn is_running(&self, id: &str) -> bool {
+        let pidfile = self.data.join(id).join("postmaster.pid");
+        match std::fs::read_to_string(&pidfile) {
+            Ok(content) => parse_pid(&content).is_none_or(is_postmaster),
+            Err(_) => false,
+        }
+    }
+
+    pub async fn status(&self, id: &str) -> Result<Status> {
+        let data = self.data.join(id);
+        if !data.is_dir() {
+            return Err(Error::DataDirNotFound(data));
+        }
+
+        let mut meta = Metadata::from_file(&data.join("quickpg.json")).await?;
+        meta.user.get_or_insert_with(|| self.user.clone());
+        let data_owner = tokio::fs::metadata(&data).await.ok().map(|dir| Ownership {
+            uid: dir.uid(),
+            gid: dir.gid(),
+        });
+
+        let server_version = tokio::fs::read_to_string(data.join("PG_VERSION"))
+            .await
+            .ok()
+            .map(|version| version.trim().to_string());
+        // Removed by Postgres on promotion
+        let role = if data.join("standby.signal").is_file() {
+            ReplicationRole::Standby
+        } else {
+            ReplicationRole::Primary
+        };
+
+        let pidfile = data.join("postmaster.pid");
+        if !pidfile.is_file() {
+            return Ok(Status {
+                server_version,
+                role,
+                ..Status::stopped(id, meta, data_owner, self.sockets.clone())
+            });
+        }
+
+        let content = tokio::fs::read_to_string(&pidfile).await?;
+
+        if let Some(pid) = parse_pid(&content) {
+            // A postmaster that crashed leaves its pid file behind, which would block starts
+            if !is_postmaster(pid) {
+                tracing::warn!("removing stale pid file of {}, {} is gone", id, pid);
+                if let Err(err) = tokio::fs::remove_file(&pidfile).await {
+                    tracing::warn!("failed to remove stale pid file of {}: {}", id, err);
+                }
+                return Ok(Status {
+                    server_version,
+                    role,
+                    ..Status::stopped(id, meta, data_owner, self.sockets.clone())
+                });
+            }
+
+            let usage = match meta.limits {
+                Some(_) => cgroup::usage(id).await,
+                None => None,
+            };
+            return Ok(Status {
+                server_version,
+                role,
+                started_at: parse_start_time(&content),
+                ..Status::running(id, meta, pid, usage, data_owner, self.sockets.clone())
+            });
+        }
+
+        Err(Error::InvalidPidFile(pidfile))
+    }
+
+    pub async fn stop(&self, id: &str, mode: StopMode, wait: bool) -> Result<()> {
+        let binary = self.instance_binary(id).await?;
+        let data = join_str(&self.data, id);
+        let mut args = vec!["--pgdata", &data, "--mode", mode.as_str()];
+        if !wait {
+            args.push("--no-wait");
+        }
+        args.push("stop");
+
+        let output = match self.run(self.command(&binary).args(args)).await {
+            Ok(output) => output,
+            Err(Error::Timeout(_, timeout)) => {
+                // Smart and fast shutdowns can wedge, immediate shutdown doesn't wait on anything
+                tracing::warn!(
+                    "stopping {} timed out after {:?}, stopping immediately",
+                    id,
+                    timeout
+                );
+                self.run(self.command(&binary).args([
+                    "--pgdata",
+                    &data,
+                    "--mode",
+                    "immediate",
+                    "stop",
+                ]))
+                .await?
+            }
+            Err(err) => return Err(err),
+        };
+
+        PgCtl::check_output(&output)
+    }
+
+    /// Stops an unresponsive instance with SIGQUIT, which skips the shutdown checkpoint, then
+    /// SIGKILL if it's still around. Returns whether there was a postmaster to kill.
+    pub async fn kill(&self, id: &str) -> Result<bool> {
+        let pidfile = self.data.join(id).join("postmaster.pid");
+        let pid = match self.status(id).await?.pid {
+            Some(pid) => pid,
+            None => return Ok(false),
+        };
+
+        signal(pid, libc::SIGQUIT)?;
+        let deadline = tokio::time::Instant::now() + KILL_GRACE;
+        while is_alive(pid) && tokio::time::Instant::now() < deadline {
+            tokio::time::sleep(READY_POLL_INTERVAL).await;
+        }
+        if is_alive(pid) {
+            tracing::warn!("{} ignored SIGQUIT, sending SIGKILL", id);
+            signal(pid, libc::SIGKILL)?;
+        }
+
+        match tokio::fs::remove_file(&pidfile).await {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(true),
+        }
+    }
+
+    /// Sends SIGHUP so the instance re-reads `postgresql.conf` without dropping connections.
+    pub async fn reload(&self, id: &str) -> Result<()> {
+        let output = self
+            .run(self.command(self.instance_binary(id).await?).args([
+                "--pgdata",
+                &join_str(&self.data, id),
+                "reload",
+            ]))
+            .await?;
+
+        PgCtl::check_output(&output)
+    }
+
+    /// Turns a running standby into a read-write primary, returning once it accepts writes.
+    pub async fn promote(&self, id: &str) -> Result<()> {
+        let status = self.status(id).await?;
+        let client = self.connect(status.port, "postgres", &status.user).await?;
+        let in_recovery: bool = client
+            .query_one("SELECT pg_is_in_recovery()", &[])
+            .await?
+            .get(0);
+        if !in_recovery {
+            return Err(Error::NotInRecovery(id.to_string()));
+        }
+
+        let output = self
+            .run(self.command(self.instance_binary(id).await?).args([
+                "--pgdata",
+                &join_str(&self.data, id),
+                "--wait",
+                "promote",
+            ]))
+            .await?;
+
+        PgCtl::check_output(&output)
+    }
+
+    /// Copies the stopped `template` into `target` and starts it. Anything left behind by a
+    /// failure is removed.
+    pub async fn fork<'a>(
+        &self,
+        template: &str,
+        target: &str,
+        dbname: &str,
+        conf: &PostgresqlConf<'a>,
+        mode: ForkMode,
+    ) -> Result<()> {
+        self.check_free(target)?;
+        let result = self.build_fork(template, target, dbname, conf, mode).await;
+        if result.is_err() {
+            self.discard(target).await;
+        }
+        result
+    }
+
+    async fn build_fork<'a>(
+        &self,
+        template: &str,
+        target: &str,
+        dbname: &str,
+        conf: &PostgresqlConf<'a>,
+        mode: ForkMode,
+    ) -> Result<()> {
+        let template_data = self.data.join(template);
+        if !template_data.is_dir() {
+            return Err(Error::DataDirNotFound(template_data));
+        }
+        let template_meta = Metadata::from_file(&template_data.join("quickpg.json")).await?;
+        self.check_clean_shutdown(template).await?;
+
+        copy::copy_pgdata(
+            template_data.clone(),
+            self.data.join(target),
+            self.copy_strategy,
+            mode,
+            self.copy_concurrency,
+            self.dir_mode,
+        )
+        .await?;
+        if self.verify_copies {
+            if let Some(path) = copy::verify_copy(template_data, self.data.join(target)).await? {
+                return Err(Error::CopyMismatch(path));
+            }
+        }
+        if let Some(owner) = self.owner {
+            copy::chown_recursive(self.data.join(target), owner.uid, owner.gid).await?;
+        }
+        if self.sync_copies {
+            copy::sync_recursive(self.data.join(target)).await?;
+        }
+
+        conf.to_config()
+            .to_file(&self.data.join(target).join("postgresql.conf"))
+            .await?;
+
+        let meta = Metadata {
+            dbname: dbname.to_string(),
+            user: template_meta.user.or_else(|| Some(self.user.clone())),
+            owner: template_meta.owner,
+            port: conf.port,
+            limits: template_meta.limits,
+            expires_at: None,
+            version: template_meta.version,
+            labels: template_meta.labels,
+            created_at: Some(now()),
+            last_started_at: None,
+            primary: None,
+        };
+        meta.to_file(&self.data.join(target).join("quickpg.json"))
+            .await?;
+
+        self.start_on_free_port(target, conf).await?;
+        Ok(())
+    }
+
+    /// Copies a running template with `pg_basebackup`, which needs the template to allow
+    /// replication connections (`wal_level = replica` and `max_wal_senders` of at least 2).
+    /// Anything left behind by a failure is removed.
+    pub async fn fork_online<'a>(
+        &self,
+        template: &Status,
+        target: &str,
+        conf: &PostgresqlConf<'a>,
+    ) -> Result<()> {
+        self.check_free(target)?;
+        self.check_replication(template).await?;
+        let result = self.build_online_fork(template, target, conf).await;
+        if result.is_err() {
+            self.discard(target).await;
+        }
+        result
+    }
+
+    /// Fails before `pg_basebackup` runs when `status` doesn't allow the two replication
+    /// connections it opens with `--wal-method=stream`.
+    async fn check_replication(&self, status: &Status) -> Result<()> {
+        let client = self.connect(status.port, "postgres", &status.user).await?;
+        let row = client
+            .query_one(
+                "SELECT current_setting('wal_level'), current_setting('max_wal_senders')::bigint",
+                &[],
+            )
+            .await?;
+        let wal_level: String = row.get(0);
+        let senders: i64 = row.get(1);
+        if wal_level == "minimal" || senders < 2 {
+            return Err(Error::ReplicationDisabled(
+                status.id.clone(),
+                wal_level,
+                senders,
+            ));
+        }
+        Ok(())
+    }
+
+    async fn build_online_fork<'a>(
+        &self,
+        template: &Status,
+        target: &str,
+        conf: &PostgresqlConf<'a>,
+    ) -> Result<()> {
+        let binary = self.versioned_binary(template.version.as_deref())?;
+        let host = self.socket_host();
+        let template_port = template.port.to_string();
+
+        let output = self
+            .run(self.command(binary.with_file_name("pg_basebackup")).args([
+                "--host",
+                &host,
+                "--port",
+                &template_port,
+                "--username",
+                &template.user,
+                "--pgdata",
+                &join_str(&self.data, target),
+                "--wal-method=stream",
+                "--checkpoint=fast",
+                "--no-sync",
+            ]))
+            .await?;
+
+        PgCtl::check_output(&output)?;
+
+        conf.to_config()
+            .to_file(&self.data.join(target).join("postgresql.conf"))
+            .await?;
+
+        let meta = Metadata {
+            dbname: template.dbname.clone(),
+            user: Some(template.user.clone()),
+            owner: template.owner.clone(),
+            port: conf.port,
+            limits: template.limits,
+            expires_at: None,
+            version: template.version.clone(),
+            labels: template.labels.clone(),
+            created_at: Some(now()),
+            last_started_at: None,
+            primary: None,
+        };
+        meta.to_file(&self.data.join(target).join("quickpg.json"))
+            .await?;
+
+        self.start_on_free_port(target, conf).await?;
+        Ok(())
+    }
+
+    /// Creates `target` as a streaming standby of the running `primary`, with the same
+    /// requirements as `fork_online`. Anything left behind by a failure is removed.
+    pub async fn replica<'a>(
+        &self,
+        primary: &Status,
+        target: &str,
+        conf: &PostgresqlConf<'a>,
+    ) -> Result<()> {
+        self.check_free(target)?;
+        self.check_replication(primary).await?;
+        let result = self.build_replica(primary, target, conf).await;
+        if result.is_err() {
+            self.discard(target).await;
+        }
+        result
+    }
+
+    async fn build_replica<'a>(
+        &self,
+        primary: &Status,
+        target: &str,
+        conf: &PostgresqlConf<'a>,
+    ) -> Result<()> {
+        let binary = self.versioned_binary(primary.version.as_deref())?;
+        let host = self.socket_host();
+        let primary_port = primary.port.to_string();
+
+        // --write-recovery-conf writes standby.signal and primary_conninfo to postgresql.auto.conf,
+        // which survives the postgresql.conf written below
+        let output = self
+            .run(self.command(binary.with_file_name("pg_basebackup")).args([
+                "--host",
+                &host,
+                "--port",
+                &primary_port,
+                "--username",
+                &primary.user,
+                "--pgdata",
+                &join_str(&self.data, target),
+                "--wal-method=stream",
+                "--checkpoint=fast",
+                "--write-recovery-conf",
+                "--no-sync",
+            ]))
+            .await?;
+
+        PgCtl::check_output(&output)?;
+
+        conf.to_config()
+            .to_file(&self.data.join(target).join("postgresql.conf"))
+            .await?;
+
+        let meta = Metadata {
+            dbname: primary.dbname.clone(),
+            user: Some(primary.user.clone()),
+            owner: primary.owner.clone(),
+            port: conf.port,
+            limits: primary.limits,
+            expires_at: None,
+            version: primary.version.clone(),
+            labels: primary.labels.clone(),
+            created_at: Some(now()),
+            last_started_at: None,
+            primary: Some(primary.id.clone()),
+        };
+        meta.to_file(&self.data.join(target).join("quickpg.json"))
+            .await?;
+
+        self.start_on_free_port(target, conf).await?;
+        Ok(())
+    }
+
+    /// Creates `target` with `init` and restores a `pg_dump` of the running template into it.
+    /// Anything left behind by a failure is removed.
+    pub async fn fork_logical<'a>(
+        &self,
+        template: &Status,
+        target: &str,
+        conf: &PostgresqlConf<'a>,
+        schema_only: bool,
+    ) -> Result<()> {
+        self.check_free(target)?;
+        let result = self
+            .build_logical_fork(template, target, conf, schema_only)
+            .await;
+        if result.is_err() {
+            self.discard(target).await;
+        }
+        result
+    }
+
+    async fn build_logical_fork<'a>(
+        &self,
+        template: &Status,
+        target: &str,
+        conf: &PostgresqlConf<'a>,
+        schema_only: bool,
+    ) -> Result<()> {
+        let options = InitOptions {
+            limits: template.limits,
+            expires_at: None,
+            version: template.version.clone(),
+            extensions: vec![],
+            init_sql: None,
+            owner: None,
+            labels: template.labels.clone(),
+            template_database: None,
+            initdb: InitdbOptions::default(),
+        };
+        let target_port = self.init(target, &template.dbname, conf, &options).await?;
+        let binary = self.versioned_binary(template.version.as_deref())?;
+
+        let host = self.socket_host();
+        let template_port = template.port.to_string();
+        let mut dump_args = vec![
+            "--host",
+            &host,
+            "--port",
+            &template_port,
+            "--username",
+            &template.user,
+            "--no-owner",
+        ];
+        if schema_only {
+            dump_args.push("--schema-only");
+        }
+        dump_args.push(&template.dbname);
+
+        let mut dump = self
+            .command(binary.with_file_name("pg_dump"))
+            .args(dump_args)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+        let dump_stdout: Stdio = dump.stdout.take().unwrap().try_into()?;
+
+        let target_port = target_port.to_string();
+        let mut restore = self.command(binary.with_file_name("psql"));
+        restore
+            .args([
+                "--host",
+                &host,
+                "--port",
+                &target_port,
+                "--username",
+                &self.user,
+                "--dbname",
+                &template.dbname,
+                "--quiet",
+                "--set",
+                "ON_ERROR_STOP=1",
+            ])
+            .stdin(dump_stdout)
+            .kill_on_drop(true);
+
+        // Like `run`, dropping both on a timeout kills them
+        let (dump_output, restore_output) =
+            match tokio::time::timeout(self.command_timeout, async {
+                tokio::try_join!(dump.wait_with_output(), restore.output())
+            })
+            .await
+            {
+                Ok(outputs) => outputs?,
+                Err(_) => {
+                    return Err(Error::Timeout(
+                        "pg_dump | psql".to_string(),
+                        self.command_timeout,
+                    ))
+                }
+            };
+
+        PgCtl::check_output(&dump_output)?;
+        PgCtl::check_output(&restore_output)
+    }
+
+    /// Starts dumping the instance's database. Failing to connect is reported here rather than
+    /// as an empty dump, since `pg_dump` writes nothing before it gives up.
+    pub async fn dump(&self, id: &str, format: DumpFormat) -> Result<Dump> {
+        let status = self.status(id).await?;
+        let binary = self.instance_binary(id).await?;
+
+        let host = self.socket_host();
+        let port = status.port.to_string();
+        let mut child = self
+            .command(binary.with_file_name("pg_dump"))
+            .args([
+                "--host",
+                &host,
+                "--port",
+                &port,
+                "--username",
+                &status.user,
+                "--format",
+                format.as_str(),
+                &status.dbname,
+            ])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+
+        let mut dump = Dump {
+            head: None,
+            stdout: child.stdout.take(),
+            child: Some(child),
+        };
+        dump.head = dump.next_chunk().await?;
+        Ok(dump)
+    }
+
+    /// Streams a `tar` of a stopped instance's data dir, with what a physical fork would copy
+    /// plus its config, so it can be unpacked and started on another machine.
+    pub async fn archive(&self, id: &str, format: ArchiveFormat) -> Result<Dump> {
+        let data = self.data.join(id);
+        if !data.is_dir() {
+            return Err(Error::DataDirNotFound(data));
+        }
+        self.check_clean_shutdown(id).await?;
+
+        let mut command = self.command("tar");
+        command
+            .arg("--create")
+            .arg("--file=-")
+            .arg("--directory")
+            .arg(&data);
+        if format == ArchiveFormat::Zst {
+            command.arg("--zstd");
+        }
+        let mut child = command
+            .args(copy::tar_entries(&data))
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+
+        let mut archive = Dump {
+            head: None,
+            stdout: child.stdout.take(),
+            child: Some(child),
+        };
+        archive.head = archive.next_chunk().await?;
+        Ok(archive)
+    }
+
+    /// Starts loading a dump in `format` into the instance's database, in a single transaction
+    /// so a failed restore leaves nothing behind. Objects belong to the owner when there is one.
+    pub async fn restore(&self, id: &str, format: DumpFormat) -> Result<Restore> {
+        let status = self.status(id).await?;
+        let binary = self.instance_binary(id).await?;
+
+        let host = self.socket_host();
+        let port = status.port.to_string();
+        let mut args = vec![
+            "--host",
+            &host,
+            "--port",
+            &port,
+            "--username",
+            &status.user,
+            "--dbname",
+            &status.dbname,
+            "--single-transaction",
+        ];
+        let role = status
+            .owner
+            .as_ref()
+            .map(|owner| format!("--role={}", owner));
+        let program = match format {
+            DumpFormat::Plain => {
+                args.extend(["--quiet", "--set", "ON_ERROR_STOP=1"]);
+                "psql"
+            }
+            DumpFormat::Custom => {
+                args.extend(["--exit-on-error", "--no-owner"]);
+                if let Some(role) = &role {
+                    args.push(role);
+                }
+                "pg_restore"
+            }
+        };
+
+        let mut restore = Restore::spawn(
+            self.command(binary.with_file_name(program)).args(args),
+            Error::Restore,
+        )?;
+
+        if let (DumpFormat::Plain, Some(owner)) = (format, &status.owner) {
+            let set_role = format!("SET ROLE {};\n", quote_identifier(owner));
+            restore.stdin.write_all(set_role.as_bytes()).await?;
+        }
+
+        Ok(restore)
+    }
+
+    /// Starts unpacking an archive of a data dir into a new instance's data dir, finished by
+    /// `import` once all of it is written. Call `discard` when either fails.
+    pub async fn extract(&self, id: &str, format: ArchiveFormat) -> Result<Restore> {
+        let data = self.data.join(id);
+        if data.exists() {
+            return Err(Error::DataDirExists(data));
+        }
+        self.create_owned_dir(&data).await?;
+
+        let mut command = self.command("tar");
+        command
+            .arg("--extract")
+            .arg("--file=-")
+            .arg("--directory")
+            .arg(&data)
+            .arg("--no-same-owner");
+        if format == ArchiveFormat::Zst {
+            command.arg("--zstd");
+        }
+        Restore::spawn(&mut command, Error::Import)
+    }
+
+    /// Turns an extracted archive into an instance on `conf.port`, using an installed binary of
+    /// the data dir's major version. Returns the port it ended up on.
+    pub async fn import<'a>(&self, id: &str, conf: &PostgresqlConf<'a>) -> Result<u32> {
+        let data = self.data.join(id);
+        let pg_version = tokio::fs::read_to_string(data.join("PG_VERSION"))
+            .await
+            .map_err(|_| Error::Import("the archive has no PG_VERSION".to_string()))?;
+        let pg_version = pg_version.trim();
+        let meta = Metadata::from_file(&data.join("quickpg.json"))
+            .await
+            .map_err(|_| Error::Import("the archive has no quickpg.json".to_string()))?;
+
+        let mut installations = self.installations().await;
+        // The version it was archived with first, then any other with the same major version
+        installations.sort_by_key(|installation| installation.version != meta.version);
+        let installation = installations
+            .into_iter()
+            .find(|installation| {
+                installation
+                    .server_version
+                    .as_deref()
+                    .and_then(major_version)
+                    == Some(pg_version)
+            })
+            .ok_or_else(|| {
+                Error::Import(format!("no installed pg_ctl for Postgres {}", pg_version))
+            })?;
+
+        // Left behind if the archive was taken from a running instance
+        let pidfile = data.join("postmaster.pid");
+        if pidfile.is_file() {
+            tokio::fs::remove_file(pidfile).await?;
+        }
+        if let Some(owner) = self.owner {
+            copy::chown_recursive(data.clone(), owner.uid, owner.gid).await?;
+        }
+
+        conf.to_config()
+            .to_file(&data.join("postgresql.conf"))
+            .await?;
+
+        let meta = Metadata {
+            port: conf.port,
+            version: installation.version,
+            expires_at: None,
+            created_at: Some(now()),
+            last_started_at: None,
+            primary: None,
+            ..meta
+        };
+        meta.to_file(&data.join("quickpg.json")).await?;
+
+        self.start_on_free_port(id, conf).await
+    }
+
+    pub async fn write_file(&self, id: &str, relative: &Path, content: &[u8]) -> Result<()> {
+        let data = self.data.join(id);
+        if !data.is_dir() {
+            return Err(Error::DataDirNotFound(data));
+        }
+
+        let is_contained = relative.components().count() > 0
+            && relative
+                .components()
+                .all(|component| matches!(component, Component::Normal(_)));
+        if !is_contained {
+            return Err(Error::InvalidPath(relative.to_path_buf()));
+        }
+
+        // Symlinks inside the data dir (e.g. pg_tblspc) could point elsewhere, so none are followed
+        let mut dir = data.clone();
+        for component in relative.parent().unwrap().components() {
+            dir.push(component);
+            match tokio::fs::symlink_metadata(&dir).await {
+                Ok(metadata) if metadata.is_dir() => (),
+                Ok(_) => return Err(Error::InvalidPath(relative.to_path_buf())),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                    self.create_owned_dir(&dir).await?
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+
+        let path = data.join(relative);
+        let mut file = match tokio::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(self.dir_mode & 0o666)
+            .custom_flags(libc::O_NOFOLLOW)
+            .open(&path)
+            .await
+        {
+            Ok(file) => file,
+            Err(err) if err.raw_os_error() == Some(libc::ELOOP) => {
+                return Err(Error::InvalidPath(relative.to_path_buf()))
+            }
+            Err(err) => return Err(err.into()),
+        };
+        if let Some(owner) = self.owner {
+            std::os::unix::fs::lchown(&path, Some(owner.uid), Some(owner.gid))?;
+        }
+        file.write_all(content).await?;
+        file.flush().await?;
+
+        Ok(())
+    }
+
+    /// Exchanges the data dirs and logs of two stopped instances, so each id refers to the other's data.
+    pub async fn swap(&self, a: &str, b: &str) -> Result<()> {
+        for id in [a, b] {
+            let data = self.data.join(id);
+            if !data.is_dir() {
+                return Err(Error::DataDirNotFound(data));
+            }
+        }
+
+        exchange(&self.data.join(a), &self.data.join(b))?;
+
+        let log_a = self.logs.join(format!("{}.log", a));
+        let log_b = self.logs.join(format!("{}.log", b));
+        match (log_a.is_file(), log_b.is_file()) {
+            (true, true) => exchange(&log_a, &log_b)?,
+            (true, false) => tokio::fs::rename(&log_a, &log_b).await?,
+            (false, true) => tokio::fs::rename(&log_b, &log_a).await?,
+            (false, false) => (),
+        }
+
+        Ok(())
+    }
+
+    /// Moves a stopped instance's data dir and log to a new id, its dbname and port stay the same.
+    pub async fn rename(&self, from: &str, to: &str) -> Result<()> {
+        let source = self.data.join(from);
+        if !source.is_dir() {
+            return Err(Error::DataDirNotFound(source));
+        }
+        let target = self.data.join(to);
+        if target.exists() {
+            return Err(Error::DataDirExists(target));
+        }
+
+        tokio::fs::rename(&source, &target).await?;
+
+        let log = self.logs.join(format!("{}.log", from));
+        if log.is_file() {
+            tokio::fs::rename(&log, self.logs.join(format!("{}.log", to))).await?;
+        }
+
+        // Limits are applied to a cgroup named after the id on the next start
+        if let Err(err) = cgroup::remove(from).await {
+            tracing::warn!("cgroup for {} not removed: {}", from, err);
+        }
+
+        Ok(())
+    }
+
+    pub async fn destroy(&self, id: &str) -> Result<()> {
+        let data = self.data.join(id);
+        if !data.is_dir() {
+            return Err(Error::DataDirNotFound(data));
+        }
+        self.warn_about_replicas(id).await;
+
+        tokio::fs::remove_dir_all(data).await?;
+
+        if let Err(err) = cgroup::remove(id).await {
+            tracing::warn!("cgroup for {} not removed: {}", id, err);
+        }
+
+        self.remove_log(id).await
+    }
+
+    /// Standbys of a destroyed primary keep running, but can only retry connecting to it.
+    async fn warn_about_replicas(&self, id: &str) {
+        let statuses = match self.list().await {
+            Ok(statuses) => statuses,
+            Err(_) => return,
+        };
+        for status in statuses {
+            if status.role == ReplicationRole::Standby && status.primary.as_deref() == Some(id) {
+                tracing::warn!("destroying {}, the primary of replica {}", id, status.id);
+            }
+        }
+    }
+
+    async fn remove_log(&self, id: &str) -> Result<()> {
+        let log = self.logs.join(format!("{}.log", id));
+        if log.is_file() {
+            tokio::fs::remove_file(log).await?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn list(&self) -> Result<Vec<Status>> {
+        let mut dir = match tokio::fs::read_dir(&self.data).await {
+            Ok(dir) => dir,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => return Err(err.into()),
+        };
+        let mut results = vec![];
+
+        while let Some(entry) = dir.next_entry().await? {
+            // Metadata is written once initdb or the copy completes, skip instances still being built
+            if !entry.path().join("quickpg.json").is_file() {
+                continue;
+            }
+
+            // One instance with corrupt metadata shouldn't hide all the others
+            let id = entry.file_name().to_string_lossy().into_owned();
+            match self.status(&id).await {
+                Ok(status) => results.push(status),
+                Err(err) => tracing::warn!("skipping {} while listing: {}", id, err),
+            }
+        }
+
+        Ok(results)
+    }
+
+    /// A copy of a template that crashed would need crash recovery, or fail to start at all.
+    async fn check_clean_shutdown(&self, id: &str) -> Result<()> {
+        match self.cluster_state(id).await {
+            Ok(state) if is_shut_down(&state) => Ok(()),
+            Ok(state) => Err(Error::TemplateNotCleanlyStopped(id.to_string(), state)),
+            Err(Error::Io(err)) if err.kind() == io::ErrorKind::NotFound => {
+                tracing::warn!("pg_controldata not found, forking {} unchecked", id);
+                Ok(())
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Reads the "Database cluster state" reported by `pg_controldata`.
+    pub async fn cluster_state(&self, id: &str) -> Result<String> {
+        let binary = self.instance_binary(id).await?;
+        let output = self
+            .run(
+                self.command(binary.with_file_name("pg_controldata"))
+                    .arg(join_str(&self.data, id)),
+            )
+            .await?;
+
+        PgCtl::check_output(&output)?;
+
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        stdout
+            .lines()
+            .find_map(|line| line.strip_prefix("Database cluster state:"))
+            .map(|state| state.trim().to_string())
+            .ok_or_else(|| Error::CliError("pg_controldata: missing cluster state".to_string()))
+    }
+
+    /// Lists the reasons an instance can't safely be used as a fork template, empty if it can.
+    pub async fn validate_template(&self, id: &str) -> Result<Vec<String>> {
+        let data = self.data.join(id);
+        if !data.is_dir() {
+            return Err(Error::DataDirNotFound(data));
+        }
+
+        let mut reasons = vec![];
+
+        if let Err(err) = Metadata::from_file(&data.join("quickpg.json")).await {
+            reasons.push(format!("quickpg.json is missing or invalid: {}", err));
+        }
+
+        if data.join("postmaster.pid").is_file() {
+            reasons.push(
+                "postmaster.pid exists, the instance is running or was not stopped cleanly"
+                    .to_string(),
+            );
+        }
+
+        if let Err(err) = copy::check_tablespaces(&data).await {
+            reasons.push(err.to_string());
+        }
+
+        match self.cluster_state(id).await {
+            Ok(state) if is_shut_down(&state) => (),
+            Ok(state) => reasons.push(format!("cluster state is \"{}\", not \"shut down\"", state)),
+            Err(err) => reasons.push(format!("could not read cluster state: {}", err)),
+        }
+
+        Ok(reasons)
+    }
+
+    /// What a physical fork of `template` would copy, and how, without creating anything.
+    pub async fn fork_plan(&self, template: &str, mode: ForkMode) -> Result<CopyPlan> {
+        let template_data = self.data.join(template);
+        if !template_data.is_dir() {
+            return Err(Error::DataDirNotFound(template_data));
+        }
+
+        Ok(copy::plan_pgdata(template_data, self.copy_strategy, mode).await?)
+    }
+
+    pub async fn disk_usage(&self, id: &str) -> Result<u64> {
+        let data = self.data.join(id);
+        if !data.is_dir() {
+            return Err(Error::DataDirNotFound(data));
+        }
+
+        Ok(copy::dir_size(data).await?)
+    }
+
+    /// Flushes dirty buffers so the clean shutdown that follows has less to write, and forks
+    /// of the stopped instance have no WAL to replay. Does nothing when it isn't running.
+    pub async fn checkpoint(&self, id: &str) -> Result<()> {
+        let status = self.status(id).await?;
+        if !status.is_running() {
+            return Ok(());
+        }
+
+        let client = self
+            .connect(status.port, &status.dbname, &status.user)
+            .await?;
+        client.batch_execute("CHECKPOINT").await?;
+        Ok(())
+    }
+
+    pub async fn connections(&self, status: &Status) -> Result<i64> {
+        let client = self
+            .connect(status.port, &status.dbname, &status.user)
+            .await?;
+        PgCtl::bound_statements(&client).await?;
+
+        let row = client
+            .query_one(
+                "SELECT count(*) FROM pg_stat_activity WHERE datname = $1 AND pid <> pg_backend_pid()",
+                &[&status.dbname],
+            )
+            .await?;
+
+        Ok(row.get(0))
+    }
+
+    pub async fn tables(&self, id: &str, dbname: Option<&str>) -> Result<Vec<TableStats>> {
+        let status = self.status(id).await?;
+        let client = self
+            .connect(status.port, dbname.unwrap_or(&status.dbname), &status.user)
+            .await?;
+        PgCtl::bound_statements(&client).await?;
+
+        let rows = client
+            .query(
+                "SELECT schemaname::text, relname::text, n_live_tup, pg_total_relation_size(relid)
+                 FROM pg_stat_user_tables
+                 ORDER BY schemaname, relname
+                 LIMIT $1",
+                &[&MAX_INTROSPECTION_ROWS],
+            )
+            .await?;
+
+        Ok(rows
+            .iter()
+            .map(|row| TableStats {
+                schema: row.get(0),
+                name: row.get(1),
+                estimated_rows: row.get(2),
+                size_bytes: row.get(3),
+            })
+            .collect())
+    }
+
+    /// Runs `sql` as one batch in the instance's database, as its owner when it has one.
+    pub async fn query(&self, id: &str, sql: &str) -> Result<Vec<QueryResult>> {
+        let status = self.status(id).await?;
+        let client = self
+            .connect(status.port, &status.dbname, &status.user)
+            .await?;
+        if let Some(owner) = &status.owner {
+            client
+                .batch_execute(&format!("SET ROLE {}", quote_identifier(owner)))
+                .await?;
+        }
+
+        let messages = client.simple_query(sql).await.map_err(Error::Query)?;
+
+        let mut results = vec![];
+        let mut columns = vec![];
+        let mut rows = vec![];
+        for message in messages {
+            match message {
+                SimpleQueryMessage::Row(row) => {
+                    if columns.is_empty() {
+                        columns = row
+                            .columns()
+                            .iter()
+                            .map(|column| column.name().to_string())
+                            .collect();
+                    }
+                    rows.push(
+                        (0..row.len())
+                            .map(|index| row.get(index).map(str::to_string))
+                            .collect(),
+                    );
+                }
+                SimpleQueryMessage::CommandComplete(rows_affected) => {
+                    results.push(QueryResult {
+                        columns: std::mem::take(&mut columns),
+                        rows: std::mem::take(&mut rows),
+                        rows_affected,
+                    });
+                }
+                _ => (),
+            }
+        }
+
+        Ok(results)
+    }
+
+    pub async fn locks(&self, id: &str) -> Result<(Vec<LockWait>, Vec<Backend>)> {
+        let status = self.status(id).await?;
+        let client = self
+            .connect(status.port, &status.dbname, &status.user)
+            .await?;
+        PgCtl::bound_statements(&client).await?;
+
+        let waiting = client
+            .query(
+                "SELECT l.pid, a.datname::text, l.locktype, l.mode, l.relation::regclass::text,
+                        a.query, pg_blocking_pids(l.pid)
+                 FROM pg_locks l
+                 JOIN pg_stat_activity a ON a.pid = l.pid
+                 WHERE NOT l.granted
+                 ORDER BY l.pid
+                 LIMIT $1",
+                &[&MAX_INTROSPECTION_ROWS],
+            )
+            .await?
+            .iter()
+            .map(|row| LockWait {
+                pid: row.get(0),
+                dbname: row.get(1),
+                locktype: row.get(2),
+                mode: row.get(3),
+                relation: row.get(4),
+                query: row.get(5),
+                blocked_by: row.get(6),
+            })
+            .collect();
+
+        let blocking = client
+            .query(
+                "SELECT a.pid, a.datname::text, a.state, a.query
+                 FROM pg_stat_activity a
+                 WHERE a.pid IN (
+                     SELECT unnest(pg_blocking_pids(l.pid)) FROM pg_locks l WHERE NOT l.granted
+                 )
+                 ORDER BY a.pid
+                 LIMIT $1",
+                &[&MAX_INTROSPECTION_ROWS],
+            )
+            .await?
+            .iter()
+            .map(|row| Backend {
+                pid: row.get(0),
+                dbname: row.get(1),
+                state: row.get(2),
+                query: row.get(3),
+            })
+            .collect();
+
+        Ok((waiting, blocking))
+    }
+
+    pub async fn orphans(&self) -> Result<Orphans> {
+        let ports: Vec<u32> = self.list().await?.iter().map(|s| s.port).collect();
+        let mut orphans = Orphans::default();
+
+        for name in read_dir_names(&self.logs).await? {
+            if let Some(id) = name.strip_suffix(".log") {
+                if !self.exists(id) {
+                    orphans.logs.push(name);
+                }
+            }
+        }
+
+        // Postgres names its sockets `.s.PGSQL.<port>` and `.s.PGSQL.<port>.lock`
+        for name in read_dir_names(&self.sockets).await? {
+            let port = name
+                .strip_prefix(".s.PGSQL.")
+                .map(|rest| rest.trim_end_matches(".lock"))
+                .and_then(|port| port.parse::<u32>().ok());
+
+            if let Some(port) = port {
+                if !ports.contains(&port) {
+                    orphans.sockets.push(name);
+                }
+            }
+        }
+
+        Ok(orphans)
+    }
+
+    pub async fn clean_orphans(&self) -> Result<Orphans> {
+        let orphans = self.orphans().await?;
+
+        for log in &orphans.logs {
+            tokio::fs::remove_file(self.logs.join(log)).await?;
+        }
+        for socket in &orphans.sockets {
+            tokio::fs::remove_file(self.sockets.join(socket)).await?;
+        }
+
+        Ok(orphans)
+    }
+
+    fn check_output(output: &Output) -> Result<()> {
+        if output.status.success() {
+            Ok(())
+        } else {
+            let mut message = match output.status.code() {
+                Some(code) => format!("exited with {}", code),
+                None => "killed by signal".to_string(),
+            };
+
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            if !stderr.trim().is_empty() {
+                message.push_str(&format!(": {}", stderr.trim()));
+            }
+
+            let stdout = String::from_utf8_lossy(&output.stdout);
+            let stdout = stdout.trim();
+            if !stdout.is_empty() {
+                let snippet: String = stdout.chars().take(MAX_STDOUT_SNIPPET).collect();
+                message.push_str(&format!(" (stdout: {})", snippet));
+            }
+
+            Err(Error::CliError(message))
+        }
+    }
+
+    /// Goes over the Unix socket so it works whatever `listen_addresses` is set to.
+    async fn create_database(
+        &self,
+        id: &str,
+        dbname: &str,
+        port: u32,
+        owner: &str,
+        template: Option<&str>,
+    ) -> Result<()> {
+        // A template can't be copied while anything, including this connection, is using it
+        let maintenance_db = match template {
+            Some("postgres") => "template1",
+            _ => "postgres",
+        };
+
+        let client = self
+            .connect_socket(id, port, maintenance_db, &self.user)
+            .await?;
+
+        let mut sql = format!(
+            "CREATE DATABASE {} OWNER {}",
+            quote_identifier(dbname),
+            quote_identifier(owner)
+        );
+
+        if let Some(template) = template {
+            PgCtl::check_template_database(&client, template).await?;
+            sql.push_str(&format!(" TEMPLATE {}", quote_identifier(template)));
+        }
+
+        client.execute(&sql, &[]).await?;
+        Ok(())
+    }
+
+    async fn check_template_database(client: &Client, template: &str) -> Result<()> {
+        let exists = client
+            .query_opt("SELECT 1 FROM pg_database WHERE datname = $1", &[&template])
+            .await?
+            .is_some();
+        if !exists {
+            return Err(Error::TemplateDatabase(
+                template.to_string(),
+                "does not exist".to_string(),
+            ));
+        }
+
+        let row = client
+            .query_one(
+                "SELECT count(*) FROM pg_stat_activity WHERE datname = $1",
+                &[&template],
+            )
+            .await?;
+        let connections: i64 = row.get(0);
+        if connections > 0 {
+            return Err(Error::TemplateDatabase(
+                template.to_string(),
+                format!("has {} open connections", connections),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Creates a login role, hashing its password here so only the SCRAM verifier reaches the
+    /// server and its logs. Roles with a password must then authenticate with it.
+    async fn create_role(&self, id: &str, port: u32, role: &Role) -> Result<()> {
+        let client = self
+            .connect_socket(id, port, "postgres", &self.user)
+            .await?;
+
+        let mut statement = format!("CREATE ROLE {} LOGIN", quote_identifier(&role.name));
+        if let Some(Password(password)) = &role.password {
+            let verifier = postgres_protocol::password::scram_sha_256(password.as_bytes());
+            statement.push_str(&format!(" PASSWORD {}", quote_literal(&verifier)));
+        }
+        client.batch_execute(&statement).await?;
+
+        if role.password.is_some() {
+            self.require_passwords(id).await?;
+        }
+        Ok(())
+    }
+
+    /// Puts `scram-sha-256` in front of the default `trust` rules for every role but quickpg's own.
+    async fn require_passwords(&self, id: &str) -> Result<()> {
+        let path = self.data.join(id).join("pg_hba.conf");
+        let existing = tokio::fs::read_to_string(&path).await?;
+        let user = quote_identifier(&self.user);
+
+        let rules = join_lines(&[
+            &format!("local all {} trust", user),
+            &format!("host all {} 127.0.0.1/32 trust", user),
+            &format!("host all {} ::1/128 trust", user),
+            "local all all scram-sha-256",
+            "host all all 127.0.0.1/32 scram-sha-256",
+            "host all all ::1/128 scram-sha-256",
+        ]);
+        tokio::fs::write(&path, rules + &existing).await?;
+
+        self.reload(id).await
+    }
+
+    async fn bound_statements(client: &Client) -> Result<()> {
+        client
+            .batch_execute(&format!(
+                "SET statement_timeout = {}",
+                QUERY_TIMEOUT.as_millis()
+            ))
+            .await?;
+        Ok(())
+    }
+
+    /// Connects through the Unix socket like every other connection quickpg makes, which works
+    /// whatever `listen_addresses` is.
+    async fn connect(&self, port: u32, dbname: &str, user: &str) -> Result<Client> {
+        let mut config = self.socket_config(port, dbname, user);
+        Ok(self.tls.connect(&mut config).await?)
+    }
+
+    /// `--host` of the client binaries, which take a directory for a Unix socket.
+    fn socket_host(&self) -> String {
+        self.sockets.to_string_lossy().into_owned()
+    }
+
+    fn socket_config(&self, port: u32, dbname: &str, user: &str) -> Config {
+        let mut config = Config::new();
+        config.host_path(&self.sockets);
+        config.port(port as u16);
+        config.dbname(dbname);
+        config.user(user);
+        config.connect_timeout(QUERY_TIMEOUT);
+        config
+    }
+
+    /// Connects through the Unix socket, which is up before TCP during startup and doesn't
+    /// depend on `listen_addresses`, so provisioning a new instance uses it.
+    ///
+    /// Retries with exponential backoff while the server is unreachable or still starting up.
+    async fn connect_socket(
+        &self,
+        id: &str,
+        port: u32,
+        dbname: &str,
+        user: &str,
+    ) -> Result<Client> {
+        let mut config = self.socket_config(port, dbname, user);
+
+        let started = tokio::time::Instant::now();
+        let mut backoff = CONNECT_BACKOFF;
+        for attempt in 1.. {
+            match self.tls.connect(&mut config).await {
+                Ok(client) => return Ok(client),
+                Err(err) if !is_transient(&err) => return Err(err.into()),
+                Err(_) if attempt == CONNECT_ATTEMPTS => break,
+                Err(err) => tracing::debug!("connecting to {} failed, retrying: {}", id, err),
+            }
+
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_CONNECT_BACKOFF);
+        }
+
+        Err(Error::StartTimeout(id.to_string(), started.elapsed()))
+    }
+}
+
+/// Connection failures that go away once the server finishes starting, as opposed to being
+/// rejected, e.g. for a missing database.
+fn is_transient(err: &tokio_postgres::Error) -> bool {
+    match err.as_db_error() {
+        Some(db) => *db.code() == SqlState::CANNOT_CONNECT_NOW,
+        None => true,
+    }
+}
+
+/// Quotes an identifier for interpolation into SQL, since identifiers can't be bound as parameters.
+fn quote_identifier(identifier: &str) -> String {
+    format!("\"{}\"", identifier.replace('"', "\"\""))
+}
+
+/// `16` from `pg_ctl (PostgreSQL) 16.2`, or `9.6` from a pre-10 `9.6.24`, like `PG_VERSION`.
+fn major_version(version_output: &str) -> Option<&str> {
+    let version = version_output.split_whitespace().last()?;
+    let end = match version.strip_prefix("9.") {
+        Some(minor) => {
+            2 + minor
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(minor.len())
+        }
+        None => version
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(version.len()),
+    };
+    Some(&version[..end]).filter(|major| !major.is_empty())
+}
+
+/// The message of the last `FATAL` or `PANIC` line in a server log.
+pub fn last_fatal(log: &str) -> Option<&str> {
+    log.lines().rev().find_map(|line| {
+        let start = line.find("FATAL:").or_else(|| line.find("PANIC:"))?;
+        Some(line[start..].trim_end())
+    })
+}
+
+fn is_shut_down(cluster_state: &str) -> bool {
+    cluster_state == "shut down" || cluster_state == "shut down in recovery"
+}
+
+fn parse_pid(pidfile: &str) -> Option<u32> {
+    pidfile.lines().next()?.trim().parse().ok()
+}
+
+/// The third line of `postmaster.pid` is the epoch the postmaster started at.
+fn parse_start_time(pidfile: &str) -> Option<u64> {
+    pidfile.lines().nth(2)?.trim().parse().ok()
+}
+
+fn is_alive(pid: u32) -> bool {
+    let result = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    result == 0 || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+fn signal(pid: u32, signal: libc::c_int) -> io::Result<()> {
+    if unsafe { libc::kill(pid as libc::pid_t, signal) } == 0 {
+        return Ok(());
+    }
+    match io::Error::last_os_error() {
+        // Exited on its own in the meantime
+        err if err.raw_os_error() == Some(libc::ESRCH) => Ok(()),
+        err => Err(err),
+    }
+}
+
+/// Whether `pid` is still a Postgres process rather than gone or reused by something else.
+fn is_postmaster(pid: u32) -> bool {
+    if !is_alive(pid) {
+        return false;
+    }
+
+    match std::fs::read_to_string(format!("/proc/{}/comm", pid)) {
+        Ok(comm) => matches!(comm.trim(), "postgres" | "postmaster"),
+        // Without procfs there is no way to tell, so trust the signal check
+        Err(_) => true,
+    }
+}
+
+fn quote_literal(literal: &str) -> String {
+    format!("'{}'", literal.replace('\'', "''"))
+}
+
+fn join_lines(lines: &[&str]) -> String {
+    let mut joined = lines.join("\n");
+    if !joined.is_empty() {
+        joined.push('\n');
+    }
+    joined
+}
+
+fn find_in_path(program: &str) -> Option<PathBuf> {
+    env::split_paths(&env::var_os("PATH")?)
+        .map(|dir| dir.join(program))
+        .find(|candidate| candidate.is_file())
+}
+
+async fn read_dir_names(directory: &Path) -> io::Result<Vec<String>> {
+    let mut dir = match tokio::fs::read_dir(directory).await {
+        Ok(dir) => dir,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(err),
+    };
+    let mut names = vec![];
+
+    while let Some(entry) = dir.next_entry().await? {
+        names.push(entry.file_name().to_string_lossy().into_owned());
+    }
+
+    Ok(names)
+}
+
+/// Atomically swaps two paths with `renameat2(RENAME_EXCHANGE)`, falling back to a
+/// rename through a temporary path on filesystems that don't support it.
+fn exchange(a: &Path, b: &Path) -> io::Result<()> {
+    let a_c = CString::new(a.as_os_str().as_bytes())?;
+    let b_c = CString::new(b.as_os_str().as_bytes())?;
+
+    let result = unsafe {
+        libc::renameat2(
+            libc::AT_FDCWD,
+            a_c.as_ptr(),
+            libc::AT_FDCWD,
+            b_c.as_ptr(),
+            libc::RENAME_EXCHANGE,
+        )
+    };
+    if result == 0 {
+        return Ok(());
+    }
+
+    let err = io::Error::last_os_error();
+    match err.raw_os_error() {
+        Some(libc::EINVAL) | Some(libc::ENOSYS) => {
+            let mut temp = a.as_os_str().to_owned();
+            temp.push(".swap");
+            std::fs::rename(a, &temp)?;
+            std::fs::rename(b, a)?;
+            std::fs::rename(&temp, b)
+        }
+        _ => Err(err),
+    }
+}
+
+fn join_str<'a, S: Into<&'a str>>(directory: &Path, id: S) -> String {
+    directory.join(id.into()).to_string_lossy().into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::process::ExitStatusExt;
+
+    use super::*;
+
+    #[test]
+    fn check_output_survives_invalid_utf8() {
+        let output = Output {
+            // Wait statuses keep the exit code in the second byte
+            status: std::process::ExitStatus::from_raw(1 << 8),
+            stdout: b"server \xffstarting".to_vec(),
+            stderr: b"pg_ctl: could not start server \xc3\x28".to_vec(),
+        };
+
+        match PgCtl::check_output(&output) {
+            Err(Error::CliError(message)) => {
+                assert!(message.starts_with("exited with 1: pg_ctl: could not start server"));
+                assert!(message.contains('\u{FFFD}'));
+                assert!(message.ends_with("(stdout: server \u{FFFD}starting)"));
+            }
+            result => panic!("expected a CliError, got {:?}", result),
+        }
+    }
+
+    /// A `PgCtl` under `root` that never runs anything, `bin/pg_ctl` only has to exist.
+    fn offline_ctl(root: &Path) -> PgCtl {
+        std::fs::create_dir_all(root.join("bin")).unwrap();
+        std::fs::write(root.join("bin/pg_ctl"), "").unwrap();
+        PgCtl::new("postgres", root, None).unwrap()
+    }
+
+    #[test]
+    fn quote_identifier_escapes_quotes() {
+        assert_eq!(quote_identifier("app"), "\"app\"");
+        assert_eq!(quote_identifier("my app"), "\"my app\"");
+        assert_eq!(quote_identifier("user"), "\"user\"");
+        assert_eq!(quote_identifier("Select"), "\"Select\"");
+        assert_eq!(quote_identifier("a\"b"), "\"a\"\"b\"");
+        assert_eq!(
+            quote_identifier("foo\"; DROP DATABASE bar; --"),
+            "\"foo\"\"; DROP DATABASE bar; --\""
+        );
+    }
+
+    #[tokio::test]
+    async fn stale_pid_file_reports_stopped() {
+        let root = tempfile::tempdir().unwrap();
+        let ctl = offline_ctl(root.path());
+        let data = root.path().join("data/stale");
+        std::fs::create_dir_all(&data).unwrap();
+        let meta = Metadata {
+            dbname: "stale".to_string(),
+            user: None,
+            owner: None,
+            port: 5432,
+            limits: None,
+            expires_at: None,
+            version: None,
+            labels: HashMap::new(),
+            created_at: None,
+            last_started_at: None,
+            primary: None,
+        };
+        meta.to_file(&data.join("quickpg.json")).await.unwrap();
+        // Above the kernel's pid_max, so no process can have it
+        std::fs::write(
+            data.join("postmaster.pid"),
+            "2147483647\n/data/stale\n1700000000\n",
+        )
+        .unwrap();
+
+        assert!(!ctl.is_running("stale"));
+        let status = ctl.status("stale").await.unwrap();
+        assert!(!status.is_running());
+        assert!(!data.join("postmaster.pid").exists());
+    }
+
+    #[tokio::test]
+    async fn write_file_stays_in_the_data_dir() {
+        let root = tempfile::tempdir().unwrap();
+        // Only root can hand files to someone else
+        let owner = match unsafe { libc::geteuid() } {
+            0 => Ownership {
+                uid: 4242,
+                gid: 4242,
+            },
+            uid => Ownership {
+                uid,
+                gid: unsafe { libc::getegid() },
+            },
+        };
+        let ctl = offline_ctl(root.path()).with_owner(Some(owner));
+        let data = root.path().join("data/files");
+        std::fs::create_dir_all(&data).unwrap();
+        let outside = root.path().join("outside");
+        std::fs::create_dir(&outside).unwrap();
+
+        ctl.write_file("files", Path::new("conf.d/extra.conf"), b"work_mem = 8MB")
+            .await
+            .unwrap();
+        for path in [data.join("conf.d"), data.join("conf.d/extra.conf")] {
+            let metadata = std::fs::metadata(&path).unwrap();
+            assert_eq!((metadata.uid(), metadata.gid()), (owner.uid, owner.gid));
+        }
+
+        std::os::unix::fs::symlink(outside.join("target"), data.join("link")).unwrap();
+        std::os::unix::fs::symlink(&outside, data.join("linked_dir")).unwrap();
+        for relative in ["link", "linked_dir/file", "../outside/file"] {
+            match ctl.write_file("files", Path::new(relative), b"").await {
+                Err(Error::InvalidPath(_)) => (),
+                result => panic!("{} was written: {:?}", relative, result),
+            }
+        }
+        assert_eq!(std::fs::read_dir(&outside).unwrap().count(), 0);
+    }
 }
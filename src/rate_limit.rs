@@ -0,0 +1,102 @@
+use std::{
+    env,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::State,
+    http::{header, Method, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde_json::json;
+
+/// Token bucket shared by every request that changes something, reads are never limited.
+#[derive(Debug)]
+pub struct RateLimiter {
+    burst: f64,
+    per_second: f64,
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl RateLimiter {
+    /// Reads `QUICKPG_RATE_LIMIT_PER_MINUTE` and `QUICKPG_RATE_LIMIT_BURST`, which defaults to
+    /// the per minute limit. Unlimited when the rate is unset or zero.
+    pub fn from_env() -> Option<Arc<RateLimiter>> {
+        let per_minute: u32 = env::var("QUICKPG_RATE_LIMIT_PER_MINUTE")
+            .ok()
+            .and_then(|limit| limit.parse().ok())
+            .filter(|limit| *limit > 0)?;
+        let burst: u32 = env::var("QUICKPG_RATE_LIMIT_BURST")
+            .ok()
+            .and_then(|burst| burst.parse().ok())
+            .filter(|burst| *burst > 0)
+            .unwrap_or(per_minute);
+
+        Some(Arc::new(RateLimiter {
+            burst: burst.into(),
+            per_second: f64::from(per_minute) / 60.0,
+            bucket: Mutex::new(Bucket {
+                tokens: burst.into(),
+                refilled_at: Instant::now(),
+            }),
+        }))
+    }
+
+    /// Takes a token, or says how long until the next one.
+    fn acquire(&self) -> Result<(), Duration> {
+        let mut bucket = self.bucket.lock().unwrap();
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.refilled_at).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.per_second).min(self.burst);
+        bucket.refilled_at = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - bucket.tokens) / self.per_second,
+            ))
+        }
+    }
+}
+
+/// Answers `429 Too Many Requests` with `Retry-After` once `POST`, `PUT` and `DELETE`
+/// requests use up the bucket.
+pub async fn limit_mutations<B>(
+    State(limiter): State<Arc<RateLimiter>>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    let mutates = matches!(
+        *request.method(),
+        Method::POST | Method::PUT | Method::PATCH | Method::DELETE
+    );
+    if !mutates {
+        return next.run(request).await;
+    }
+
+    match limiter.acquire() {
+        Ok(()) => next.run(request).await,
+        Err(wait) => {
+            let retry_after = wait.as_secs_f64().ceil().max(1.0) as u64;
+            (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(header::RETRY_AFTER, retry_after.to_string())],
+                Json(json!({
+                    "error": format!("Too many requests, retry in {}s", retry_after)
+                })),
+            )
+                .into_response()
+        }
+    }
+}
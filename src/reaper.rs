@@ -0,0 +1,76 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::{
+    pg_ctl::{Error, Status, StopMode},
+    AppState,
+};
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or(0)
+}
+
+/// Destroys every instance whose TTL has elapsed, stopping it first if it's running.
+pub async fn reap(state: &AppState) {
+    let statuses = match state.ctl.list().await {
+        Ok(statuses) => statuses,
+        Err(err) => {
+            tracing::warn!("reaper failed to list instances: {}", err);
+            return;
+        }
+    };
+
+    let now = now();
+    let expired = statuses
+        .into_iter()
+        .filter(|status| is_expired(status, now))
+        .map(|status| status.id);
+
+    for id in expired {
+        if let Err(err) = reap_one(state, &id).await {
+            tracing::warn!("reaper failed to destroy {}: {}", id, err);
+        }
+    }
+}
+
+fn is_expired(status: &Status, now: u64) -> bool {
+    matches!(status.expires_at, Some(expires_at) if expires_at <= now)
+}
+
+/// Holds the instance's lock throughout, since a start, rename or swap since the listing may
+/// have extended its TTL or moved another instance to its id.
+async fn reap_one(state: &AppState, id: &str) -> Result<(), Error> {
+    let _guard = state.lock(id).await;
+    let ctl = &state.ctl;
+    if !ctl.exists(id) {
+        return Ok(());
+    }
+    let status = ctl.status(id).await?;
+    if !is_expired(&status, now()) {
+        return Ok(());
+    }
+
+    tracing::info!(
+        "reaping {}, expired at {}",
+        id,
+        status.expires_at.unwrap_or_default()
+    );
+
+    if status.is_running() {
+        ctl.stop(id, StopMode::Immediate, true).await?;
+    }
+    ctl.destroy(id).await?;
+    state.remove_job(id);
+    Ok(())
+}
+
+pub async fn reap_loop(state: AppState, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+
+    loop {
+        ticker.tick().await;
+        reap(&state).await;
+    }
+}
@@ -0,0 +1,71 @@
+use std::time::{Duration, UNIX_EPOCH};
+
+use serde::{de, Deserialize, Deserializer, Serializer};
+
+/// Writes an optional Unix timestamp in seconds as RFC3339, for `#[serde(with = "timestamp")]`.
+pub fn serialize<S: Serializer>(secs: &Option<u64>, serializer: S) -> Result<S::Ok, S::Error> {
+    match secs {
+        Some(secs) => {
+            let time = UNIX_EPOCH + Duration::from_secs(*secs);
+            serializer.collect_str(&humantime::format_rfc3339_seconds(time))
+        }
+        None => serializer.serialize_none(),
+    }
+}
+
+/// Also reads the plain seconds that metadata files held before.
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Timestamp {
+        Secs(u64),
+        Rfc3339(String),
+    }
+
+    match Option::<Timestamp>::deserialize(deserializer)? {
+        Some(Timestamp::Secs(secs)) => Ok(Some(secs)),
+        Some(Timestamp::Rfc3339(time)) => {
+            let time = humantime::parse_rfc3339(&time).map_err(de::Error::custom)?;
+            let secs = time.duration_since(UNIX_EPOCH).map_err(de::Error::custom)?;
+            Ok(Some(secs.as_secs()))
+        }
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, PartialEq, Deserialize, Serialize)]
+    struct Times {
+        #[serde(default, with = "super")]
+        at: Option<u64>,
+    }
+
+    #[test]
+    fn round_trips_through_rfc3339() {
+        let json = serde_json::to_string(&Times {
+            at: Some(1700000000),
+        })
+        .unwrap();
+        assert_eq!(json, r#"{"at":"2023-11-14T22:13:20Z"}"#);
+        assert_eq!(
+            serde_json::from_str::<Times>(&json).unwrap().at,
+            Some(1700000000)
+        );
+
+        assert_eq!(
+            serde_json::from_str::<Times>(r#"{"at":1700000000}"#)
+                .unwrap()
+                .at,
+            Some(1700000000)
+        );
+        assert_eq!(serde_json::from_str::<Times>("{}").unwrap().at, None);
+        assert_eq!(
+            serde_json::to_string(&Times { at: None }).unwrap(),
+            r#"{"at":null}"#
+        );
+        assert!(serde_json::from_str::<Times>(r#"{"at":"yesterday"}"#).is_err());
+    }
+}
@@ -0,0 +1,164 @@
+use std::{env, fmt};
+
+use tokio_postgres::{config::Host, Client, Config, NoTls};
+
+const SSLMODE_ENV: &str = "QUICKPG_PG_SSLMODE";
+
+/// How quickpg's own connections to the instances it manages are made.
+pub enum Connector {
+    Plain,
+    /// Encrypts without verifying the server certificate, like libpq's `sslmode=require`
+    #[cfg(feature = "tls")]
+    Rustls(tokio_postgres_rustls::MakeRustlsConnect),
+}
+
+impl fmt::Debug for Connector {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Connector::Plain => write!(formatter, "Plain"),
+            #[cfg(feature = "tls")]
+            Connector::Rustls(_) => write!(formatter, "Rustls"),
+        }
+    }
+}
+
+impl Connector {
+    /// Reads `QUICKPG_PG_SSLMODE`, only `require` needs TLS and it needs the `tls` feature.
+    pub fn from_env() -> Result<Connector, String> {
+        match env::var(SSLMODE_ENV).as_deref() {
+            Err(_) | Ok("disable") => Ok(Connector::Plain),
+            Ok("require") => Connector::require(),
+            Ok(other) => Err(format!("unsupported {}: {}", SSLMODE_ENV, other)),
+        }
+    }
+
+    #[cfg(feature = "tls")]
+    fn require() -> Result<Connector, String> {
+        Ok(Connector::Rustls(insecure::connector()?))
+    }
+
+    #[cfg(not(feature = "tls"))]
+    fn require() -> Result<Connector, String> {
+        Err(format!(
+            "{}=require needs quickpg built with the tls feature",
+            SSLMODE_ENV
+        ))
+    }
+
+    pub fn requires_ssl(&self) -> bool {
+        !matches!(self, Connector::Plain)
+    }
+
+    pub async fn connect(&self, config: &mut Config) -> Result<Client, tokio_postgres::Error> {
+        // Postgres refuses SSL over Unix sockets, which never leave the machine anyway
+        let unix = config
+            .get_hosts()
+            .iter()
+            .all(|host| matches!(host, Host::Unix(_)));
+
+        match self {
+            _ if unix => {
+                let (client, connection) = config.connect(NoTls).await?;
+                spawn_connection(connection);
+                Ok(client)
+            }
+            Connector::Plain => {
+                let (client, connection) = config.connect(NoTls).await?;
+                spawn_connection(connection);
+                Ok(client)
+            }
+            #[cfg(feature = "tls")]
+            Connector::Rustls(tls) => {
+                config.ssl_mode(tokio_postgres::config::SslMode::Require);
+                let (client, connection) = config.connect(tls.clone()).await?;
+                spawn_connection(connection);
+                Ok(client)
+            }
+        }
+    }
+}
+
+fn spawn_connection<F>(connection: F)
+where
+    F: std::future::Future<Output = Result<(), tokio_postgres::Error>> + Send + 'static,
+{
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            eprintln!("connection error: {}", e);
+        }
+    });
+}
+
+#[cfg(feature = "tls")]
+mod insecure {
+    use std::sync::Arc;
+
+    use rustls::{
+        client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        crypto::{ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider},
+        pki_types::{CertificateDer, ServerName, UnixTime},
+        ClientConfig, DigitallySignedStruct, SignatureScheme,
+    };
+    use tokio_postgres_rustls::MakeRustlsConnect;
+
+    /// Instances use self-signed certificates, so only the handshake signatures are checked.
+    #[derive(Debug)]
+    struct AcceptAnyCert(Arc<CryptoProvider>);
+
+    impl ServerCertVerifier for AcceptAnyCert {
+        fn verify_server_cert(
+            &self,
+            _end_entity: &CertificateDer<'_>,
+            _intermediates: &[CertificateDer<'_>],
+            _server_name: &ServerName<'_>,
+            _ocsp_response: &[u8],
+            _now: UnixTime,
+        ) -> Result<ServerCertVerified, rustls::Error> {
+            Ok(ServerCertVerified::assertion())
+        }
+
+        fn verify_tls12_signature(
+            &self,
+            message: &[u8],
+            cert: &CertificateDer<'_>,
+            dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            verify_tls12_signature(
+                message,
+                cert,
+                dss,
+                &self.0.signature_verification_algorithms,
+            )
+        }
+
+        fn verify_tls13_signature(
+            &self,
+            message: &[u8],
+            cert: &CertificateDer<'_>,
+            dss: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, rustls::Error> {
+            verify_tls13_signature(
+                message,
+                cert,
+                dss,
+                &self.0.signature_verification_algorithms,
+            )
+        }
+
+        fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+            self.0.signature_verification_algorithms.supported_schemes()
+        }
+    }
+
+    pub fn connector() -> Result<MakeRustlsConnect, String> {
+        let provider = Arc::new(ring::default_provider());
+        let config = ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .map_err(|err| err.to_string())?
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(AcceptAnyCert(provider)))
+            .with_no_client_auth();
+
+        Ok(MakeRustlsConnect::new(config))
+    }
+}
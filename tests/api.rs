@@ -0,0 +1,765 @@
+//! End to end tests of the HTTP API against real Postgres instances, see `common`.
+
+mod common;
+
+use std::{
+    os::unix::fs::{MetadataExt, PermissionsExt},
+    path::{Path, PathBuf},
+    process::Command,
+    time::{Duration, Instant},
+};
+
+use serde_json::{json, Value};
+
+#[test]
+fn destroy_is_idempotent_unless_strict() {
+    let Some(server) = common::start() else {
+        return;
+    };
+
+    server.expect(server.delete("/pg/instance/missing"), 200);
+    server.expect(server.delete("/pg/instance/missing?strict=true"), 404);
+
+    server.create("gone");
+    server.expect(server.delete("/pg/instance/gone"), 200);
+    server.expect(server.delete("/pg/instance/gone"), 200);
+    server.expect(server.get("/pg/instance/gone"), 404);
+}
+
+#[tokio::test]
+async fn reflink_fork_matches_template() {
+    let Some(server) = common::server()
+        .env("QUICKPG_COPY_STRATEGY", "reflink")
+        .start()
+    else {
+        return;
+    };
+
+    let template = server.create("template");
+    let client = common::connect(&template).await;
+    client
+        .batch_execute("CREATE TABLE t AS SELECT i, md5(i::text) FROM generate_series(1, 100000) i")
+        .await
+        .unwrap();
+    drop(client);
+    server.expect(server.post("/pg/instance/template/stop", json!({})), 200);
+
+    let plan = server.expect(server.get("/pg/instance/template/fork/plan"), 200);
+    let started = Instant::now();
+    let fork = server.expect(server.post("/pg/instance/template/fork", json!({})), 201);
+    let elapsed = started.elapsed();
+
+    let client = common::connect(&fork).await;
+    let row = client
+        .query_one(
+            "SELECT count(*), md5(string_agg(md5, '' ORDER BY i)) FROM t",
+            &[],
+        )
+        .await
+        .unwrap();
+    assert_eq!(row.get::<_, i64>(0), 100000);
+    let expected: String = row.get(1);
+
+    server.expect(server.post("/pg/instance/template/start", json!({})), 200);
+    let template = server.expect(server.get("/pg/instance/template"), 200);
+    let row = common::connect(&template)
+        .await
+        .query_one("SELECT md5(string_agg(md5, '' ORDER BY i)) FROM t", &[])
+        .await
+        .unwrap();
+    assert_eq!(row.get::<_, String>(0), expected);
+
+    if plan["method"] == "reflink" {
+        // Cloning shares extents, so it mostly costs the start of the fork
+        assert!(
+            elapsed < Duration::from_secs(5),
+            "reflink fork took {:?}",
+            elapsed
+        );
+    } else {
+        eprintln!(
+            "{} can't reflink, only the fallback copy was checked ({:?})",
+            plan["filesystem"], elapsed
+        );
+    }
+}
+
+#[test]
+fn instances_live_under_the_configured_root() {
+    let Some(server) = common::server().root("storage/quickpg").start() else {
+        return;
+    };
+
+    server.create("rooted");
+    assert!(server.root.join("data/rooted/PG_VERSION").is_file());
+    assert!(server.root.ends_with("storage/quickpg"));
+    // The server runs in the scratch dir, which must stay untouched
+    assert!(!server.dir().join("data").exists());
+}
+
+#[tokio::test]
+async fn restart_applies_config_changes() {
+    let Some(server) = common::start() else {
+        return;
+    };
+
+    let instance = server.create("restarted");
+    common::connect(&instance)
+        .await
+        .batch_execute("ALTER SYSTEM SET max_connections = 42")
+        .await
+        .unwrap();
+
+    let restarted = server.expect(
+        server.post("/pg/instance/restarted/restart", json!({})),
+        200,
+    );
+    assert_eq!(restarted["state"], "Running");
+    assert_ne!(restarted["proc_info"]["pid"], instance["proc_info"]["pid"]);
+    let row = common::connect(&restarted)
+        .await
+        .query_one("SHOW max_connections", &[])
+        .await
+        .unwrap();
+    assert_eq!(row.get::<_, String>(0), "42");
+
+    common::connect(&restarted)
+        .await
+        .batch_execute("ALTER SYSTEM SET shared_preload_libraries = 'not_installed'")
+        .await
+        .unwrap();
+    let failed = server.expect(
+        server.post("/pg/instance/restarted/restart", json!({})),
+        500,
+    );
+    assert!(
+        failed["error"].as_str().unwrap().contains("not_installed"),
+        "{}",
+        failed
+    );
+
+    server.expect(server.post("/pg/instance/missing/restart", json!({})), 404);
+}
+
+#[test]
+fn list_skips_broken_instances() {
+    let Some(server) = common::start() else {
+        return;
+    };
+
+    server.create("good");
+    let data = server.root.join("data");
+    std::fs::create_dir(data.join("broken")).unwrap();
+    std::fs::write(data.join("broken/quickpg.json"), "{ not json").unwrap();
+    std::fs::create_dir(data.join("empty")).unwrap();
+    std::fs::write(data.join("stray"), "").unwrap();
+
+    let list = server.expect(server.get("/pg/instance"), 200);
+    let ids: Vec<&str> = list["instances"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|instance| instance["id"].as_str().unwrap())
+        .collect();
+    assert_eq!(ids, ["good"]);
+}
+
+#[tokio::test]
+async fn databases_with_awkward_names() {
+    let Some(server) = common::start() else {
+        return;
+    };
+
+    for (id, dbname) in [
+        ("reserved", "select"),
+        ("hyphen", "my-app"),
+        ("upper", "MyApp"),
+    ] {
+        let instance = server.expect(
+            server.post("/pg/instance", json!({ "id": id, "dbname": dbname })),
+            201,
+        );
+        let row = common::connect(&instance)
+            .await
+            .query_one("SELECT current_database()", &[])
+            .await
+            .unwrap();
+        assert_eq!(row.get::<_, String>(0), dbname);
+    }
+
+    for dbname in ["foo; DROP DATABASE postgres", "with space", "quo\"te"] {
+        server.expect(
+            server.post("/pg/instance", json!({ "id": "bad", "dbname": dbname })),
+            400,
+        );
+    }
+}
+
+/// Major versions installed side by side, keyed like quickpg's `bin/<version>` layout.
+fn installed_versions() -> Vec<(String, PathBuf)> {
+    let mut dirs: Vec<PathBuf> = std::fs::read_dir("/usr/lib/postgresql")
+        .map(|entries| {
+            entries
+                .filter_map(|entry| Some(entry.ok()?.path().join("bin")))
+                .collect()
+        })
+        .unwrap_or_default();
+    dirs.extend(common::pg_bin());
+
+    let mut versions: Vec<(String, PathBuf)> = dirs
+        .into_iter()
+        .filter_map(|dir| {
+            let output = Command::new(dir.join("pg_ctl"))
+                .arg("--version")
+                .output()
+                .ok()?;
+            let version = String::from_utf8(output.stdout).ok()?;
+            let major = version
+                .split_whitespace()
+                .last()?
+                .split('.')
+                .next()?
+                .to_string();
+            Some((major, dir))
+        })
+        .collect();
+    versions.sort();
+    versions.dedup_by(|a, b| a.0 == b.0);
+    versions
+}
+
+#[test]
+fn instances_on_several_versions() {
+    let Some(server) = common::start() else {
+        return;
+    };
+
+    let versions = installed_versions();
+    if versions.len() < 2 {
+        eprintln!("only one Postgres major version installed, pairing it with the default binary");
+    }
+    std::fs::create_dir(server.root.join("bin")).unwrap();
+    for (major, dir) in &versions {
+        std::os::unix::fs::symlink(dir, server.root.join("bin").join(major)).unwrap();
+    }
+
+    let default = server.create("default");
+    assert_eq!(default["version"], Value::Null);
+    for (major, _) in &versions {
+        let id = format!("v{}", major);
+        let instance = server.expect(
+            server.post(
+                "/pg/instance",
+                json!({ "id": id, "dbname": "app", "version": major }),
+            ),
+            201,
+        );
+        assert_eq!(instance["version"], major.as_str());
+        assert_eq!(instance["server_version"], major.as_str());
+        // Starts again with the binary recorded for it
+        server.expect(
+            server.post(&format!("/pg/instance/{}/stop", id), json!({})),
+            200,
+        );
+        server.expect(
+            server.post(&format!("/pg/instance/{}/start", id), json!({})),
+            200,
+        );
+    }
+    assert_eq!(server.get("/pg/instance/default").body["state"], "Running");
+
+    let missing = server.expect(
+        server.post(
+            "/pg/instance",
+            json!({ "id": "missing", "dbname": "app", "version": "99" }),
+        ),
+        400,
+    );
+    assert!(
+        missing["error"].as_str().unwrap().contains("99"),
+        "{}",
+        missing
+    );
+}
+
+/// Pids of processes whose command line mentions `path`.
+fn processes_using(path: &Path) -> Vec<String> {
+    let path = path.to_string_lossy();
+    std::fs::read_dir("/proc")
+        .unwrap()
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let cmdline = std::fs::read(entry.path().join("cmdline")).ok()?;
+            String::from_utf8_lossy(&cmdline)
+                .contains(path.as_ref())
+                .then(|| entry.file_name().to_string_lossy().into_owned())
+        })
+        .collect()
+}
+
+#[test]
+fn concurrent_lifecycle_operations_on_one_id() {
+    let Some(server) = common::start() else {
+        return;
+    };
+
+    let operations: [(&str, &str); 4] = [
+        ("POST", "/pg/instance"),
+        ("POST", "/pg/instance/contended/start"),
+        ("POST", "/pg/instance/contended/stop"),
+        ("DELETE", "/pg/instance/contended"),
+    ];
+    let descriptor = json!({ "id": "contended", "dbname": "app" });
+
+    std::thread::scope(|scope| {
+        for worker in 0..8 {
+            let server = &server;
+            let descriptor = &descriptor;
+            scope.spawn(move || {
+                for round in 0..4 {
+                    let (method, path) = operations[(worker + round) % operations.len()];
+                    let response = server.request(method, path, Some(descriptor));
+                    assert!(
+                        response.status < 500,
+                        "{} {}: {} {}\n{}",
+                        method,
+                        path,
+                        response.status,
+                        response.body,
+                        server.log()
+                    );
+                }
+            });
+        }
+    });
+
+    server.expect(server.delete("/pg/instance/contended"), 200);
+    let data = server.root.join("data/contended");
+    assert!(!data.exists());
+    assert_eq!(processes_using(&data), Vec::<String>::new());
+}
+
+#[test]
+fn status_reports_disk_usage_on_request() {
+    let Some(server) = common::start() else {
+        return;
+    };
+
+    server.create("sized");
+    let status = server.expect(server.get("/pg/instance/sized"), 200);
+    assert_eq!(status.get("disk_bytes"), None);
+
+    let status = server.expect(server.get("/pg/instance/sized?with_size=true"), 200);
+    let bytes = status["disk_bytes"].as_u64().unwrap();
+    // A fresh cluster is a few dozen MB, mostly its first WAL segment and the catalogs
+    assert!((1 << 20..1 << 30).contains(&bytes), "{} bytes", bytes);
+
+    let list = server.expect(server.get("/pg/instance?with_size=true"), 200);
+    // The running server keeps writing, so the two sizes needn't match exactly
+    assert!(list["instances"][0]["disk_bytes"].as_u64().unwrap() > 1 << 20);
+}
+
+#[tokio::test]
+async fn templates_with_tablespaces_are_not_forked() {
+    let Some(server) = common::start() else {
+        return;
+    };
+
+    let template = server.create("template");
+    // Postgres has to own the location, which is whoever owns the data dirs
+    let location = server.dir().join("tablespace");
+    std::fs::create_dir(&location).unwrap();
+    let owner = std::fs::metadata(server.root.join("data/template")).unwrap();
+    std::os::unix::fs::chown(&location, Some(owner.uid()), Some(owner.gid())).unwrap();
+    let client = common::connect(&template).await;
+    client
+        .batch_execute(&format!(
+            "CREATE TABLESPACE elsewhere LOCATION '{}'",
+            location.display()
+        ))
+        .await
+        .unwrap();
+    client
+        .batch_execute("CREATE TABLE t (i int) TABLESPACE elsewhere")
+        .await
+        .unwrap();
+    drop(client);
+    server.expect(server.post("/pg/instance/template/stop", json!({})), 200);
+
+    let response = server.post("/pg/instance/template/fork", json!({}));
+    assert_ne!(response.status, 201);
+    assert!(
+        response.body["error"]
+            .as_str()
+            .unwrap()
+            .contains("tablespace"),
+        "{}",
+        response.body
+    );
+    let dirs: Vec<_> = std::fs::read_dir(server.root.join("data"))
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    assert_eq!(dirs, ["template"]);
+}
+
+#[tokio::test]
+async fn forks_of_a_running_template_start_without_replaying_wal() {
+    let Some(server) = common::start() else {
+        return;
+    };
+
+    let template = server.create("template");
+    common::connect(&template)
+        .await
+        .batch_execute("CREATE TABLE t AS SELECT i FROM generate_series(1, 500000) i")
+        .await
+        .unwrap();
+
+    let started = Instant::now();
+    let fork = server.expect(
+        server.post("/pg/instance/template/fork?stop_template=true", json!({})),
+        201,
+    );
+    // The checkpoint, stop, copy and start of the fork and restart of the template together
+    eprintln!("fork with stop_template took {:?}", started.elapsed());
+
+    let template = server.expect(server.get("/pg/instance/template"), 200);
+    assert_eq!(template["state"], "Running");
+    let row = common::connect(&fork)
+        .await
+        .query_one("SELECT count(*) FROM t", &[])
+        .await
+        .unwrap();
+    assert_eq!(row.get::<_, i64>(0), 500000);
+
+    let log = server.get(&format!(
+        "/pg/instance/{}/logs",
+        fork["id"].as_str().unwrap()
+    ));
+    let log = log.body.as_str().unwrap();
+    assert!(log.contains("database system was shut down at"), "{}", log);
+    assert!(!log.contains("redo starts"), "{}", log);
+}
+
+#[test]
+fn failed_creates_leave_nothing_behind() {
+    let Some(server) = common::start() else {
+        return;
+    };
+
+    // Fails after the cluster is initialized and running
+    let response = server.post(
+        "/pg/instance",
+        json!({ "id": "seeded", "dbname": "app", "init_sql": "SELECT * FROM missing_table" }),
+    );
+    assert_eq!(response.status, 400, "{}", response.body);
+    assert!(!server.root.join("data/seeded").exists());
+    assert!(!server.root.join("logs/seeded.log").exists());
+    assert_eq!(
+        processes_using(&server.root.join("data/seeded")),
+        Vec::<String>::new()
+    );
+    server.expect(server.get("/pg/instance/seeded"), 404);
+
+    // The id is free again
+    server.create("seeded");
+}
+
+/// Connects like a local libpq client would, through the reported `socket_dir`.
+async fn connect_socket(instance: &Value) -> tokio_postgres::Client {
+    let conn_info = &instance["conn_info"];
+    let mut config = tokio_postgres::Config::new();
+    config
+        .host_path(conn_info["socket_dir"].as_str().unwrap())
+        .port(conn_info["port"].as_u64().unwrap() as u16)
+        .user(conn_info["user"].as_str().unwrap())
+        .dbname(conn_info["dbname"].as_str().unwrap());
+
+    let (client, connection) = config.connect(tokio_postgres::NoTls).await.unwrap();
+    tokio::spawn(connection);
+    client
+}
+
+#[tokio::test]
+async fn clients_connect_through_the_reported_socket_dir() {
+    for builder in [
+        common::server(),
+        common::server().env("QUICKPG_SOCKET_DIR", "sockets"),
+    ] {
+        let Some(server) = builder.start() else {
+            return;
+        };
+
+        let instance = server.create("socket");
+        let socket_dir = PathBuf::from(instance["conn_info"]["socket_dir"].as_str().unwrap());
+        assert!(socket_dir.is_absolute(), "{}", socket_dir.display());
+        let port = instance["conn_info"]["port"].as_u64().unwrap();
+        assert!(socket_dir.join(format!(".s.PGSQL.{}", port)).exists());
+
+        let row = connect_socket(&instance)
+            .await
+            .query_one("SELECT current_database()", &[])
+            .await
+            .unwrap();
+        assert_eq!(row.get::<_, String>(0), "app");
+    }
+}
+
+#[tokio::test]
+async fn renamed_instances_start_under_their_new_id() {
+    let Some(server) = common::start() else {
+        return;
+    };
+
+    let instance = server.create("before");
+    common::connect(&instance)
+        .await
+        .batch_execute("CREATE TABLE kept (i int)")
+        .await
+        .unwrap();
+    server.create("taken");
+
+    let response = server.post("/pg/instance/before/rename", json!({ "id": "after" }));
+    server.expect(response, 400);
+    server.expect(server.post("/pg/instance/before/stop", json!({})), 200);
+    let response = server.post("/pg/instance/before/rename", json!({ "id": "taken" }));
+    server.expect(response, 409);
+
+    let renamed = server.expect(
+        server.post("/pg/instance/before/rename", json!({ "id": "after" })),
+        200,
+    );
+    assert_eq!(renamed["id"], "after");
+    assert_eq!(renamed["conn_info"]["port"], instance["conn_info"]["port"]);
+    server.expect(server.get("/pg/instance/before"), 404);
+    assert!(!server.root.join("data/before").exists());
+    assert!(server.root.join("logs/after.log").exists());
+
+    let started = server.expect(server.post("/pg/instance/after/start", json!({})), 200);
+    assert_eq!(started["state"], "Running");
+    common::connect(&started)
+        .await
+        .batch_execute("SELECT * FROM kept")
+        .await
+        .unwrap();
+}
+
+#[test]
+fn deep_roots_ask_for_a_shorter_socket_dir() {
+    let root = "a".repeat(100);
+    let Some(server) = common::server().root(&root).start() else {
+        return;
+    };
+
+    let response = server.post("/pg/instance", json!({ "id": "deep", "dbname": "app" }));
+    assert_ne!(response.status, 201);
+    let message = response.body["error"].as_str().unwrap_or_default();
+    assert!(
+        message.contains("pick a shorter --socket-dir"),
+        "{}",
+        response.body
+    );
+    assert!(!server.root.join("data/deep").exists());
+    drop(server);
+
+    let Some(server) = common::server()
+        .root(&root)
+        .env("QUICKPG_SOCKET_DIR", "sockets")
+        .start()
+    else {
+        return;
+    };
+    server.create("deep");
+}
+
+#[test]
+fn empty_roots_need_no_setup() {
+    let Some(server) = common::start() else {
+        return;
+    };
+    // The harness only creates the root itself
+    let list = server.expect(server.get("/pg/instance"), 200);
+    assert_eq!(list["instances"], json!([]));
+
+    server.create("first");
+    let list = server.expect(server.get("/pg/instance"), 200);
+    assert_eq!(list["instances"][0]["id"], "first");
+}
+
+#[tokio::test]
+async fn instances_without_tcp_are_provisioned_over_the_socket() {
+    let Some(server) = common::start() else {
+        return;
+    };
+
+    let instance = server.expect(
+        server.post(
+            "/pg/instance",
+            json!({
+                "id": "socket_only",
+                "dbname": "app",
+                "init_sql": "CREATE TABLE seeded (i int)",
+                "listen_addresses": [],
+            }),
+        ),
+        201,
+    );
+    assert_eq!(instance["state"], "Running");
+
+    let row = connect_socket(&instance)
+        .await
+        .query_one(
+            "SELECT current_setting('listen_addresses'), count(*) FROM seeded",
+            &[],
+        )
+        .await
+        .unwrap();
+    assert_eq!(row.get::<_, String>(0), "");
+    assert_eq!(row.get::<_, i64>(1), 0);
+
+    // Everything quickpg connects for goes through the socket as well
+    let status = server.expect(
+        server.get("/pg/instance/socket_only?with_connections=true"),
+        200,
+    );
+    assert!(status["connections"].is_i64(), "{}", status);
+    let restarted = server.expect(
+        server.post("/pg/instance/socket_only/restart", json!({})),
+        200,
+    );
+    assert_eq!(restarted["state"], "Running");
+}
+
+#[test]
+fn failed_background_creates_are_forgotten_once_the_id_is_created() {
+    let Some(server) = common::start() else {
+        return;
+    };
+
+    let response = server.post(
+        "/pg/instance?async=true",
+        json!({ "id": "retried", "dbname": "app", "init_sql": "SELECT * FROM missing_table" }),
+    );
+    server.expect(response, 201);
+    let started = Instant::now();
+    loop {
+        let status = server.get("/pg/instance/retried");
+        if status.status == 500 {
+            break;
+        }
+        assert!(
+            started.elapsed() < Duration::from_secs(30),
+            "{}",
+            status.body
+        );
+        std::thread::sleep(Duration::from_millis(100));
+    }
+
+    server.create("retried");
+    let status = server.expect(server.get("/pg/instance/retried"), 200);
+    assert_eq!(status["state"], "Running");
+}
+
+#[test]
+fn expired_instances_are_reaped() {
+    let Some(server) = common::server()
+        .env("QUICKPG_REAPER_INTERVAL_SECS", "1")
+        .start()
+    else {
+        return;
+    };
+
+    server.expect(
+        server.post(
+            "/pg/instance",
+            json!({ "id": "expiring", "dbname": "app", "ttl_seconds": 1 }),
+        ),
+        201,
+    );
+    server.create("kept");
+
+    let started = Instant::now();
+    while server.get("/pg/instance/expiring").status != 404 {
+        assert!(started.elapsed() < Duration::from_secs(30), "not reaped");
+        std::thread::sleep(Duration::from_millis(100));
+    }
+    assert!(!server.root.join("data/expiring").exists());
+    server.expect(server.get("/pg/instance/kept"), 200);
+}
+
+#[test]
+fn timestamps_are_rfc3339() {
+    let Some(server) = common::start() else {
+        return;
+    };
+
+    let instance = server.create("dated");
+    for field in ["created_at", "last_started_at"] {
+        let time = instance[field].as_str().unwrap();
+        assert!(time.ends_with('Z') && time.contains('T'), "{}", time);
+    }
+    server.expect(server.post("/pg/instance/dated/stop", json!({})), 200);
+    let stopped = server.expect(server.get("/pg/instance/dated"), 200);
+    assert_eq!(stopped["created_at"], instance["created_at"]);
+}
+
+#[test]
+fn upserts_reconcile_labels_and_expiry() {
+    let Some(server) = common::start() else {
+        return;
+    };
+
+    let put = |body: Value| server.request("PUT", "/pg/instance/declared", Some(&body));
+    let created = server.expect(
+        put(json!({ "dbname": "app", "labels": { "team": "payments" } })),
+        200,
+    );
+    assert_eq!(created["labels"], json!({ "team": "payments" }));
+    assert_eq!(created["expires_at"], Value::Null);
+
+    let updated = server.expect(
+        put(json!({ "dbname": "app", "labels": { "team": "search" }, "ttl_seconds": 3600 })),
+        200,
+    );
+    assert_eq!(updated["proc_info"]["pid"], created["proc_info"]["pid"]);
+    assert_eq!(updated["labels"], json!({ "team": "search" }));
+    assert!(updated["expires_at"].as_u64().is_some(), "{}", updated);
+
+    let status = server.expect(server.get("/pg/instance/declared"), 200);
+    assert_eq!(status["labels"], updated["labels"]);
+    assert_eq!(status["expires_at"], updated["expires_at"]);
+    let list = server.expect(server.get("/pg/instance?label=team=payments"), 200);
+    assert_eq!(list["instances"], json!([]));
+
+    // Cleared again by a manifest without them
+    let cleared = server.expect(put(json!({ "dbname": "app" })), 200);
+    assert_eq!(cleared["labels"], json!({}));
+    assert_eq!(cleared["expires_at"], Value::Null);
+
+    server.expect(put(json!({ "dbname": "other" })), 409);
+    server.expect(put(json!({ "dbname": "app", "owner": "someone" })), 409);
+}
+
+#[test]
+fn hung_post_start_hooks_are_killed() {
+    let scripts = tempfile::tempdir().unwrap();
+    let hook = scripts.path().join("hook.sh");
+    std::fs::write(&hook, "#!/bin/sh\nsleep 60\n").unwrap();
+    std::fs::set_permissions(&hook, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let Some(server) = common::server()
+        .env("QUICKPG_POST_START_HOOK", hook.to_str().unwrap())
+        .env("QUICKPG_POST_START_HOOK_STRICT", "true")
+        .env("QUICKPG_COMMAND_TIMEOUT_SECS", "2")
+        .start()
+    else {
+        return;
+    };
+
+    let started = Instant::now();
+    let response = server.post("/pg/instance", json!({ "id": "hooked", "dbname": "app" }));
+    assert_ne!(response.status, 201);
+    let message = response.body["error"].as_str().unwrap_or_default();
+    assert!(message.contains("timed out after 2s"), "{}", response.body);
+    assert!(started.elapsed() < Duration::from_secs(30));
+}
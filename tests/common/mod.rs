@@ -0,0 +1,246 @@
+//! Runs the quickpg binary against a scratch root for the API tests. They need a Postgres
+//! installation, found through `QUICKPG_PG_BIN` or `pg_ctl` on `$PATH`, and are skipped with a
+//! message without one. `QUICKPG_TEST_REQUIRE_PG=true` turns that skip into a failure.
+
+#![allow(dead_code)]
+
+use std::{
+    env,
+    ffi::CString,
+    fs,
+    io::{Read, Write},
+    net::{SocketAddr, TcpStream},
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+use serde_json::Value;
+use tempfile::TempDir;
+
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct Response {
+    pub status: u16,
+    pub body: Value,
+}
+
+pub struct Builder {
+    /// Relative to the scratch dir, which the server also runs in
+    root: PathBuf,
+    envs: Vec<(String, String)>,
+}
+
+impl Builder {
+    pub fn root(mut self, root: impl Into<PathBuf>) -> Builder {
+        self.root = root.into();
+        self
+    }
+
+    pub fn env(mut self, key: &str, value: &str) -> Builder {
+        self.envs.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    /// Waits until the server answers, `None` when Postgres isn't installed.
+    pub fn start(self) -> Option<Server> {
+        let pg_bin = match pg_bin() {
+            Some(pg_bin) => pg_bin,
+            None => return skip("pg_ctl not found in QUICKPG_PG_BIN or on $PATH"),
+        };
+
+        let dir = tempfile::tempdir().unwrap();
+        // Postgres may run as another user, who has to reach the root
+        fs::set_permissions(dir.path(), fs::Permissions::from_mode(0o755)).unwrap();
+        let root = dir.path().join(&self.root);
+        fs::create_dir_all(&root).unwrap();
+
+        let port = portpicker::pick_unused_port().expect("no free port");
+        let addr = SocketAddr::from(([127, 0, 0, 1], port));
+        let log = fs::File::create(dir.path().join("server.log")).unwrap();
+
+        let mut command = Command::new(env!("CARGO_BIN_EXE_quickpg"));
+        command
+            .current_dir(dir.path())
+            .env("QUICKPG_BIND", addr.to_string())
+            .env("QUICKPG_ROOT", &root)
+            .env("QUICKPG_PG_BIN", &pg_bin)
+            .env("RUST_LOG", "info")
+            .stdout(Stdio::null())
+            .stderr(log);
+        // initdb refuses to run as root
+        if unsafe { libc::geteuid() } == 0 {
+            let (uid, gid) = match postgres_user() {
+                Some(owner) => owner,
+                None => return skip("running as root without a postgres user to own the data"),
+            };
+            command
+                .env("QUICKPG_DATA_UID", uid.to_string())
+                .env("QUICKPG_DATA_GID", gid.to_string());
+        }
+        for (key, value) in &self.envs {
+            command.env(key, value);
+        }
+
+        let mut server = Server {
+            child: command.spawn().unwrap(),
+            addr,
+            root,
+            dir,
+        };
+        server.wait_until_up();
+        Some(server)
+    }
+}
+
+pub fn server() -> Builder {
+    Builder {
+        root: PathBuf::from("root"),
+        envs: vec![],
+    }
+}
+
+pub fn start() -> Option<Server> {
+    server().start()
+}
+
+fn skip<T>(reason: &str) -> Option<T> {
+    if env::var("QUICKPG_TEST_REQUIRE_PG").as_deref() == Ok("true") {
+        panic!("{}", reason);
+    }
+    eprintln!("skipping: {}", reason);
+    None
+}
+
+pub fn pg_bin() -> Option<PathBuf> {
+    if let Some(dir) = env::var_os("QUICKPG_PG_BIN") {
+        return Some(PathBuf::from(dir));
+    }
+    env::split_paths(&env::var_os("PATH")?).find(|dir| dir.join("pg_ctl").is_file())
+}
+
+fn postgres_user() -> Option<(u32, u32)> {
+    let name = CString::new("postgres").unwrap();
+    let passwd = unsafe { libc::getpwnam(name.as_ptr()) };
+    if passwd.is_null() {
+        return None;
+    }
+    unsafe { Some(((*passwd).pw_uid, (*passwd).pw_gid)) }
+}
+
+pub struct Server {
+    child: Child,
+    addr: SocketAddr,
+    pub root: PathBuf,
+    dir: TempDir,
+}
+
+impl Server {
+    pub fn dir(&self) -> &Path {
+        self.dir.path()
+    }
+
+    fn wait_until_up(&mut self) {
+        let started = Instant::now();
+        while TcpStream::connect(self.addr).is_err() {
+            if let Some(status) = self.child.try_wait().unwrap() {
+                panic!("quickpg exited with {}:\n{}", status, self.log());
+            }
+            if started.elapsed() > STARTUP_TIMEOUT {
+                panic!("quickpg did not come up:\n{}", self.log());
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    pub fn log(&self) -> String {
+        fs::read_to_string(self.dir.path().join("server.log")).unwrap_or_default()
+    }
+
+    pub fn request(&self, method: &str, path: &str, body: Option<&Value>) -> Response {
+        let body = body.map(Value::to_string).unwrap_or_default();
+        let mut stream = TcpStream::connect(self.addr).unwrap();
+        // HTTP/1.0 has the server close the connection after a plain, unchunked body
+        write!(
+            stream,
+            "{} {} HTTP/1.0\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+            method,
+            path,
+            self.addr,
+            body.len(),
+            body
+        )
+        .unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+        let body = match body {
+            "" => Value::Null,
+            body => serde_json::from_str(body).unwrap_or_else(|_| Value::String(body.to_string())),
+        };
+
+        Response { status, body }
+    }
+
+    pub fn get(&self, path: &str) -> Response {
+        self.request("GET", path, None)
+    }
+
+    pub fn post(&self, path: &str, body: Value) -> Response {
+        self.request("POST", path, Some(&body))
+    }
+
+    pub fn delete(&self, path: &str) -> Response {
+        self.request("DELETE", path, None)
+    }
+
+    /// Creates a running instance, panicking with the server's log if that fails.
+    pub fn create(&self, id: &str) -> Value {
+        let response = self.post(
+            "/pg/instance",
+            serde_json::json!({ "id": id, "dbname": "app" }),
+        );
+        self.expect(response, 201)
+    }
+
+    pub fn expect(&self, response: Response, status: u16) -> Value {
+        assert_eq!(
+            response.status,
+            status,
+            "unexpected response {}\n{}",
+            response.body,
+            self.log()
+        );
+        response.body
+    }
+}
+
+impl Drop for Server {
+    /// Instances would outlive the scratch dir otherwise.
+    fn drop(&mut self) {
+        if self.child.try_wait().unwrap().is_none() {
+            self.delete("/pg/instance?all=true");
+        }
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Connects to an instance like a client would, over TCP as quickpg's own user.
+pub async fn connect(instance: &Value) -> tokio_postgres::Client {
+    let conn_info = &instance["conn_info"];
+    let mut config = tokio_postgres::Config::new();
+    config
+        .host(conn_info["host"].as_str().unwrap())
+        .port(conn_info["port"].as_u64().unwrap() as u16)
+        .user(conn_info["user"].as_str().unwrap())
+        .dbname(conn_info["dbname"].as_str().unwrap());
+
+    let (client, connection) = config.connect(tokio_postgres::NoTls).await.unwrap();
+    tokio::spawn(connection);
+    client
+}